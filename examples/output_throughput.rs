@@ -0,0 +1,65 @@
+//! Compares reading a large stdout through the default `String` channel with
+//! `Command::stdout_bytes_into`.
+//!
+//! `cargo run --release --features bytes --example output_throughput -- [GiB]`
+//!
+//! The child is `head -c <size> /dev/zero`, so this needs a Unix userland. The
+//! size defaults to 2 GiB.
+
+use managed_command::Command;
+use simple_broadcaster::broadcasting_channel;
+use std::{
+    env,
+    sync::mpsc::channel,
+    time::{Duration, Instant},
+};
+
+const GIB: u64 = 1 << 30;
+
+fn child(size: u64) -> Command {
+    let mut std_cmd = std::process::Command::new("head");
+    std_cmd.args(["-c", &size.to_string(), "/dev/zero"]);
+    Command::from(std_cmd)
+}
+
+fn text(size: u64) -> (u64, Duration) {
+    let (_broadcaster, subscriber) = broadcasting_channel("throughput");
+    let started = Instant::now();
+    let mut handle = child(size).run(subscriber.into()).expect("spawn failed");
+    let stdout = handle.stdout.take().unwrap();
+    let mut read = 0;
+    while let Ok(chunk) = stdout.recv() {
+        read += chunk.len() as u64;
+    }
+    handle.wait().expect("wait failed");
+    (read, started.elapsed())
+}
+
+fn bytes(size: u64) -> (u64, Duration) {
+    let (_broadcaster, subscriber) = broadcasting_channel("throughput");
+    let (tx, rx) = channel();
+    let started = Instant::now();
+    let handle = child(size)
+        .stdout_bytes_into(tx)
+        .run(subscriber.into())
+        .expect("spawn failed");
+    let read = rx.iter().map(|chunk| chunk.len() as u64).sum();
+    handle.wait().expect("wait failed");
+    (read, started.elapsed())
+}
+
+fn main() {
+    let gib: f64 = env::args()
+        .nth(1)
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(2.0);
+    let size = (gib * GIB as f64) as u64;
+    for (name, measure) in [("String", text as fn(u64) -> _), ("Bytes", bytes)] {
+        let (read, spent) = measure(size);
+        assert_eq!(read, size, "{name} path lost output");
+        println!(
+            "{name:>6}: {gib} GiB in {spent:?} ({:.0} MiB/s)",
+            read as f64 / (1 << 20) as f64 / spent.as_secs_f64()
+        );
+    }
+}
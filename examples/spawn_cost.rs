@@ -0,0 +1,54 @@
+//! Measures how long the calling thread spends in `run` versus `run_deferred`.
+//!
+//! `cargo run --release --example spawn_cost -- [program] [count]`
+//!
+//! The program defaults to the test process in `testing/`. Every spawned
+//! command is cancelled once the measurement is done.
+
+use managed_command::Command;
+use simple_broadcaster::broadcasting_channel;
+use std::{
+    env,
+    time::{Duration, Instant},
+};
+
+fn measure(program: &str, count: usize, deferred: bool) -> Duration {
+    let mut running = Vec::with_capacity(count);
+    let mut spent = Duration::ZERO;
+    for _ in 0..count {
+        let (broadcaster, subscriber) = broadcasting_channel("spawn cost");
+        let mut std_cmd = std::process::Command::new(program);
+        std_cmd.env("PATH", "testing");
+        let mut cmd = Command::from(std_cmd);
+        let canceller = subscriber.into();
+        let started = Instant::now();
+        let handle = if deferred {
+            cmd.run_deferred(canceller)
+        } else {
+            cmd.run(canceller)
+        };
+        spent += started.elapsed();
+        running.push((broadcaster, handle.expect("spawn failed")));
+    }
+    for (broadcaster, handle) in running {
+        broadcaster.broadcast(()).expect("cancel failed");
+        let _ = handle.wait();
+    }
+    spent
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let program = args
+        .next()
+        .unwrap_or_else(|| "managed-command-test-process".to_owned());
+    let count = args.next().and_then(|n| n.parse().ok()).unwrap_or(200);
+    for (name, deferred) in [("run", false), ("run_deferred", true)] {
+        let spent = measure(&program, count, deferred);
+        println!(
+            "{name:>12}: {count} commands, {:?} on the calling thread ({:?} per command)",
+            spent,
+            spent / count as u32
+        );
+    }
+}
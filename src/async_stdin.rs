@@ -0,0 +1,87 @@
+//! Feeding the child's stdin from async code, with the `tokio` feature.
+
+use crate::{
+    report_write_failure, stdin_gone, stdin_writer, Error, StdinMap, Worker, WriteFailure,
+};
+use std::{
+    process::ChildStdin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::Sender,
+        Arc, Mutex,
+    },
+};
+use tokio::sync::{mpsc, oneshot};
+
+/// The async counterpart of [`StdinSender`](crate::StdinSender), see
+/// [`Command::async_stdin`](crate::Command::async_stdin).
+pub struct AsyncStdinSender {
+    tx: mpsc::Sender<String>,
+    bytes_written: Arc<AtomicUsize>,
+    write_failure: WriteFailure,
+    closed: oneshot::Receiver<()>,
+}
+
+impl AsyncStdinSender {
+    /// Queues `input` for the child's stdin, waiting while the queue is full.
+    /// Fails like [`StdinSender::send`](crate::StdinSender::send) once the stdin
+    /// thread is gone.
+    pub async fn send(&self, input: String) -> Result<(), Error> {
+        self.tx
+            .send(input)
+            .await
+            .map_err(|mpsc::error::SendError(input)| stdin_gone(&self.write_failure, input))
+    }
+
+    /// Signals EOF to the child once everything queued has been written, and
+    /// waits until it has.
+    pub async fn close(self) {
+        let Self { tx, closed, .. } = self;
+        drop(tx);
+        let _ = closed.await;
+    }
+
+    /// Number of bytes written to the child's stdin so far, after `map_stdin`.
+    pub fn bytes_written(&self) -> usize {
+        self.bytes_written.load(Ordering::Relaxed)
+    }
+}
+
+/// Like the blocking stdin thread, but fed through a channel of `capacity`
+/// messages, so senders feel the backpressure of a slow child.
+pub(crate) fn worker(
+    cmd: String,
+    stdin: ChildStdin,
+    stdin_map: Option<StdinMap>,
+    record: Option<Arc<Mutex<String>>>,
+    capacity: usize,
+    errors: Sender<Error>,
+) -> (AsyncStdinSender, Worker) {
+    let (tx, mut rx) = mpsc::channel::<String>(capacity);
+    let (closed_tx, closed) = oneshot::channel();
+    let bytes_written = Arc::new(AtomicUsize::new(0));
+    let write_failure = WriteFailure::default();
+    let writer = stdin_writer(
+        cmd,
+        Arc::new(Mutex::new(Some(Arc::new(stdin)))),
+        stdin_map,
+        record,
+        Arc::clone(&bytes_written),
+        Arc::clone(&write_failure),
+        move || rx.blocking_recv(),
+    );
+    let reported = Arc::clone(&write_failure);
+    let worker: Worker = Box::new(move || {
+        // the pipe is closed once the writer returns
+        writer();
+        report_write_failure(&reported, &errors);
+        let _ = closed_tx.send(());
+    });
+    let sender = AsyncStdinSender {
+        tx,
+        bytes_written,
+        write_failure,
+        closed,
+    };
+    (sender, worker)
+}
@@ -0,0 +1,93 @@
+//! Cancellation sources other than a `simple_broadcaster` subscriber, turned
+//! into the [`Canceller`] that [`Command::run`](crate::Command::run) takes.
+
+use simple_broadcaster::Canceller;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+use tracing::trace;
+
+/// A cancel flag that can be handed to any number of commands. Clones share
+/// the flag, and dropping the last clone counts like dropping a subscriber's
+/// broadcaster, see [`Command::kill_on_canceller_drop`](crate::Command::kill_on_canceller_drop).
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<Mutex<TokenState>>);
+
+#[derive(Debug, Default)]
+struct TokenState {
+    cancelled: bool,
+    /// One per canceller handed out.
+    waiting: Vec<Sender<()>>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancels every command run with a canceller of this token, including the
+    /// ones started from now on.
+    pub fn cancel(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.cancelled = true;
+        for tx in state.waiting.drain(..) {
+            let _ = tx.send(());
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.lock().unwrap().cancelled
+    }
+
+    /// A canceller that fires when this token is cancelled, right away if it
+    /// already was.
+    pub fn canceller(&self) -> Canceller {
+        let (tx, rx) = channel();
+        let mut state = self.0.lock().unwrap();
+        match state.cancelled {
+            true => {
+                let _ = tx.send(());
+            }
+            false => state.waiting.push(tx),
+        }
+        Canceller(rx)
+    }
+}
+
+impl From<CancellationToken> for Canceller {
+    fn from(token: CancellationToken) -> Self {
+        token.canceller()
+    }
+}
+
+impl From<&CancellationToken> for Canceller {
+    fn from(token: &CancellationToken) -> Self {
+        token.canceller()
+    }
+}
+
+/// A canceller that fires once `flag` is set, checked every `poll_interval` by a
+/// helper thread. The thread ends when the flag is set or when it holds the
+/// last reference to it, which then counts as a dropped canceller. Prefer a
+/// [`CancellationToken`], which needs no thread.
+pub fn flag_canceller(flag: Arc<AtomicBool>, poll_interval: Duration) -> Canceller {
+    let (tx, rx) = channel();
+    thread::spawn(move || loop {
+        if flag.load(Ordering::SeqCst) {
+            let _ = tx.send(());
+            break;
+        }
+        if Arc::strong_count(&flag) == 1 {
+            trace!("cancel flag dropped without being set");
+            break;
+        }
+        thread::sleep(poll_interval);
+    });
+    Canceller(rx)
+}
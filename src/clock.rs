@@ -0,0 +1,72 @@
+//! Where the crate's time-based features read the time from.
+
+#[cfg(any(test, feature = "test-util"))]
+use std::sync::{Arc, Mutex};
+use std::{
+    sync::mpsc::RecvTimeoutError,
+    time::{Duration, Instant},
+};
+
+/// A source of the current time, see [`Command::clock`](crate::Command::clock).
+pub trait Clock: Send + Sync + 'static {
+    fn now(&self) -> Instant;
+}
+
+/// Waits for `recv` until `clock` reaches `deadline`, at most `poll_interval` at
+/// a time, so that a clock advanced by hand is noticed.
+pub(crate) fn recv_until<T>(
+    clock: &dyn Clock,
+    deadline: Instant,
+    poll_interval: Duration,
+    mut recv: impl FnMut(Duration) -> Result<T, RecvTimeoutError>,
+) -> Result<T, RecvTimeoutError> {
+    loop {
+        let left = deadline.saturating_duration_since(clock.now());
+        match recv(left.min(poll_interval)) {
+            Err(RecvTimeoutError::Timeout) if !left.is_zero() => {}
+            received => return received,
+        }
+    }
+}
+
+/// The real time, [`Instant::now`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that stands still until advanced, so tests of time-based behaviour
+/// need not sleep. Clones share the same time. Available with the `test-util`
+/// feature.
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Debug, Clone)]
+pub struct ManualClock(Arc<Mutex<Instant>>);
+
+#[cfg(any(test, feature = "test-util"))]
+impl ManualClock {
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(Instant::now())))
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.0.lock().unwrap() += by;
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+}
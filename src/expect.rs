@@ -0,0 +1,143 @@
+//! Scripted interaction with a child, in the style of `expect`.
+
+use crate::{clock, Error, ProcessHandle, StreamKind};
+use std::{
+    mem,
+    sync::mpsc::RecvTimeoutError,
+    time::{Duration, Instant},
+};
+
+/// How long a step waits for its pattern unless [`Expect::timeout`] says otherwise.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+enum Step {
+    Expect(StreamKind, String),
+    Send(String),
+}
+
+/// A script of steps run against a [`ProcessHandle`], started with
+/// [`ProcessHandle::expect`]. Each expect step waits until its pattern shows up
+/// in the output, then the steps after it run. Patterns are plain text and may
+/// span several reads. Output up to the end of a match is consumed, so the next
+/// pattern has to come after it. What was read past the last match, or without
+/// a match when a step fails, stays with the handle for the next script.
+///
+/// Prompts usually lack a newline, so run the child without
+/// [`Command::line_mode`](crate::Command::line_mode), which would hold them back.
+pub struct Expect<'a> {
+    handle: &'a ProcessHandle,
+    steps: Vec<Step>,
+    timeout: Duration,
+}
+
+impl<'a> Expect<'a> {
+    pub(crate) fn new(handle: &'a ProcessHandle) -> Self {
+        Self {
+            handle,
+            steps: Vec::new(),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Waits for `pattern` on stdout.
+    pub fn expect(mut self, pattern: impl Into<String>) -> Self {
+        self.steps
+            .push(Step::Expect(StreamKind::Stdout, pattern.into()));
+        self
+    }
+
+    /// Waits for `pattern` on stderr.
+    pub fn expect_stderr(mut self, pattern: impl Into<String>) -> Self {
+        self.steps
+            .push(Step::Expect(StreamKind::Stderr, pattern.into()));
+        self
+    }
+
+    /// Writes `input` to the child's stdin.
+    pub fn send(mut self, input: impl Into<String>) -> Self {
+        self.steps.push(Step::Send(input.into()));
+        self
+    }
+
+    /// How long each expect step may wait, 10 seconds by default, on the
+    /// command's [`Clock`](crate::Clock).
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Runs the steps in order. Fails with [`Error::ExpectTimeout`] or
+    /// [`Error::ExpectEof`] on the first pattern that does not show up, and with
+    /// [`Error::StdinClosed`] if the child no longer takes input.
+    pub fn run(self) -> Result<(), Error> {
+        let (mut stdout, mut stderr) = mem::take(&mut *self.handle.expect_buffer.lock().unwrap());
+        let ran = self.run_steps(&mut stdout, &mut stderr);
+        *self.handle.expect_buffer.lock().unwrap() = (stdout, stderr);
+        ran
+    }
+
+    fn run_steps(&self, stdout: &mut String, stderr: &mut String) -> Result<(), Error> {
+        for step in &self.steps {
+            match step {
+                Step::Send(input) => match &self.handle.stdin {
+                    Some(stdin) => stdin.send(input.clone())?,
+                    None => return Err(Error::StdinClosed(input.clone())),
+                },
+                Step::Expect(kind, pattern) => {
+                    let seen = match *kind {
+                        StreamKind::Stdout => &mut *stdout,
+                        StreamKind::Stderr => &mut *stderr,
+                    };
+                    let deadline = self.handle.clock.now() + self.timeout;
+                    self.wait_for(*kind, pattern, seen, deadline)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads `kind` into `seen` until it contains `pattern`, then drops `seen` up
+    /// to the end of the match.
+    fn wait_for(
+        &self,
+        kind: StreamKind,
+        pattern: &str,
+        seen: &mut String,
+        deadline: Instant,
+    ) -> Result<(), Error> {
+        loop {
+            if let Some(at) = seen.find(pattern) {
+                seen.drain(..at + pattern.len());
+                return Ok(());
+            }
+            let (clock, poll_interval) = (&*self.handle.clock, self.handle.poll_interval);
+            let received = clock::recv_until(clock, deadline, poll_interval, |timeout| {
+                match (kind, &self.handle.stdout, &self.handle.stderr) {
+                    (StreamKind::Stdout, Some(stdout), _) => stdout.recv_timeout(timeout),
+                    (StreamKind::Stderr, _, Some(stderr)) => stderr.recv_timeout(timeout),
+                    _ => Err(RecvTimeoutError::Disconnected),
+                }
+            });
+            match received {
+                Ok(msg) => {
+                    seen.push_str(&msg);
+                    if self.handle.line_mode {
+                        seen.push('\n');
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(Error::ExpectTimeout {
+                        pattern: pattern.to_owned(),
+                        seen: seen.clone(),
+                    })
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(Error::ExpectEof {
+                        pattern: pattern.to_owned(),
+                        seen: seen.clone(),
+                    })
+                }
+            }
+        }
+    }
+}
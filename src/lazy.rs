@@ -0,0 +1,164 @@
+//! Spawning the child on first use, see [`Command::lazy`](crate::Command::lazy).
+
+use crate::{Command, Error, ProcessHandle, StdinSender};
+use simple_broadcaster::Canceller;
+use std::{sync::Mutex, time::Duration};
+use tracing::trace;
+
+/// Shared by a lazy handle and its stdio ends, whichever is used first runs
+/// the command.
+pub(crate) struct LazyStart {
+    /// The command and its canceller until they are run.
+    pending: Mutex<Option<(Command, Canceller)>>,
+    /// The handle of the running child, without its stdio ends.
+    handle: Mutex<Option<ProcessHandle>>,
+    /// The stdin sender of the running child, until the lazy one is dropped.
+    stdin: Mutex<Option<StdinSender>>,
+    /// An [`StdinSender::auto_close_after_idle`] window set before the spawn.
+    auto_close: Mutex<Option<Duration>>,
+    /// Why the spawn failed, until reported.
+    failure: Mutex<Option<Error>>,
+}
+
+impl LazyStart {
+    pub(crate) fn new(command: Command, canceller: Canceller) -> Self {
+        Self {
+            pending: Mutex::new(Some((command, canceller))),
+            handle: Mutex::default(),
+            stdin: Mutex::default(),
+            auto_close: Mutex::default(),
+            failure: Mutex::default(),
+        }
+    }
+
+    /// Runs the command unless that was done already. Concurrent callers wait
+    /// for the spawn.
+    pub(crate) fn start(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        let Some((mut command, canceller)) = pending.take() else {
+            return;
+        };
+        match command.run(canceller) {
+            Ok(mut handle) => {
+                let stdin = handle.stdin.take();
+                if let (Some(stdin), Some(after)) = (&stdin, self.auto_close.lock().unwrap().take())
+                {
+                    stdin.auto_close_after_idle(after);
+                }
+                *self.stdin.lock().unwrap() = stdin;
+                *self.handle.lock().unwrap() = Some(handle);
+            }
+            Err(e) => {
+                trace!("the lazy spawn failed: {e}");
+                *self.failure.lock().unwrap() = Some(e);
+            }
+        }
+    }
+
+    /// The spawn error the first time, [`Error::NoChild`] afterwards.
+    fn failure(&self) -> Error {
+        self.failure
+            .lock()
+            .unwrap()
+            .take()
+            .unwrap_or(Error::NoChild)
+    }
+
+    /// Runs `f` on the handle, starting the child first if needed. `f` gets a
+    /// share of the handle, so that a `wait` does not block other callers.
+    pub(crate) fn with_handle<R>(
+        &self,
+        f: impl FnOnce(&ProcessHandle) -> Result<R, Error>,
+    ) -> Result<R, Error> {
+        self.start();
+        let handle = self.share();
+        match handle {
+            Some(handle) => f(&handle),
+            None => Err(self.failure()),
+        }
+    }
+
+    /// Runs `f` on the handle if the child was started.
+    pub(crate) fn if_started<R>(&self, f: impl FnOnce(&ProcessHandle) -> R) -> Option<R> {
+        self.share().as_ref().map(f)
+    }
+
+    fn share(&self) -> Option<ProcessHandle> {
+        self.handle
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(ProcessHandle::share)
+    }
+
+    pub(crate) fn send(&self, input: String) -> Result<(), Error> {
+        self.with_stdin(input, StdinSender::send)
+    }
+
+    pub(crate) fn send_if_idle(&self, input: String) -> Result<bool, Error> {
+        self.with_stdin(input, StdinSender::send_if_idle)
+    }
+
+    /// Starts the child if needed and hands `input` to `send` on its stdin.
+    fn with_stdin<R>(
+        &self,
+        input: String,
+        send: impl FnOnce(&StdinSender, String) -> Result<R, Error>,
+    ) -> Result<R, Error> {
+        self.start();
+        match self.stdin.lock().unwrap().as_ref() {
+            Some(stdin) => send(stdin, input),
+            None => match self.failure.lock().unwrap().take() {
+                Some(e) => Err(e),
+                None => Err(Error::StdinClosed(input)),
+            },
+        }
+    }
+
+    pub(crate) fn is_awaiting_response(&self) -> bool {
+        let stdin = self.stdin.lock().unwrap();
+        stdin
+            .as_ref()
+            .is_some_and(StdinSender::is_awaiting_response)
+    }
+
+    /// Applies `after` to the child's stdin, or keeps it for the spawn if the
+    /// child is not running yet.
+    pub(crate) fn auto_close_after_idle(&self, after: Duration) {
+        // the spawn takes the window while holding `pending`
+        let pending = self.pending.lock().unwrap();
+        if pending.is_some() {
+            *self.auto_close.lock().unwrap() = Some(after);
+            return;
+        }
+        drop(pending);
+        if let Some(stdin) = self.stdin.lock().unwrap().as_ref() {
+            stdin.auto_close_after_idle(after);
+        }
+    }
+
+    pub(crate) fn bytes_written(&self) -> usize {
+        let stdin = self.stdin.lock().unwrap();
+        stdin.as_ref().map_or(0, StdinSender::bytes_written)
+    }
+
+    pub(crate) fn close_stdin(&self) {
+        drop(self.stdin.lock().unwrap().take());
+    }
+
+    /// Starts the child if needed and hands out its handle, with the stdio ends
+    /// of `lazy` in place of its own.
+    pub(crate) fn take_handle(&self, mut lazy: ProcessHandle) -> Result<ProcessHandle, Error> {
+        self.start();
+        let mut handle = self
+            .handle
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| self.failure())?;
+        handle.stdin = lazy.stdin.take();
+        handle.stdout = lazy.stdout.take();
+        handle.stderr = lazy.stderr.take();
+        Ok(handle)
+    }
+}
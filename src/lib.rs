@@ -1,207 +1,7386 @@
-use simple_broadcaster::Canceller;
+#[cfg(feature = "tokio")]
+mod async_stdin;
+mod cancel;
+mod clock;
+mod expect;
+mod lazy;
+mod ntstatus;
+mod rate;
+#[cfg(feature = "rtrb")]
+mod ring;
+mod rotate;
+#[cfg(feature = "regex")]
+mod router;
+mod signal;
+#[cfg(unix)]
+mod user;
+
+#[cfg(feature = "tokio")]
+pub use async_stdin::AsyncStdinSender;
+#[cfg(feature = "bytes")]
+use bytes::{Bytes, BytesMut};
+pub use cancel::{flag_canceller, CancellationToken};
+#[cfg(feature = "test-util")]
+pub use clock::ManualClock;
+pub use clock::{Clock, SystemClock};
+pub use expect::Expect;
+use lazy::LazyStart;
+pub use ntstatus::NtStatus;
+pub use rate::RateLimiter;
+use rotate::RotatingLog;
+#[cfg(feature = "regex")]
+pub use router::{Router, Routes};
+pub use signal::Signal;
+use simple_broadcaster::{broadcasting_channel, Canceller};
 use std::{
+    any::Any,
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    env,
+    ffi::{OsStr, OsString},
+    fmt::Debug,
+    fs::OpenOptions,
     io::{self, Read, Write},
-    process::{Command as StdCommand, Stdio},
-    sync::mpsc::{self, channel, Receiver, RecvError, SendError, Sender},
+    iter, mem,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    process::{Child, ChildStdin, Command as StdCommand, ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{
+            self, channel, Receiver, RecvError, RecvTimeoutError, SendError, Sender, SyncSender,
+            TryRecvError,
+        },
+        Arc, Condvar, Mutex,
+    },
     thread,
+    time::{Duration, Instant},
 };
 use thiserror::Error as ThisError;
-use tracing::{self, trace};
+use tracing::{self, debug_span, error_span, info_span, trace, trace_span, warn_span, Level, Span};
+
+type StdinMap = Box<dyn Fn(String) -> String + Send>;
+type OnSpawn = Box<dyn Fn(u32) + Send>;
+type OnExit = Box<dyn Fn(ExitStatus, Duration) + Send>;
+type OnBacklog = Arc<Mutex<Box<dyn Fn() + Send>>>;
+type OnStall = Box<dyn Fn() + Send>;
+type Worker = Box<dyn FnOnce() + Send>;
+/// Caller data attached with [`Command::with_context`].
+type Context = Arc<dyn Any + Send + Sync>;
+/// One message shared between a reader thread and the handle.
+type SharedMessage = Arc<Mutex<Option<String>>>;
+/// Where a reader thread leaves the error that ended it early.
+type ReadFailure = Arc<Mutex<Option<io::Error>>>;
+
+/// Where the stdin thread leaves a write error other than a broken pipe.
+type WriteFailure = Arc<Mutex<Option<io::Error>>>;
+
+/// How much of stderr [`Error::NonZeroExit`] keeps, whichever limit is hit first.
+const STDERR_TAIL_LINES: usize = 20;
+const STDERR_TAIL_BYTES: usize = 2048;
+
+/// How long [`ProcessHandle::wait`] waits for the end of stderr with
+/// [`Command::fail_on_stderr`] or [`Command::fail_on_nonzero`] once the child
+/// has exited, should a grandchild keep the pipe open.
+const STDERR_GRACE: Duration = Duration::from_secs(1);
+/// Default for [`Command::poll_interval`].
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
 
 pub struct Command {
     std_command: StdCommand,
+    stdin_map: Option<StdinMap>,
+    stdin_rate_limit: Option<RateLimiter>,
+    kill_on_canceller_drop: bool,
+    cancel_signal: Signal,
+    kill_schedule: Arc<[(Duration, Signal)]>,
+    line_mode: bool,
+    trim_output: bool,
+    stdin_mode: StdioMode,
+    stdout_mode: StdioMode,
+    stderr_mode: StdioMode,
+    on_spawn: Option<OnSpawn>,
+    on_exit: Option<OnExit>,
+    on_backlog: Option<(usize, OnBacklog)>,
+    on_stall: Option<(Duration, OnStall)>,
+    read_limit: Option<ReadLimit>,
+    stdout_into: Option<Box<dyn OutputSender>>,
+    stderr_into: Option<Box<dyn OutputSender>>,
+    #[cfg(feature = "bytes")]
+    stdout_bytes_into: Option<Sender<Bytes>>,
+    #[cfg(feature = "bytes")]
+    stderr_bytes_into: Option<Sender<Bytes>>,
+    stdout_pairs_into: Option<Sender<(Vec<u8>, String)>>,
+    stderr_pairs_into: Option<Sender<(Vec<u8>, String)>>,
+    stdout_writer: Option<Box<dyn Write + Send>>,
+    kill_on_writer_error: bool,
+    capture_after: Option<String>,
+    record_sentinel: Option<String>,
+    normalize_newlines: bool,
+    strip_ansi: bool,
+    dedup_consecutive: bool,
+    #[cfg(all(unix, feature = "signal-forwarding"))]
+    forward_signals: Vec<Signal>,
+    record_invocation: bool,
+    #[cfg(unix)]
+    reset_signal_handlers: bool,
+    /// Target fd numbers and their sources, closed here once the child has them.
+    #[cfg(unix)]
+    inherit_fds: Vec<(i32, std::os::fd::OwnedFd)>,
+    #[cfg(unix)]
+    memory_limit: Option<u64>,
+    #[cfg(unix)]
+    cpu_time_limit: Option<Duration>,
+    /// `CLONE_NEW*` flags to unshare right before exec.
+    #[cfg(target_os = "linux")]
+    namespaces: libc::c_int,
+    /// Requested uid and gid, to explain a failing spawn.
+    #[cfg(unix)]
+    credentials: (Option<u32>, Option<u32>),
+    /// See [`Command::arg0`].
+    arg0: Option<OsString>,
+    clock: Arc<dyn Clock>,
+    poll_interval: Duration,
+    fail_on_stderr: bool,
+    fail_on_nonzero: bool,
+    kill_on_stderr: bool,
+    #[cfg(feature = "tokio")]
+    async_stdin: Option<usize>,
+    #[cfg(feature = "rtrb")]
+    stdout_ring: Option<usize>,
+    sync_pwd: bool,
+    /// Set once the environment was cleared through this type, see
+    /// [`Command::effective_env`].
+    env_cleared: bool,
+    /// Variables [`Command::run_with_env`] set that were not set explicitly
+    /// before. std cannot unset them, so they are no longer counted as set.
+    scoped_env: BTreeSet<OsString>,
+    /// Set by [`Command::feed_and_wait`]: stdin stays with the `Child` and the
+    /// output goes to the null device.
+    write_only: bool,
+    catch_callback_panics: bool,
+    /// Frames stderr into lines even without [`Command::line_mode`].
+    stderr_lines: bool,
+    /// The level of [`Command::log_stderr`], which the `command` span takes.
+    log_stderr: Option<Level>,
+    redirect_stdout: Option<(PathBuf, OpenOptions)>,
+    redirect_stderr: Option<(PathBuf, OpenOptions)>,
+    /// The directory and size limit of [`Command::tee_stdout_rotating`].
+    tee_stdout: Option<(PathBuf, u64)>,
+    keep_rotated: Option<usize>,
+    decode_threads: usize,
+    spawn_timeout: Option<Duration>,
+    lazy: bool,
+    /// Along with its `Debug` form for the tracing span.
+    context: Option<(Context, String)>,
+    /// A child was spawned; the hooks and senders it took are gone.
+    has_run: bool,
 }
 
 impl From<StdCommand> for Command {
     fn from(std_command: StdCommand) -> Self {
-        Self { std_command }
+        Self {
+            std_command,
+            stdin_map: None,
+            stdin_rate_limit: None,
+            kill_on_canceller_drop: false,
+            cancel_signal: Signal::Kill,
+            kill_schedule: Arc::new([]),
+            line_mode: false,
+            trim_output: false,
+            stdin_mode: StdioMode::Piped,
+            stdout_mode: StdioMode::Piped,
+            stderr_mode: StdioMode::Piped,
+            on_spawn: None,
+            on_exit: None,
+            on_backlog: None,
+            on_stall: None,
+            read_limit: None,
+            stdout_into: None,
+            stderr_into: None,
+            #[cfg(feature = "bytes")]
+            stdout_bytes_into: None,
+            #[cfg(feature = "bytes")]
+            stderr_bytes_into: None,
+            stdout_pairs_into: None,
+            stdout_writer: None,
+            kill_on_writer_error: false,
+            stderr_pairs_into: None,
+            capture_after: None,
+            record_sentinel: None,
+            normalize_newlines: false,
+            strip_ansi: false,
+            dedup_consecutive: false,
+            #[cfg(all(unix, feature = "signal-forwarding"))]
+            forward_signals: Vec::new(),
+            record_invocation: false,
+            #[cfg(unix)]
+            reset_signal_handlers: false,
+            #[cfg(unix)]
+            inherit_fds: Vec::new(),
+            #[cfg(unix)]
+            memory_limit: None,
+            #[cfg(unix)]
+            cpu_time_limit: None,
+            #[cfg(target_os = "linux")]
+            namespaces: 0,
+            #[cfg(unix)]
+            credentials: (None, None),
+            arg0: None,
+            clock: Arc::new(SystemClock),
+            poll_interval: POLL_INTERVAL,
+            fail_on_stderr: false,
+            fail_on_nonzero: false,
+            kill_on_stderr: false,
+            #[cfg(feature = "tokio")]
+            async_stdin: None,
+            #[cfg(feature = "rtrb")]
+            stdout_ring: None,
+            sync_pwd: false,
+            env_cleared: false,
+            scoped_env: BTreeSet::new(),
+            write_only: false,
+            catch_callback_panics: false,
+            stderr_lines: false,
+            log_stderr: None,
+            redirect_stdout: None,
+            redirect_stderr: None,
+            tee_stdout: None,
+            keep_rotated: None,
+            decode_threads: 0,
+            spawn_timeout: None,
+            lazy: false,
+            context: None,
+            has_run: false,
+        }
     }
 }
 
-pub struct StdinSender(Sender<String>);
-pub struct StdoutReceiver(Receiver<String>);
-pub struct StderrReceiver(Receiver<String>);
+pub struct StdinSender {
+    tx: Sender<String>,
+    bytes_written: Arc<AtomicUsize>,
+    write_failure: WriteFailure,
+    /// Shared with the stdin thread so that stdin can be closed while the thread
+    /// waits for input; `None` for a mocked handle.
+    pipe: Option<SharedPipe>,
+    idle: Arc<Mutex<IdleClose>>,
+    clock: Arc<dyn Clock>,
+    poll_interval: Duration,
+    created: Instant,
+    /// See [`StdinSender::send_if_idle`].
+    awaiting_response: Arc<AtomicBool>,
+    /// Set for a lazy handle, whose sends go to the child once it runs.
+    lazy: Option<Arc<LazyStart>>,
+}
 
-impl Command {
-    pub fn run(
-        &mut self,
-        canceller: Canceller,
-    ) -> Result<(StdinSender, StdoutReceiver, StderrReceiver), Error> {
-        let cmd = self
-            .std_command
-            .get_program()
-            .to_owned()
-            .into_string()
+impl Drop for StdinSender {
+    fn drop(&mut self) {
+        if let Some(lazy) = &self.lazy {
+            lazy.close_stdin();
+        }
+    }
+}
+
+/// The child's stdin, `None` once it has been closed. The lock is only held to
+/// look at or take the pipe: writes go through a clone of the inner `Arc`, so
+/// closing never waits for a write stuck on a full pipe. The descriptor then
+/// closes once that write returns.
+type SharedPipe = Arc<Mutex<Option<Arc<ChildStdin>>>>;
+
+/// State of [`StdinSender::auto_close_after_idle`].
+struct IdleClose {
+    last_send: Instant,
+    after: Option<Duration>,
+    closed: bool,
+}
+// the `Cell` counts the messages received so far, the `Option` is set for a
+// lazy handle, see `Command::lazy`, and the `bool` is `Command::line_mode`
+pub struct StdoutReceiver(
+    Receiver<String>,
+    Arc<AtomicUsize>,
+    ReadFailure,
+    Cell<usize>,
+    Option<Arc<LazyStart>>,
+    bool,
+);
+pub struct StderrReceiver(
+    Receiver<String>,
+    Arc<AtomicUsize>,
+    Cell<usize>,
+    Option<Arc<LazyStart>>,
+);
+pub struct ErrorReceiver(Receiver<Error>);
+
+/// A message along with its 1-based number within its stream, see
+/// [`StdoutReceiver::numbered`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NumberedLine {
+    pub stream_line_no: usize,
+    pub data: String,
+}
+
+/// What [`ProcessHandle::items`] yields: the stdout lines, then the status.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Item {
+    Line(String),
+    Exit(ExitStatus),
+}
+
+/// A running (or mocked) command. Like `std::process::Child`, the stdio ends are
+/// public so they can be `take`n and moved to other threads independently.
+pub struct ProcessHandle {
+    pub stdin: Option<StdinSender>,
+    /// Set instead of `stdin` with [`Command::async_stdin`].
+    #[cfg(feature = "tokio")]
+    pub async_stdin: Option<AsyncStdinSender>,
+    pub stdout: Option<StdoutReceiver>,
+    /// Set instead of `stdout` with [`Command::stdout_ring`].
+    #[cfg(feature = "rtrb")]
+    pub stdout_ring: Option<rtrb::Consumer<u8>>,
+    pub stderr: Option<StderrReceiver>,
+    /// Errors the background threads run into: failed stdin writes other than
+    /// the child closing its stdin, failed stdout or stderr reads and failed
+    /// kills. They are reported here as well as where they show otherwise. The
+    /// channel closes once the child was reaped, its output was read to the end
+    /// and stdin was closed; `None` for a mocked handle.
+    pub errors: Option<ErrorReceiver>,
+    child: Option<Arc<Mutex<Child>>>,
+    exit_status: Option<ExitStatus>,
+    recording: Option<Recording>,
+    stdout_backlog: Arc<AtomicUsize>,
+    stderr_backlog: Arc<AtomicUsize>,
+    stdout_peak_backlog: Arc<AtomicUsize>,
+    stderr_peak_backlog: Arc<AtomicUsize>,
+    /// Number of the stdout message that is a final line lacking its newline, 0
+    /// for none, so that collecting does not add one.
+    stdout_unterminated: Arc<AtomicUsize>,
+    stderr_unterminated: Arc<AtomicUsize>,
+    cancel: Option<CancelWiring>,
+    /// Messages are lines without their terminator, see [`Command::line_mode`].
+    line_mode: bool,
+    trim_output: bool,
+    poll_interval: Duration,
+    /// Times [`ProcessHandle::shutdown`], deadlines and [`Expect`] steps.
+    clock: Arc<dyn Clock>,
+    /// Stdout and stderr an [`Expect`] script read past its last match, for the
+    /// next script.
+    expect_buffer: Mutex<(String, String)>,
+    readers: Arc<OpenReaders>,
+    /// The decoded stderr reader alone, which feeds `first_stderr` and the
+    /// stderr tail, so that their checks wait for the end of stderr only.
+    stderr_reader: Arc<OpenReaders>,
+    latest_line: SharedMessage,
+    /// The first stderr message, kept with [`Command::fail_on_stderr`].
+    first_stderr: Option<SharedMessage>,
+    /// The first panic message, with [`Command::catch_callback_panics`].
+    panicked: Option<SharedMessage>,
+    /// The program and the stderr tail, with [`Command::fail_on_nonzero`].
+    nonzero: Option<(String, Arc<Mutex<String>>)>,
+    /// Set once [`Command::read_limit`] killed the child.
+    read_limit_reached: Arc<AtomicBool>,
+    context: Option<Context>,
+    /// Set until a lazy handle is turned into the one of its child.
+    lazy: Option<Arc<LazyStart>>,
+}
+
+/// Reader threads that have not delivered their last message yet.
+#[derive(Default)]
+struct OpenReaders {
+    count: Mutex<usize>,
+    closed: Condvar,
+}
+
+impl OpenReaders {
+    /// Counts `reader` as open until it returns, or unwinds.
+    fn track(self: &Arc<Self>, reader: Worker) -> Worker {
+        struct Guard(Arc<OpenReaders>);
+        impl Drop for Guard {
+            fn drop(&mut self) {
+                *self.0.count.lock().unwrap() -= 1;
+                self.0.closed.notify_all();
+            }
+        }
+        *self.count.lock().unwrap() += 1;
+        let guard = Guard(Arc::clone(self));
+        Box::new(move || {
+            let _guard = guard;
+            reader()
+        })
+    }
+
+    /// Blocks until all readers returned, up to `grace`; returns whether they did.
+    fn wait_closed(&self, grace: Duration) -> bool {
+        let count = self.count.lock().unwrap();
+        let (count, _) = self
+            .closed
+            .wait_timeout_while(count, grace, |open| *open > 0)
             .unwrap();
-        trace!("preparing to run '{cmd:}'");
-        let (tx_in, rx_in) = channel::<String>();
-        let (tx_out, rx_out) = channel::<String>();
-        let (tx_err, rx_err) = channel::<String>();
-        let mut pid = self
-            .std_command
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()?;
-        if let Some(mut stdin) = pid.stdin.take() {
-            let cmd = cmd.clone();
-            thread::spawn(move || {
-                trace!("'{cmd:}' is in stdin recv");
-                while let Ok(stdin_text) = rx_in.recv() {
-                    let stdin_text: String = stdin_text;
-                    trace!("'{cmd:}' received '{stdin_text}' in stdin thread");
-                    stdin.write_all(stdin_text.as_bytes()).unwrap();
+        *count == 0
+    }
+}
+
+/// What a canceller thread needs, kept by the handle so that a canceller can be
+/// attached after spawning.
+#[derive(Clone)]
+struct CancelWiring {
+    cmd: String,
+    child: Arc<Mutex<Child>>,
+    /// Bumped for every canceller attached; only the latest one may kill.
+    generation: Arc<AtomicUsize>,
+    kill_on_drop: bool,
+    signal: Signal,
+    /// See [`Command::kill_schedule`], used by cancellers instead of `signal`.
+    schedule: Arc<[(Duration, Signal)]>,
+    poll_interval: Duration,
+    /// Times the kill schedule.
+    clock: Arc<dyn Clock>,
+    /// Set once a canceller killed the still running child.
+    cancelled: Arc<AtomicBool>,
+    /// Shared by all clones, let go of by the monitor thread once the child
+    /// was reaped so that [`ProcessHandle::errors`] can close.
+    errors: Arc<Mutex<Option<Sender<Error>>>>,
+}
+
+impl CancelWiring {
+    fn worker(&self, canceller: Canceller) -> Worker {
+        let wiring = self.clone();
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        Box::new(move || {
+            let current = || wiring.generation.load(Ordering::SeqCst) == generation;
+            let cancelled = loop {
+                match canceller.0.recv_timeout(wiring.poll_interval) {
+                    Ok(()) => break true,
+                    Err(RecvTimeoutError::Disconnected) => break false,
+                    Err(RecvTimeoutError::Timeout) if !current() => {
+                        trace!("retiring a replaced canceller of '{}'", wiring.cmd);
+                        return;
+                    }
+                    Err(RecvTimeoutError::Timeout) => {}
                 }
-                trace!("exiting the stdin thread of '{cmd:}'");
-            });
+            };
+            if current() && (cancelled || wiring.kill_on_drop) {
+                wiring.escalate();
+            }
+            trace!("exiting the canceller thread of '{}'", wiring.cmd);
+        })
+    }
+
+    /// Signals the child unless it exited already; returns whether it did.
+    fn kill(&self) -> bool {
+        self.send(self.signal)
+    }
+
+    /// Works through the kill schedule, or else just kills.
+    fn escalate(&self) {
+        if self.schedule.is_empty() {
+            self.kill();
+            return;
         }
-        if let Some(mut stdout) = pid.stdout.take() {
-            let cmd = cmd.clone();
-            thread::spawn(move || {
-                let mut buf: [u8; 128] = [0; 128];
-                trace!("'{cmd:}' is in stdout read");
-                while let Ok(read_bytes) = stdout.read(&mut buf) {
-                    if read_bytes == 0 {
-                        trace!("'{cmd:}' stdout closed");
-                        break;
-                    }
-                    let stdout_text = String::from_utf8_lossy(&buf[0..read_bytes]);
-                    trace!("'{cmd:}' received '{stdout_text}' in stdout thread");
-                    if tx_out.send(stdout_text.into_owned()).is_err() {
-                        break;
-                    }
+        let started = self.clock.now();
+        for &(after, signal) in self.schedule.iter() {
+            loop {
+                let elapsed = self.clock.now().saturating_duration_since(started);
+                let left = after.saturating_sub(elapsed);
+                if left.is_zero() {
+                    break;
                 }
-                trace!("exiting the stdout thread of '{cmd:}'");
-            });
+                if !matches!(self.child.lock().unwrap().try_wait(), Ok(None)) {
+                    return;
+                }
+                thread::sleep(left.min(self.poll_interval));
+            }
+            if !self.send(signal) {
+                return;
+            }
         }
+    }
 
-        if let Some(mut stderr) = pid.stderr.take() {
-            let cmd = cmd.clone();
-            thread::spawn(move || {
-                let mut buf: [u8; 128] = [0; 128];
-                trace!("'{cmd:}' is in stderr read");
-                while let Ok(read_bytes) = stderr.read(&mut buf) {
-                    if read_bytes == 0 {
-                        trace!("'{cmd:}' stderr closed");
-                        break;
-                    }
-                    let stderr_text = String::from_utf8_lossy(&buf[0..read_bytes]);
-                    trace!("'{cmd:}' received '{stderr_text}' in stderr thread");
-                    if tx_err.send(stderr_text.into_owned()).is_err() {
-                        break;
-                    }
+    fn send(&self, signal: Signal) -> bool {
+        let mut child = self.child.lock().unwrap();
+        if let Ok(None) = child.try_wait() {
+            self.cancelled.store(true, Ordering::SeqCst);
+            if let Err(e) = signal::send(&mut child, signal) {
+                trace!("cancelling '{}' failed: {e}", self.cmd);
+                if let Some(errors) = self.errors.lock().unwrap().as_ref() {
+                    let _ = errors.send(e.into());
                 }
-                trace!("exiting the stderr thread of '{cmd:}'");
-            });
+            }
+            return true;
         }
+        false
+    }
+}
 
-        let cmd_ = cmd.clone();
-        thread::spawn(move || {
-            if canceller.0.recv().is_ok() {
-                let _ = pid.kill();
-            }
-            trace!("exiting the canceller thread of '{cmd_:}'");
-        });
+/// How one of the child's standard streams is connected, see [`Command::stdio`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StdioMode {
+    /// A pipe served by the handle.
+    Piped,
+    /// The null device.
+    Null,
+    /// The same stream as this process's.
+    Inherit,
+}
 
-        trace!("exiting run '{cmd:}'");
-        Ok((
-            StdinSender(tx_in),
-            StdoutReceiver(rx_out),
-            StderrReceiver(rx_err),
-        ))
+impl StdioMode {
+    fn stdio(self) -> Stdio {
+        match self {
+            StdioMode::Piped => Stdio::piped(),
+            StdioMode::Null => Stdio::null(),
+            StdioMode::Inherit => Stdio::inherit(),
+        }
     }
 }
 
-#[derive(Debug, ThisError)]
-pub enum Error {
-    #[error(transparent)]
-    IoError(#[from] io::Error),
-    #[error(transparent)]
-    SendError(#[from] mpsc::SendError<String>),
-    #[error("thread could not join")]
-    ThreadCouldNotJoin(String),
+/// Which output stream a chunk was read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StreamKind {
+    Stdout,
+    Stderr,
 }
 
-impl StdinSender {
-    pub fn send(&self, input: String) -> Result<(), SendError<String>> {
-        self.0.send(input)
+/// Everything a command printed, along with how it exited.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Output {
+    pub status: ExitStatus,
+    pub stdout: String,
+    pub stderr: String,
+    /// Filled in when [`Command::record_invocation`] is on.
+    pub invocation: Option<Invocation>,
+    /// How the run ended, so callers need not piece it together from the status.
+    pub result: RunResult,
+}
+
+impl Output {
+    /// Whether a deadline cut the run short, the output is partial then.
+    pub fn timed_out(&self) -> bool {
+        self.result == RunResult::TimedOut
     }
 }
 
-impl StdoutReceiver {
-    pub fn recv(&self) -> Result<String, RecvError> {
-        self.0.recv()
+/// How a run ended. Anything but `Completed` means this crate killed the child,
+/// the status is then the one the kill left and the output may be partial.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunResult {
+    /// The child exited on its own, successfully or not.
+    Completed(ExitStatus),
+    /// A canceller killed it, see [`ProcessHandle::was_cancelled`].
+    Cancelled,
+    /// A deadline killed it, see [`Command::run_with_deadline`].
+    TimedOut,
+    /// It printed more than [`Command::read_limit`] allows.
+    OutputLimited,
+}
+
+impl RunResult {
+    /// The NTSTATUS a crashed child left as its exit code, e.g. an access
+    /// violation, `None` for ordinary exit codes and runs this crate ended.
+    #[cfg(windows)]
+    pub fn nt_status(&self) -> Option<NtStatus> {
+        match self {
+            RunResult::Completed(status) => status.code().and_then(NtStatus::from_code),
+            _ => None,
+        }
     }
 }
 
-impl StderrReceiver {
-    pub fn recv(&self) -> Result<String, RecvError> {
-        self.0.recv()
+/// A complete record of one run, enough to reproduce it or to turn it into a
+/// regression fixture. Serializable with the `serde` feature.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Invocation {
+    pub program: String,
+    pub args: Vec<String>,
+    /// Variables set (`Some`) or removed (`None`) on top of the inherited ones.
+    pub env: Vec<(String, Option<String>)>,
+    pub current_dir: Option<String>,
+    /// Everything written to the child's stdin, after [`Command::map_stdin`].
+    pub stdin: String,
+    pub stdout: String,
+    pub stderr: String,
+    /// `None` if the child was terminated by a signal.
+    pub exit_code: Option<i32>,
+}
+
+/// An [`Invocation`] being recorded while the child runs.
+struct Recording {
+    invocation: Invocation,
+    stdin: Arc<Mutex<String>>,
+}
+
+/// The part of [`Command`] that application code depends on. Code written against
+/// this trait can be unit tested with a mock returning a handle built by
+/// [`ProcessHandle::from_channels`] instead of spawning real processes.
+pub trait CommandRunner {
+    fn run(&mut self, canceller: Canceller) -> Result<ProcessHandle, Error>;
+}
+
+impl CommandRunner for Command {
+    fn run(&mut self, canceller: Canceller) -> Result<ProcessHandle, Error> {
+        Command::run(self, canceller)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use anyhow::Result as AnyResult;
-    // use pretty_assertions::assert_eq;
-    use simple_broadcaster::broadcasting_channel;
-    use std::{thread, time::Duration};
+impl Command {
+    /// Builds a command from a line like `git commit -m "initial commit"`, split
+    /// into program and arguments the way a POSIX shell splits words: single
+    /// quotes keep everything, double quotes all but the escapes `\"`, `\\`,
+    /// `\$` and `` \` ``, and a backslash outside quotes escapes any character. No
+    /// shell runs, so there is no expansion, globbing or redirection. Fails with
+    /// [`Error::InvalidCommandLine`] on an unbalanced quote, a trailing backslash
+    /// or an empty line.
+    pub fn parse(line: &str) -> Result<Self, Error> {
+        let words = split_words(line).map_err(Error::InvalidCommandLine)?;
+        let (program, args) = words
+            .split_first()
+            .ok_or_else(|| Error::InvalidCommandLine("no program".to_owned()))?;
+        let mut std_command = StdCommand::new(program);
+        std_command.args(args);
+        Ok(std_command.into())
+    }
 
-    #[test]
-    fn kill_test() -> AnyResult<()> {
-        let _ = tracing_subscriber::fmt::try_init();
-        let (broadcaster, subscriber) = broadcasting_channel("test the managed command");
-        let mut std_cmd = std::process::Command::new("managed-command-test-process");
-        std_cmd.env("PATH", "testing");
-        let mut cmd = Command::from(std_cmd);
-        let (_stdin, _stdout, _stderr) = cmd.run(subscriber.into())?;
-        trace!("will wait for 1 sec");
-        thread::sleep(Duration::from_secs(1));
-        trace!("will kill the process now. and sleep for 1 more sec");
-        broadcaster.broadcast(())?;
-        thread::sleep(Duration::from_secs(1));
-        Ok(())
+    /// Applies `f` to every stdin message in the stdin thread, right before it is
+    /// written to the child. Useful to audit or normalize what gets sent.
+    pub fn map_stdin(&mut self, f: impl Fn(String) -> String + Send + 'static) -> &mut Self {
+        self.stdin_map = Some(Box::new(f));
+        self
     }
 
-    #[test]
-    fn check_input_output() -> AnyResult<()> {
-        let _ = tracing_subscriber::fmt::try_init();
-        let (broadcaster, subscriber) = broadcasting_channel("test the managed command");
-        let mut std_cmd = std::process::Command::new("managed-command-test-process");
-        std_cmd.env("PATH", "testing");
-        let mut cmd = Command::from(std_cmd);
-        let (stdin, stdout, _stderr) = cmd.run(subscriber.into())?;
-        let handle = thread::spawn(move || {
-            while let Ok(out) = stdout.recv() {
-                trace!("received: '{}'", out.trim());
+    /// Paces the writes to the child's stdin to stay under `limiter`'s rate.
+    /// Commands sharing a limiter share its rate, so none of them can hog the
+    /// writes. Counted after [`Command::map_stdin`]; the stdin thread waits, so
+    /// sends queue up meanwhile.
+    pub fn stdin_rate_limit(&mut self, limiter: RateLimiter) -> &mut Self {
+        self.stdin_rate_limit = Some(limiter);
+        self
+    }
+
+    /// [`Command::map_stdin`] followed by the wait for [`Command::stdin_rate_limit`].
+    fn take_stdin_map(&mut self) -> Option<StdinMap> {
+        let map = self.stdin_map.take();
+        let Some(limiter) = self.stdin_rate_limit.clone() else {
+            return map;
+        };
+        let (clock, poll_interval) = (Arc::clone(&self.clock), self.poll_interval);
+        Some(Box::new(move |input| {
+            let input = match &map {
+                Some(map) => map(input),
+                None => input,
+            };
+            limiter.acquire(input.len(), &*clock, poll_interval);
+            input
+        }))
+    }
+
+    /// Spawns the child with stdin connected to the null device, so it sees EOF
+    /// right away. No stdin thread is started and [`ProcessHandle::stdin`] is `None`.
+    /// Output-only commands should use this, as whether the caller will ever send
+    /// cannot be told at spawn time.
+    #[doc(alias = "no_stdin")]
+    pub fn stdin_null(&mut self) -> &mut Self {
+        self.stdin_mode = StdioMode::Null;
+        self
+    }
+
+    /// Sends the child's stderr to the null device, the output side of
+    /// [`Command::stdin_null`], dropping any [`Command::redirect_stderr`]. No
+    /// stderr thread or channel is created and [`ProcessHandle::stderr`] is
+    /// `None`; with both, a run costs just the stdout thread and the canceller's.
+    pub fn discard_stderr(&mut self) -> &mut Self {
+        self.stderr_mode = StdioMode::Null;
+        self.redirect_stderr = None;
+        self
+    }
+
+    /// Sets how all three standard streams are connected at once, dropping any
+    /// earlier [`Command::redirect_stdout`] or [`Command::redirect_stderr`]. The
+    /// handle's end of a stream that is not piped is `None`.
+    pub fn stdio(&mut self, stdin: StdioMode, stdout: StdioMode, stderr: StdioMode) -> &mut Self {
+        (self.stdin_mode, self.stdout_mode, self.stderr_mode) = (stdin, stdout, stderr);
+        (self.redirect_stdout, self.redirect_stderr) = (None, None);
+        self
+    }
+
+    /// Pipes all three streams, the default.
+    pub fn capture_all(&mut self) -> &mut Self {
+        self.stdio(StdioMode::Piped, StdioMode::Piped, StdioMode::Piped)
+    }
+
+    /// Lets the child share all three streams of this process, like a shell
+    /// running it in the foreground. No stdio threads are started.
+    pub fn inherit_all(&mut self) -> &mut Self {
+        self.stdio(StdioMode::Inherit, StdioMode::Inherit, StdioMode::Inherit)
+    }
+
+    /// How many pipe ends this process holds for the child while it runs, one
+    /// per stream that is piped rather than redirected, nulled or inherited.
+    pub fn fd_count(&self) -> usize {
+        let piped_output = |redirect: &Option<(PathBuf, OpenOptions)>, mode| {
+            redirect.is_none() && !self.write_only && mode == StdioMode::Piped
+        };
+        usize::from(self.stdin_mode == StdioMode::Piped)
+            + usize::from(piped_output(&self.redirect_stdout, self.stdout_mode))
+            + usize::from(piped_output(&self.redirect_stderr, self.stderr_mode))
+    }
+
+    /// Called with the child's pid right after it has been spawned, on the thread
+    /// that runs the command. [`callback_context`] returns the command's context
+    /// in here.
+    pub fn on_spawn(&mut self, f: impl Fn(u32) + Send + 'static) -> &mut Self {
+        self.on_spawn = Some(Box::new(f));
+        self
+    }
+
+    /// Called from a monitor thread with the exit status and run time once the
+    /// child has exited, whether or not anyone waits on the handle.
+    /// [`callback_context`] returns the command's context in here.
+    pub fn on_exit(&mut self, f: impl Fn(ExitStatus, Duration) + Send + 'static) -> &mut Self {
+        self.on_exit = Some(Box::new(f));
+        self
+    }
+
+    /// Called when more than `threshold` messages wait in the built-in stdout or
+    /// stderr channel, i.e. the consumer is falling behind. It fires once per
+    /// crossing and again only after that stream's backlog has dropped back to the
+    /// threshold. It runs on the reader thread, which delivers nothing meanwhile.
+    pub fn on_backlog(&mut self, threshold: usize, f: impl Fn() + Send + 'static) -> &mut Self {
+        self.on_backlog = Some((threshold, Arc::new(Mutex::new(Box::new(f)))));
+        self
+    }
+
+    /// Called when neither stdout nor stderr delivered anything for `after` while
+    /// the child is still running, e.g. to show that it is still working. It
+    /// fires once per quiet spell and is armed again by the next message; the
+    /// child is left alone. It runs on a watchdog thread of its own, which
+    /// checks at [`Command::poll_interval`] and ends with the child. Output read
+    /// undecoded, as by [`Command::stdout_pairs_into`], does not count.
+    pub fn on_stall(&mut self, after: Duration, f: impl Fn() + Send + 'static) -> &mut Self {
+        self.on_stall = Some((after, Box::new(f)));
+        self
+    }
+
+    /// Attaches `context`, e.g. a job id, to correlate this command in logs and
+    /// lifecycle callbacks. The spawn and every thread serving the child, the
+    /// [`Command::on_spawn`] and [`Command::on_exit`] callbacks included, run in a
+    /// `command` tracing span with `context` as a `Debug` field, at the trace
+    /// level unless [`Command::log_stderr`] sets another.
+    /// [`ProcessHandle::context`] hands it back, and [`callback_context`] hands it
+    /// to the callbacks, which are not passed the handle.
+    pub fn with_context<C: Debug + Send + Sync + 'static>(&mut self, context: C) -> &mut Self {
+        let debug = format!("{context:?}");
+        self.context = Some((Arc::new(context), debug));
+        self
+    }
+
+    /// Fails `run` with [`Error::SpawnTimeout`] if creating the process takes
+    /// longer than `timeout`, e.g. for a binary on a hung network mount. The
+    /// spawn then happens on a helper thread, which kills the child should the
+    /// spawn still succeed later. The command counts as run either way. Nothing
+    /// limits the child once it runs, see [`Command::run_with_deadline`] for that.
+    pub fn spawn_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.spawn_timeout = Some(timeout);
+        self
+    }
+
+    /// Has `run` hand out the handle without spawning the child, for speculative
+    /// setups whose children may never be used. The command is moved into the
+    /// handle and run, threads and all, on the first send to stdin, receive from
+    /// stdout or stderr, or call of a handle method that needs the child, such
+    /// as [`ProcessHandle::wait`]. Spawn errors therefore surface there rather
+    /// than from `run`: the first of these calls that can return an error
+    /// returns it, a receive sees the channel closed, and later calls fail with
+    /// [`Error::NoChild`]. Only the plain stdio channels are handed out; the
+    /// handle's `errors` is `None` and the byte and backlog counters stay 0,
+    /// except for [`StdinSender::bytes_written`]. [`Command::run_with_deadline`]
+    /// and [`race`] ignore this and spawn right away.
+    pub fn lazy(&mut self, lazy: bool) -> &mut Self {
+        self.lazy = lazy;
+        self
+    }
+
+    /// How often the background threads and [`ProcessHandle::wait`] check whether
+    /// the child has exited; 10ms by default. A shorter interval notices the exit
+    /// sooner, a longer one wakes the CPU less often, which matters on battery
+    /// and with many children running.
+    pub fn poll_interval(&mut self, interval: Duration) -> &mut Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Reads the time from `clock` instead of the system clock for the time-based
+    /// features: the run time passed to [`Command::on_exit`], [`Command::on_stall`],
+    /// [`Command::kill_schedule`], [`Command::stdin_rate_limit`],
+    /// [`Command::run_with_deadline`], [`ProcessHandle::shutdown`],
+    /// [`ProcessHandle::join_with_deadline`], [`Expect::timeout`] and the timing of
+    /// [`StdinSender`]. Waits are cut into slices of at most
+    /// [`Command::poll_interval`] of real time, after each of which the clock is
+    /// read again. Not covered: [`Command::spawn_timeout`], which has to bound a
+    /// spawn that blocks in the OS, the grace of
+    /// [`ProcessHandle::wait_for_output_drain`], [`Command::cpu_time_limit`],
+    /// which the kernel enforces, and the poll interval itself.
+    /// Meant for tests, e.g. with a `ManualClock` from the `test-util` feature.
+    pub fn clock(&mut self, clock: impl Clock) -> &mut Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Delivers stdout and stderr one line per message instead of raw read chunks.
+    /// Lines split across reads are reassembled, the line terminator is stripped
+    /// and a final unterminated line is delivered when the stream closes.
+    pub fn line_mode(&mut self, line_mode: bool) -> &mut Self {
+        self.line_mode = line_mode;
+        self
+    }
+
+    /// Strips trailing whitespace off the `stdout` and `stderr` of the [`Output`]
+    /// the collecting helpers return, like `$(cmd)` in a shell. Streamed messages
+    /// and [`Output::invocation`] keep the exact text.
+    pub fn trim_output(&mut self, trim: bool) -> &mut Self {
+        self.trim_output = trim;
+        self
+    }
+
+    /// Cancels the child once `limit` worth of stdout has been delivered, then
+    /// closes stdout. Meant for sampling endless producers like `yes`; unlike an
+    /// error, reaching the limit is the expected way for such a run to end.
+    pub fn read_limit(&mut self, limit: ReadLimit) -> &mut Self {
+        self.read_limit = Some(limit);
+        self
+    }
+
+    /// Delivers stdout into `tx` instead of a channel of the crate's own; the
+    /// handle's stdout is then `None`.
+    pub fn stdout_into(&mut self, tx: impl OutputSender) -> &mut Self {
+        self.stdout_into = Some(Box::new(tx));
+        self
+    }
+
+    /// Delivers stderr into `tx` instead of a channel of the crate's own; the
+    /// handle's stderr is then `None`.
+    pub fn stderr_into(&mut self, tx: impl OutputSender) -> &mut Self {
+        self.stderr_into = Some(Box::new(tx));
+        self
+    }
+
+    /// Delivers stdout undecoded into `tx`, bypassing line mode and the other text
+    /// options; the handle's stdout is then `None`. The chunks are slices of one
+    /// reused read buffer instead of an allocation each, which pays off for very
+    /// high-throughput output. [`Command::read_limit`] applies, with each chunk as
+    /// a message. The channel being the caller's, the backlog is not tracked,
+    /// like with [`Command::stdout_into`].
+    #[cfg(feature = "bytes")]
+    pub fn stdout_bytes_into(&mut self, tx: Sender<Bytes>) -> &mut Self {
+        self.stdout_bytes_into = Some(tx);
+        self
+    }
+
+    /// Delivers stderr undecoded into `tx`, see [`Command::stdout_bytes_into`].
+    #[cfg(feature = "bytes")]
+    pub fn stderr_bytes_into(&mut self, tx: Sender<Bytes>) -> &mut Self {
+        self.stderr_bytes_into = Some(tx);
+        self
+    }
+
+    /// Delivers each read of stdout into `tx` both as the bytes read and decoded,
+    /// e.g. to pass the output on verbatim while parsing it. Line mode and the
+    /// other text options are bypassed and the handle's stdout is then `None`. A
+    /// character cut in two by a read is decoded with the read completing it.
+    pub fn stdout_pairs_into(&mut self, tx: Sender<(Vec<u8>, String)>) -> &mut Self {
+        self.stdout_pairs_into = Some(tx);
+        self
+    }
+
+    /// Delivers stderr as bytes and decoded, see [`Command::stdout_pairs_into`].
+    pub fn stderr_pairs_into(&mut self, tx: Sender<(Vec<u8>, String)>) -> &mut Self {
+        self.stderr_pairs_into = Some(tx);
+        self
+    }
+
+    /// Copies stdout undecoded into `writer`, e.g. a `TcpStream` to forward logs,
+    /// with no channel in between; the handle's stdout is then `None`. Line mode
+    /// and the other text options are bypassed. Once a write fails, e.g. because
+    /// the peer closed the connection, the stdout thread stops reading, so a
+    /// child writing on blocks on the full pipe unless
+    /// [`Command::kill_on_writer_error`] is set.
+    pub fn stdout_to_writer(&mut self, writer: impl Write + Send + 'static) -> &mut Self {
+        self.stdout_writer = Some(Box::new(writer));
+        self
+    }
+
+    /// Kills the child when writing to [`Command::stdout_to_writer`] fails.
+    pub fn kill_on_writer_error(&mut self, kill: bool) -> &mut Self {
+        self.kill_on_writer_error = kill;
+        self
+    }
+
+    /// Reads stdout straight into a lock-free ring buffer of `capacity` bytes: the
+    /// handle gets the consumer end as [`ProcessHandle::stdout_ring`] instead of
+    /// a `stdout`. There is no channel send nor allocation per read, for the
+    /// highest throughput on very large output. Line mode and the other text
+    /// options are bypassed. The reader waits while the buffer is full, and the
+    /// consumer sees the end of the stream as the producer being abandoned; check
+    /// that before looking for the bytes left, so the last ones are not missed.
+    #[cfg(feature = "rtrb")]
+    pub fn stdout_ring(&mut self, capacity: usize) -> &mut Self {
+        self.stdout_ring = Some(capacity);
+        self
+    }
+
+    /// Feeds stdin from async code: the handle gets an
+    /// [`ProcessHandle::async_stdin`] instead of a blocking `stdin`. Inputs are
+    /// queued in a channel of `capacity` messages, so a sender awaits while the
+    /// child is slow to read; the writes happen on the stdin thread and never
+    /// block the runtime. Runtime agnostic, though built on `tokio::sync`.
+    #[cfg(feature = "tokio")]
+    pub fn async_stdin(&mut self, capacity: usize) -> &mut Self {
+        self.async_stdin = Some(capacity);
+        self
+    }
+
+    /// Sets an environment variable for the child, like `std::process::Command`
+    /// does.
+    pub fn env(&mut self, key: impl AsRef<OsStr>, val: impl AsRef<OsStr>) -> &mut Self {
+        self.scoped_env.remove(key.as_ref());
+        self.std_command.env(key, val);
+        self
+    }
+
+    /// Clears the environment of the child, like `std::process::Command` does.
+    pub fn env_clear(&mut self) -> &mut Self {
+        self.std_command.env_clear();
+        self.scoped_env.clear();
+        self.env_cleared = true;
+        self
+    }
+
+    /// The variables set or removed for the child, without those left over from
+    /// [`Command::run_with_env`].
+    fn explicit_env(&self) -> impl Iterator<Item = (&OsStr, Option<&OsStr>)> {
+        self.std_command
+            .get_envs()
+            .filter(|(key, _)| !self.scoped_env.contains(*key))
+    }
+
+    /// The environment the child would start with, sorted by name: that of this
+    /// process unless cleared, with the variables set or removed for the child
+    /// applied, and `PWD` as by [`Command::sync_pwd`]. Only a clear through this
+    /// type counts, `std::process::Command` does not tell whether it was cleared
+    /// before being converted.
+    pub fn effective_env(&self) -> Vec<(OsString, OsString)> {
+        let mut vars: BTreeMap<OsString, OsString> = match self.env_cleared {
+            true => BTreeMap::new(),
+            false => env::vars_os().collect(),
+        };
+        for (key, val) in self.explicit_env() {
+            match val {
+                Some(val) => vars.insert(key.to_owned(), val.to_owned()),
+                None => vars.remove(key),
+            };
+        }
+        if let (true, Some(dir)) = (self.sync_pwd, self.std_command.get_current_dir()) {
+            if let Ok(cwd) = env::current_dir() {
+                vars.insert("PWD".into(), cwd.join(dir).into_os_string());
             }
-        });
-        stdin.send("second input\n".to_owned())?;
-        stdin.send("first input\n".to_owned())?;
-        thread::sleep(Duration::from_secs(2));
-        broadcaster.broadcast(())?;
-        stdin.send("second input\n".to_owned())?;
-        let _ = handle.join();
-        Ok(())
+        }
+        vars.into_iter().collect()
     }
 
-    #[test]
-    fn check_only_output() -> AnyResult<()> {
-        let _ = tracing_subscriber::fmt::try_init();
-        let (broadcaster, subscriber) = broadcasting_channel("test the managed command");
-        let mut std_cmd = std::process::Command::new("managed-command-test-process");
-        std_cmd.env("PATH", "testing");
-        let mut cmd = Command::from(std_cmd);
-        let (_stdin, stdout, _stderr) = cmd.run(subscriber.into())?;
-        let thread_handle = thread::spawn(move || loop {
-            let out = stdout.recv().unwrap();
-            trace!("received: '{}'", out.trim());
-            //assert_eq!(out.trim(), "this is the default output!");
-        });
-        thread::sleep(Duration::from_secs(2));
-        broadcaster.broadcast(())?;
-        let _ = thread_handle.join();
+    /// Starts the child with an empty environment apart from the `allowlist`ed
+    /// variables of this process and those set explicitly, before or after this
+    /// call, through [`Command::env`]. Works through `env_clear`, so calling
+    /// `env_clear` on the underlying command afterwards drops the allowlisted
+    /// variables again. As nothing is inherited anymore, the `env` of a recorded
+    /// [`Invocation`] is then the complete environment of the child.
+    pub fn secure_env(&mut self, allowlist: &[&str]) -> &mut Self {
+        let explicit: Vec<(OsString, Option<OsString>)> = self
+            .explicit_env()
+            .map(|(key, val)| (key.to_owned(), val.map(OsStr::to_owned)))
+            .collect();
+        self.env_clear();
+        for key in allowlist {
+            if let Some(val) = env::var_os(key) {
+                self.std_command.env(key, val);
+            }
+        }
+        for (key, val) in explicit {
+            match val {
+                Some(val) => self.std_command.env(key, val),
+                None => self.std_command.env_remove(key),
+            };
+        }
+        self
+    }
+
+    /// Sets the child's working directory, like `std::process::Command` does.
+    pub fn current_dir(&mut self, dir: impl AsRef<Path>) -> &mut Self {
+        self.std_command.current_dir(dir);
+        self
+    }
+
+    /// Sets `PWD` for the child to its working directory, for children that trust
+    /// `$PWD` over the real cwd. Without this they see the `PWD` of this process
+    /// while running elsewhere. A relative directory is resolved against the cwd
+    /// of this process; without one set, nothing changes.
+    pub fn sync_pwd(&mut self, sync: bool) -> &mut Self {
+        self.sync_pwd = sync;
+        self
+    }
+
+    /// Catches panics of the callbacks run on the crate's threads, i.e.
+    /// [`Command::map_stdin`], [`Command::on_exit`] and custom [`OutputSender`]s.
+    /// The thread then ends as if its stream had closed, and
+    /// [`ProcessHandle::wait`] returns [`Error::CallbackPanicked`] instead of the
+    /// exit status. Without this such a panic just ends the thread. An `on_exit`
+    /// panic may come only after `wait` has returned.
+    pub fn catch_callback_panics(&mut self, catch: bool) -> &mut Self {
+        self.catch_callback_panics = catch;
+        self
+    }
+
+    /// Treats any stderr output as a failure: [`ProcessHandle::wait`], and so every
+    /// helper waiting on the child, returns [`Error::StderrProduced`] with the
+    /// first stderr message instead of the exit status. Not applied with
+    /// [`Command::stderr_bytes_into`].
+    pub fn fail_on_stderr(&mut self, fail: bool) -> &mut Self {
+        self.fail_on_stderr = fail;
+        self
+    }
+
+    /// Treats a non-zero exit as a failure: [`ProcessHandle::wait`], and so every
+    /// helper waiting on the child, returns [`Error::NonZeroExit`] with the tail
+    /// of stderr instead of the exit status. A child that exits right away, e.g.
+    /// on bad arguments, then fails loudly rather than just closing its streams
+    /// like a successful run without output would. The tail is kept whoever
+    /// receives stderr, but not with [`Command::stderr_bytes_into`].
+    pub fn fail_on_nonzero(&mut self, fail: bool) -> &mut Self {
+        self.fail_on_nonzero = fail;
+        self
+    }
+
+    /// Like [`Command::fail_on_stderr`], and kills the child on its first stderr
+    /// output rather than letting it run to the end.
+    pub fn kill_on_stderr(&mut self, kill: bool) -> &mut Self {
+        self.kill_on_stderr = kill;
+        self
+    }
+
+    /// Points the child's stdout straight at the file at `path`, opened with
+    /// `options` (e.g. `append(true).create(true)`), like `cmd >>out.log`. No
+    /// stdout thread is started and the handle's stdout is `None`. Both files are
+    /// opened before spawning, so a failure to open either starts nothing.
+    pub fn redirect_stdout(&mut self, path: impl Into<PathBuf>, options: OpenOptions) -> &mut Self {
+        self.redirect_stdout = Some((path.into(), options));
+        self
+    }
+
+    /// Also writes stdout, as it is delivered, to numbered files in `dir`:
+    /// `stdout.0.log` first, moving on to the next number before a file would grow
+    /// past `max_size` bytes. `dir` is created if needed and the files of an
+    /// earlier run are overwritten. A failed write is logged and ends the copy,
+    /// not the run. Nothing is written with [`Command::redirect_stdout`].
+    pub fn tee_stdout_rotating(&mut self, dir: impl Into<PathBuf>, max_size: u64) -> &mut Self {
+        self.tee_stdout = Some((dir.into(), max_size));
+        self
+    }
+
+    /// Keeps only the newest `count` files of [`Command::tee_stdout_rotating`],
+    /// at least one, removing the oldest as it rotates.
+    pub fn keep_rotated(&mut self, count: usize) -> &mut Self {
+        self.keep_rotated = Some(count.max(1));
+        self
+    }
+
+    /// Points the child's stderr at a file, see [`Command::redirect_stdout`].
+    pub fn redirect_stderr(&mut self, path: impl Into<PathBuf>, options: OpenOptions) -> &mut Self {
+        self.redirect_stderr = Some((path.into(), options));
+        self
+    }
+
+    /// Decodes stdout and stderr on `n` threads per stream besides the one reading
+    /// the pipe, so a child writing very fast is not throttled by a reader busy
+    /// decoding. The reads are then larger and the output is still delivered in
+    /// order. 0, the default, decodes on the reading thread.
+    pub fn decode_threads(&mut self, n: usize) -> &mut Self {
+        self.decode_threads = n;
+        self
+    }
+
+    /// Logs every stderr line as a `tracing` event at `level`, with the program
+    /// name in a `program` field. This takes the place of
+    /// [`Command::stderr_into`], so the handle's stderr is `None`. The events are
+    /// within the `command` span, which then has `level` too, so that a filter
+    /// letting the events through does not drop the span.
+    #[doc(alias = "stderr_to_tracing")]
+    pub fn log_stderr(&mut self, level: Level) -> &mut Self {
+        let program = self
+            .std_command
+            .get_program()
+            .to_string_lossy()
+            .into_owned();
+        self.stderr_lines = true;
+        self.log_stderr = Some(level);
+        self.stderr_into(TracingSender { program, level })
+    }
+
+    /// Drops stdout up to and including the first line containing `marker`, so
+    /// only what follows reaches the receiver. Handy to skip banners and setup
+    /// noise.
+    pub fn capture_after(&mut self, marker: String) -> &mut Self {
+        self.capture_after = Some(marker);
+        self
+    }
+
+    /// Delivers stdout as records rather than chunks or lines: the lines up to
+    /// a line that is exactly `sentinel`, joined by `\n`, without the sentinel
+    /// and a final newline. A last record the stream ends without a sentinel
+    /// after is delivered as well.
+    pub fn record_sentinel(&mut self, sentinel: String) -> &mut Self {
+        self.record_sentinel = Some(sentinel);
+        self
+    }
+
+    /// Turns `\r\n` and lone `\r` in stdout and stderr into `\n`, so output
+    /// reads the same whatever the child's platform conventions. A trailing `\r`
+    /// is held back until the next read shows whether a `\n` follows.
+    pub fn normalize_newlines(&mut self, normalize: bool) -> &mut Self {
+        self.normalize_newlines = normalize;
+        self
+    }
+
+    /// Removes ANSI escape sequences, such as colors and cursor movement, from
+    /// stdout and stderr, e.g. to keep logs of a colored tool plain. A sequence
+    /// cut in two by a read is held back until it is complete. Off by default,
+    /// which passes them on untouched.
+    pub fn strip_ansi(&mut self, strip: bool) -> &mut Self {
+        self.strip_ansi = strip;
+        self
+    }
+
+    /// Collapses runs of identical consecutive lines in stdout and stderr, for
+    /// children that repeat themselves: the first line of a run is delivered
+    /// right away, and once the run ends, with a different line or the end of
+    /// the stream, a line `(repeated N times)` tells how many copies were
+    /// dropped. Only takes effect with [`Command::line_mode`].
+    pub fn dedup_consecutive(&mut self, dedup: bool) -> &mut Self {
+        self.dedup_consecutive = dedup;
+        self
+    }
+
+    /// Relays `signals` received by this process to the child, so e.g. Ctrl+C at
+    /// the terminal reaches it too. This installs process-wide handlers through
+    /// `signal-hook` while the child runs, and with a handler installed this
+    /// process no longer gets the default action (like terminating on SIGINT)
+    /// for those signals, so only list the ones you mean to relay. The handlers
+    /// are unregistered within [`Command::poll_interval`] of the child exiting;
+    /// `signal-hook` keeps its hook in place though, so the default action does
+    /// not come back.
+    #[cfg(all(unix, feature = "signal-forwarding"))]
+    pub fn forward_signals(&mut self, signals: &[Signal]) -> &mut Self {
+        self.forward_signals = signals.to_vec();
+        self
+    }
+
+    /// Records program, arguments, environment changes, working directory and all
+    /// stdin written, so the [`Output`] returned by the collecting helpers carries
+    /// a complete [`Invocation`].
+    pub fn record_invocation(&mut self, record: bool) -> &mut Self {
+        self.record_invocation = record;
+        self
+    }
+
+    fn spawn_child(&mut self) -> Result<Child, Error> {
+        let Some(timeout) = self.spawn_timeout else {
+            return self.std_command.spawn().map_err(|e| self.spawn_error(e));
+        };
+        // the command travels to the helper thread and back, unless it is late
+        let placeholder = StdCommand::new(self.std_command.get_program());
+        let mut std_command = mem::replace(&mut self.std_command, placeholder);
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let spawned = std_command.spawn();
+            if let Err(SendError((Ok(mut child), _))) = tx.send((spawned, std_command)) {
+                trace!("spawned after the spawn timeout, killing the child");
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+        });
+        match rx.recv_timeout(timeout) {
+            Ok((spawned, std_command)) => {
+                self.std_command = std_command;
+                spawned.map_err(|e| self.spawn_error(e))
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                self.has_run = true;
+                Err(Error::SpawnTimeout(timeout))
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                self.has_run = true;
+                Err(Error::ThreadCouldNotJoin("spawn".to_owned()))
+            }
+        }
+    }
+
+    fn spawn_error(&self, e: io::Error) -> Error {
+        #[cfg(unix)]
+        if self.credentials != (None, None) && e.kind() == io::ErrorKind::PermissionDenied {
+            let (uid, gid) = self.credentials;
+            return Error::PrivilegeDrop {
+                uid,
+                gid,
+                source: e,
+            };
+        }
+        e.into()
+    }
+
+    /// The program followed by its arguments, as they are or were handed to the
+    /// OS, so with [`Command::arg0`] that name comes first. Non-UTF-8 parts are
+    /// converted lossily.
+    pub fn spawned_argv(&self) -> Vec<String> {
+        let Invocation { program, args, .. } = self.invocation_template();
+        let first = match &self.arg0 {
+            Some(arg0) => arg0.to_string_lossy().into_owned(),
+            None => program,
+        };
+        iter::once(first).chain(args).collect()
+    }
+
+    fn high_water(&self) -> Option<HighWater> {
+        let (threshold, callback) = self.on_backlog.as_ref()?;
+        Some(HighWater {
+            threshold: *threshold,
+            callback: Arc::clone(callback),
+            above: false,
+        })
+    }
+
+    fn invocation_template(&self) -> Invocation {
+        let lossy = |s: &std::ffi::OsStr| s.to_string_lossy().into_owned();
+        Invocation {
+            program: lossy(self.std_command.get_program()),
+            args: self.std_command.get_args().map(lossy).collect(),
+            env: self
+                .explicit_env()
+                .map(|(key, value)| (lossy(key), value.map(lossy)))
+                .collect(),
+            current_dir: self
+                .std_command
+                .get_current_dir()
+                .map(|dir| dir.to_string_lossy().into_owned()),
+            ..Default::default()
+        }
+    }
+
+    /// Starts the child with every signal at its default disposition and none
+    /// blocked. exec only resets caught signals and std only restores SIGPIPE, so
+    /// without this the child inherits any other signal this process ignores.
+    #[cfg(unix)]
+    pub fn reset_signal_handlers(&mut self, reset: bool) -> &mut Self {
+        self.reset_signal_handlers = reset;
+        self
+    }
+
+    /// Limits the child's address space to `bytes` with `RLIMIT_AS`, set right
+    /// before exec. Allocations past the limit fail, which most programs answer
+    /// by aborting. Virtual memory counts, so a tight limit can break runtimes
+    /// that reserve large regions up front.
+    #[cfg(unix)]
+    pub fn memory_limit(&mut self, bytes: u64) -> &mut Self {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    /// Limits the CPU time of the child with `RLIMIT_CPU`, in whole seconds and
+    /// at least one. It is sent `SIGXCPU` once it used them up and killed a
+    /// second later.
+    #[cfg(unix)]
+    pub fn cpu_time_limit(&mut self, limit: Duration) -> &mut Self {
+        self.cpu_time_limit = Some(limit);
+        self
+    }
+
+    /// Hands `source` to the child as fd `target_fd`, e.g. one end of a socketpair
+    /// for out-of-band messages. It is dup2'ed into place right before exec and
+    /// closed in this process after spawning. A target must not be the number of
+    /// another source, each source is copied as it is at that point.
+    #[cfg(unix)]
+    pub fn inherit_fd(&mut self, target_fd: i32, source: std::os::fd::OwnedFd) -> &mut Self {
+        self.inherit_fds.push((target_fd, source));
+        self
+    }
+
+    /// Moves the child into a new PID namespace. As with `unshare --pid` without
+    /// `--fork`, the child itself stays where it is and the processes it starts
+    /// are the ones in the new namespace, the first of them as pid 1. Needs
+    /// `CAP_SYS_ADMIN`, or [`Command::new_user_namespace`] where unprivileged
+    /// user namespaces are enabled; otherwise spawning fails with a
+    /// `PermissionDenied` error.
+    #[cfg(target_os = "linux")]
+    pub fn new_pid_namespace(&mut self, new: bool) -> &mut Self {
+        self.namespace(libc::CLONE_NEWPID, new)
+    }
+
+    /// Gives the child a private copy of the mount table, see
+    /// [`Command::new_pid_namespace`] for the privileges needed.
+    #[cfg(target_os = "linux")]
+    pub fn new_mount_namespace(&mut self, new: bool) -> &mut Self {
+        self.namespace(libc::CLONE_NEWNS, new)
+    }
+
+    /// Gives the child a network stack of its own with only a loopback device,
+    /// which is down, see [`Command::new_pid_namespace`] for the privileges
+    /// needed.
+    #[cfg(target_os = "linux")]
+    pub fn new_net_namespace(&mut self, new: bool) -> &mut Self {
+        self.namespace(libc::CLONE_NEWNET, new)
+    }
+
+    /// Moves the child into a new user namespace, in which it holds every
+    /// capability, so the other namespaces can be created without privileges
+    /// where the kernel allows unprivileged user namespaces. No ids are mapped,
+    /// so files show up as owned by the overflow user.
+    #[cfg(target_os = "linux")]
+    pub fn new_user_namespace(&mut self, new: bool) -> &mut Self {
+        self.namespace(libc::CLONE_NEWUSER, new)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn namespace(&mut self, flag: libc::c_int, new: bool) -> &mut Self {
+        match new {
+            true => self.namespaces |= flag,
+            false => self.namespaces &= !flag,
+        }
+        self
+    }
+
+    /// Runs the child as user `uid`. Spawning fails with
+    /// [`Error::PrivilegeDrop`] if this process may not switch to it.
+    #[cfg(unix)]
+    pub fn uid(&mut self, uid: u32) -> &mut Self {
+        use std::os::unix::process::CommandExt;
+        self.std_command.uid(uid);
+        self.credentials.0 = Some(uid);
+        self
+    }
+
+    /// Runs the child with group `gid`, see [`Command::uid`].
+    #[cfg(unix)]
+    pub fn gid(&mut self, gid: u32) -> &mut Self {
+        use std::os::unix::process::CommandExt;
+        self.std_command.gid(gid);
+        self.credentials.1 = Some(gid);
+        self
+    }
+
+    /// Hands the child `name` as its `argv[0]` instead of the program path, e.g.
+    /// an applet name for a multi-call binary like busybox, or a name with a
+    /// leading `-` to start a login shell.
+    #[cfg(unix)]
+    pub fn arg0(&mut self, name: impl AsRef<OsStr>) -> &mut Self {
+        use std::os::unix::process::CommandExt;
+        self.std_command.arg0(&name);
+        self.arg0 = Some(name.as_ref().to_owned());
+        self
+    }
+
+    /// Runs the child as `username` with its primary group, both looked up in the
+    /// system user database.
+    #[cfg(unix)]
+    pub fn run_as(&mut self, username: &str) -> Result<&mut Self, Error> {
+        let (uid, gid) =
+            user::lookup(username)?.ok_or_else(|| Error::UnknownUser(username.to_owned()))?;
+        Ok(self.uid(uid).gid(gid))
+    }
+
+    /// By default only an actual broadcast kills the child; if the broadcaster is
+    /// dropped without broadcasting, the canceller thread exits and the child keeps
+    /// running. With `kill` set, dropping the broadcaster counts as a cancel too,
+    /// so it can be used as a scope guard for the child.
+    pub fn kill_on_canceller_drop(&mut self, kill: bool) -> &mut Self {
+        self.kill_on_canceller_drop = kill;
+        self
+    }
+
+    /// Delivers the output the child wrote before a cancel, then closes the
+    /// receivers. This is always the case, see [`Command::run`], so the option is
+    /// there for call sites to spell it out and `false` changes nothing.
+    pub fn drain_on_cancel(&mut self, _drain: bool) -> &mut Self {
+        self
+    }
+
+    /// The signal a canceller sends, [`Signal::Kill`] by default. A child that
+    /// shuts down cleanly on e.g. [`Signal::Term`] can then do so, but one that
+    /// ignores the signal keeps running. Other platforms have no signals and
+    /// always terminate the child.
+    pub fn cancel_signal(&mut self, signal: Signal) -> &mut Self {
+        self.cancel_signal = signal;
+        self
+    }
+
+    /// Has a canceller escalate like a service manager instead of sending the
+    /// [`Command::cancel_signal`]: each signal is sent once its delay, counted
+    /// from the cancel, has passed, in order, until the child has exited. E.g.
+    /// `[(0s, Term), (5s, Term), (10s, Kill)]`. End with [`Signal::Kill`] to be
+    /// sure the child goes. The canceller thread is busy meanwhile; the child is
+    /// checked at [`Command::poll_interval`]. Empty, the default, turns this off.
+    pub fn kill_schedule(&mut self, schedule: Vec<(Duration, Signal)>) -> &mut Self {
+        self.kill_schedule = schedule.into();
+        self
+    }
+
+    /// Runs the command to completion and returns its stdout with trailing
+    /// whitespace trimmed, the `$(cmd)` of this crate. stderr is drained alongside
+    /// and its tail ends up in [`Error::NonZeroExit`] if the child does not exit
+    /// successfully.
+    pub fn stdout_string(&mut self) -> Result<String, Error> {
+        let (_broadcaster, canceller) = idle_canceller();
+        let output = self.run_collecting(canceller, |_, _| {})?;
+        self.check_success(&output)?;
+        let mut stdout = output.stdout;
+        stdout.truncate(stdout.trim_end().len());
+        Ok(stdout)
+    }
+
+    /// Runs the command to completion like [`Command::stdout_string`], but keeps
+    /// only the last stdout line that is not blank, for tools that print progress
+    /// and then a result. The lines are read as they come rather than buffered.
+    /// `None` if the child printed no such line.
+    pub fn last_line(&mut self) -> Result<Option<String>, Error> {
+        let (_broadcaster, canceller) = idle_canceller();
+        let line_mode = mem::replace(&mut self.line_mode, true);
+        let handle = self.stdin_null().run(canceller);
+        self.line_mode = line_mode;
+        let mut handle = handle?;
+        let stdout = handle.stdout.take();
+        let last = thread::spawn(move || {
+            stdout
+                .into_iter()
+                .flat_map(|rx| iter::from_fn(move || rx.recv().ok()))
+                .filter(|line| !line.trim().is_empty())
+                .last()
+        });
+        let output = handle.collect(|_, _| {})?;
+        self.check_success(&output)?;
+        Ok(last.join().unwrap_or_default())
+    }
+
+    /// The error [`Command::stdout_string`] reports for an unsuccessful exit.
+    fn check_success(&self, output: &Output) -> Result<(), Error> {
+        if output.status.success() {
+            return Ok(());
+        }
+        Err(Error::NonZeroExit {
+            program: self
+                .std_command
+                .get_program()
+                .to_string_lossy()
+                .into_owned(),
+            status: output.status,
+            stderr: stderr_tail(&output.stderr).to_owned(),
+        })
+    }
+
+    /// Streams `input` into the child's stdin on the calling thread, closes it and
+    /// returns the exit status, for write-only sinks such as a printer. stdout and
+    /// stderr go to the null device and [`Command::map_stdin`] is not applied.
+    /// A child exiting before it read everything is not an error; SIGPIPE is
+    /// blocked on the calling thread meanwhile, so it cannot end this process.
+    pub fn feed_and_wait(
+        &mut self,
+        mut input: impl Read,
+        canceller: Canceller,
+    ) -> Result<ExitStatus, Error> {
+        let write_only = mem::replace(&mut self.write_only, true);
+        let handle = self.run(canceller);
+        self.write_only = write_only;
+        let handle = handle?;
+        if let Some(mut stdin) = handle.with_child(|child| child.stdin.take())? {
+            #[cfg(unix)]
+            let _sigpipe = signal::SigpipeBlocked::new();
+            match io::copy(&mut input, &mut stdin) {
+                Err(e) if e.kind() != io::ErrorKind::BrokenPipe => return Err(e.into()),
+                _ => {}
+            }
+        }
+        handle.wait()
+    }
+
+    /// Runs the command with `script` as its whole stdin, e.g. for `python -` or
+    /// `sqlite3`, and blocks until it exits. stdin is closed once the script is
+    /// written, so the interpreter sees EOF. An interpreter exiting before it
+    /// read all of it is not an error.
+    pub fn run_script(&mut self, script: &str, canceller: Canceller) -> Result<Output, Error> {
+        self.stdin_mode = StdioMode::Piped;
+        let handle = self.run(canceller)?;
+        // no stdin with Command::write_only
+        let sent = match &handle.stdin {
+            Some(stdin) => stdin.send(script.to_owned()),
+            None => Err(Error::StdinClosed(script.to_owned())),
+        };
+        match sent {
+            Ok(()) | Err(Error::StdinClosed(_)) => {}
+            Err(e) => return Err(e),
+        }
+        handle.finish_and_collect()
+    }
+
+    /// Runs the command with stdin on the null device and blocks until it exits,
+    /// calling `on_chunk` for every chunk as it arrives and returning everything
+    /// at the end.
+    pub fn run_collecting(
+        &mut self,
+        canceller: Canceller,
+        on_chunk: impl FnMut(StreamKind, &str),
+    ) -> Result<Output, Error> {
+        self.stdin_null().run(canceller)?.collect(on_chunk)
+    }
+
+    /// Runs the command with stdin on the null device like
+    /// [`Command::run_collecting`], but
+    /// kills the child once `deadline` has passed, with [`Signal::Kill`] whatever
+    /// [`Command::cancel_signal`] says. Whatever it printed up to then
+    /// is returned all the same, along with `true` if the deadline killed it,
+    /// even with [`Command::fail_on_nonzero`] or [`Command::fail_on_stderr`].
+    /// As with a cancel, output is read until the pipes close, so a grandchild
+    /// still holding them delays the return.
+    pub fn run_with_deadline(&mut self, deadline: Duration) -> Result<(Output, bool), Error> {
+        let (_broadcaster, canceller) = idle_canceller();
+        let mut handle = self.stdin_null().start(canceller, false)?;
+        let (done_tx, done_rx) = channel::<()>();
+        let (deadline, poll_interval) = (self.clock.now() + deadline, self.poll_interval);
+        let timer = handle.cancel.clone().map(|cancel| {
+            thread::spawn(move || {
+                let recv = |timeout| done_rx.recv_timeout(timeout);
+                match clock::recv_until(&*cancel.clock, deadline, poll_interval, recv) {
+                    Err(RecvTimeoutError::Timeout) => cancel.send(Signal::Kill),
+                    _ => false,
+                }
+            })
+        });
+        let (stdout, stderr, _) = handle.gather(None, |_, _| {});
+        drop(done_tx);
+        let expired = timer.is_some_and(|timer| timer.join().unwrap_or(false));
+        let output = handle.into_output(stdout, stderr, expired)?;
+        Ok((output, expired))
+    }
+
+    /// Runs the command in [`Command::line_mode`] and parses every stdout line as
+    /// JSON into `tx`, for processing the values concurrently; the handle's
+    /// stdout is then `None`. Like [`StdoutReceiver::json_lines`], blank lines are
+    /// skipped and a malformed line is delivered as an `Err` without ending the
+    /// stream.
+    #[cfg(feature = "serde")]
+    pub fn run_json_lines_into<T: serde::de::DeserializeOwned + Send + 'static>(
+        &mut self,
+        tx: Sender<Result<T, Error>>,
+        canceller: Canceller,
+    ) -> Result<ProcessHandle, Error> {
+        self.line_mode(true)
+            .stdout_into(JsonLinesSender(tx))
+            .run(canceller)
+    }
+
+    /// Spawns the child and the threads serving its stdio.
+    ///
+    /// When `canceller` fires the child is killed, which closes its pipes. The
+    /// reader threads keep forwarding whatever the child wrote before it died
+    /// until they see EOF, and only then are the receivers closed, so output
+    /// produced right before a cancel is never dropped.
+    ///
+    /// A `Command` spawns one child only: running it again fails with
+    /// [`Error::AlreadyRun`].
+    pub fn run(&mut self, canceller: Canceller) -> Result<ProcessHandle, Error> {
+        match self.lazy {
+            true => self.start_lazy(canceller),
+            false => self.start(canceller, false),
+        }
+    }
+
+    /// Runs the command to completion for its exit status alone, on the calling
+    /// thread: streams left piped are inherited instead, so no stdio threads are
+    /// started, only the canceller's, which can still kill the child meanwhile.
+    /// Streams set up otherwise, e.g. by [`Command::discard_stderr`], stay so.
+    pub fn run_and_wait(&mut self, canceller: Canceller) -> Result<ExitStatus, Error> {
+        for mode in [
+            &mut self.stdin_mode,
+            &mut self.stdout_mode,
+            &mut self.stderr_mode,
+        ] {
+            if *mode == StdioMode::Piped {
+                *mode = StdioMode::Inherit;
+            }
+        }
+        self.start(canceller, false)?.wait()
+    }
+
+    /// Like [`Command::run`], with the variables in `overrides` set for this spawn
+    /// only. A `Command` spawns once, so what is set back is its environment as
+    /// [`Command::effective_env`], [`Command::secure_env`] and recorded
+    /// invocations see it: variables set or removed explicitly before get that
+    /// back. std cannot forget a variable once set, so the others stay set in the
+    /// underlying command, but no longer count as set.
+    pub fn run_with_env(
+        &mut self,
+        overrides: &[(&str, &str)],
+        canceller: Canceller,
+    ) -> Result<ProcessHandle, Error> {
+        let previous: Vec<(&str, Option<Option<OsString>>)> = overrides
+            .iter()
+            .map(|(key, _)| {
+                let explicit = self
+                    .explicit_env()
+                    .find(|(set, _)| set == key)
+                    .map(|(_, val)| val.map(OsStr::to_owned));
+                (*key, explicit)
+            })
+            .collect();
+        self.std_command.envs(overrides.iter().copied());
+        let handle = self.run(canceller);
+        for (key, explicit) in previous {
+            match explicit {
+                Some(Some(val)) => {
+                    self.std_command.env(key, val);
+                }
+                Some(None) => {
+                    self.std_command.env_remove(key);
+                }
+                None => {
+                    self.scoped_env.insert(key.into());
+                }
+            }
+        }
+        handle
+    }
+
+    /// Like [`Command::run`], but the calling thread only spawns the child and a
+    /// single setup thread, which then starts the stdin, stdout, stderr, canceller
+    /// and monitor threads. This saves the calling thread three to four thread
+    /// creations per command; the process spawn itself is untouched and usually
+    /// dominates, so measure with the `spawn_cost` example before relying on it.
+    pub fn run_deferred(&mut self, canceller: Canceller) -> Result<ProcessHandle, Error> {
+        match self.lazy {
+            true => self.start_lazy(canceller),
+            false => self.start(canceller, true),
+        }
+    }
+
+    /// See [`Command::lazy`].
+    fn start_lazy(&mut self, canceller: Canceller) -> Result<ProcessHandle, Error> {
+        let program = self.std_command.get_program().to_owned();
+        if self.has_run {
+            return Err(Error::AlreadyRun(program.to_string_lossy().into_owned()));
+        }
+        let plain_stdout = self.stdout_mode == StdioMode::Piped
+            && self.redirect_stdout.is_none()
+            && !self.write_only
+            && self.stdout_into.is_none()
+            && self.stdout_writer.is_none()
+            && self.stdout_pairs_into.is_none();
+        let plain_stderr = self.stderr_mode == StdioMode::Piped
+            && self.redirect_stderr.is_none()
+            && !self.write_only
+            && self.stderr_into.is_none()
+            && self.stderr_pairs_into.is_none();
+        #[cfg(feature = "bytes")]
+        let (plain_stdout, plain_stderr) = (
+            plain_stdout && self.stdout_bytes_into.is_none(),
+            plain_stderr && self.stderr_bytes_into.is_none(),
+        );
+        #[cfg(feature = "rtrb")]
+        let plain_stdout = plain_stdout && self.stdout_ring.is_none();
+        let plain_stdin = self.stdin_mode == StdioMode::Piped && !self.write_only;
+        #[cfg(feature = "tokio")]
+        let plain_stdin = plain_stdin && self.async_stdin.is_none();
+        let (stdout_tx, stdout_rx) = channel::<String>();
+        let (stderr_tx, stderr_rx) = channel::<String>();
+        if plain_stdout {
+            self.stdout_into(stdout_tx);
+        }
+        if plain_stderr {
+            self.stderr_into(stderr_tx);
+        }
+        let mut spent = Command::from(StdCommand::new(&program));
+        spent.has_run = true;
+        let mut command = mem::replace(self, spent);
+        command.lazy = false;
+        let (line_mode, trim_output, poll_interval) = (
+            command.line_mode,
+            command.trim_output,
+            command.poll_interval,
+        );
+        let context = command
+            .context
+            .as_ref()
+            .map(|(context, _)| Arc::clone(context));
+        let clock = Arc::clone(&command.clock);
+        let lazy = Arc::new(LazyStart::new(command, canceller));
+        let mut handle = ProcessHandle::from_channels(
+            None,
+            plain_stdout.then_some(stdout_rx),
+            plain_stderr.then_some(stderr_rx),
+        );
+        handle.stdin = plain_stdin.then(|| {
+            let mut stdin = StdinSender::new(channel().0, Arc::clone(&clock), poll_interval);
+            stdin.lazy = Some(Arc::clone(&lazy));
+            stdin
+        });
+        if let Some(stdout) = &mut handle.stdout {
+            stdout.4 = Some(Arc::clone(&lazy));
+        }
+        if let Some(stderr) = &mut handle.stderr {
+            stderr.3 = Some(Arc::clone(&lazy));
+        }
+        (handle.line_mode, handle.trim_output) = (line_mode, trim_output);
+        (handle.poll_interval, handle.clock) = (poll_interval, clock);
+        handle.context = context;
+        handle.lazy = Some(lazy);
+        Ok(handle)
+    }
+
+    fn start(&mut self, canceller: Canceller, deferred: bool) -> Result<ProcessHandle, Error> {
+        let cmd = self
+            .std_command
+            .get_program()
+            .to_owned()
+            .into_string()
+            .unwrap();
+        if self.has_run {
+            return Err(Error::AlreadyRun(cmd));
+        }
+        let context = self.context.as_ref().map(|(_, debug)| debug.as_str());
+        let span = match (self.log_stderr, context) {
+            (None, None) => Span::none(),
+            (level, _) => command_span(level.unwrap_or(Level::TRACE), &cmd, context),
+        };
+        let _entered = span.enter();
+        trace!("preparing to run '{cmd:}'");
+        if let (true, Some(dir)) = (self.sync_pwd, self.std_command.get_current_dir()) {
+            let pwd = env::current_dir()?.join(dir);
+            self.std_command.env("PWD", pwd);
+        }
+        let stdin_io = self.stdin_mode.stdio();
+        #[cfg(unix)]
+        if self.reset_signal_handlers {
+            use std::os::unix::process::CommandExt;
+            // SAFETY: the hook only makes async-signal-safe calls
+            unsafe {
+                self.std_command.pre_exec(signal::reset_dispositions);
+            }
+        }
+        #[cfg(target_os = "linux")]
+        if self.namespaces != 0 {
+            use std::os::unix::process::CommandExt;
+            let flags = self.namespaces;
+            // SAFETY: unshare is a plain syscall; the user namespace is created
+            // first by the kernel, so it grants the rights for the others
+            unsafe {
+                self.std_command
+                    .pre_exec(move || match libc::unshare(flags) {
+                        0 => Ok(()),
+                        _ => Err(io::Error::last_os_error()),
+                    });
+            }
+        }
+        #[cfg(unix)]
+        if self.memory_limit.is_some() || self.cpu_time_limit.is_some() {
+            use std::os::unix::process::CommandExt;
+            let memory = self.memory_limit.map(|bytes| (bytes, bytes));
+            let cpu = self.cpu_time_limit.map(|limit| {
+                let seconds = limit.as_secs().max(1);
+                (seconds, seconds + 1)
+            });
+            let set = |resource, (soft, hard): (u64, u64)| {
+                let limit = libc::rlimit {
+                    rlim_cur: soft as libc::rlim_t,
+                    rlim_max: hard as libc::rlim_t,
+                };
+                // SAFETY: plain syscall on a valid struct
+                match unsafe { libc::setrlimit(resource, &limit) } {
+                    0 => Ok(()),
+                    _ => Err(io::Error::last_os_error()),
+                }
+            };
+            // SAFETY: setrlimit is async-signal-safe
+            unsafe {
+                self.std_command.pre_exec(move || {
+                    if let Some(limit) = memory {
+                        set(libc::RLIMIT_AS, limit)?;
+                    }
+                    if let Some(limit) = cpu {
+                        set(libc::RLIMIT_CPU, limit)?;
+                    }
+                    Ok(())
+                });
+            }
+        }
+        #[cfg(unix)]
+        if !self.inherit_fds.is_empty() {
+            use std::os::{fd::AsRawFd, unix::process::CommandExt};
+            let fds: Vec<_> = self
+                .inherit_fds
+                .iter()
+                .map(|(target, source)| (*target, source.as_raw_fd()))
+                .collect();
+            // SAFETY: dup2 and fcntl are async-signal-safe and the hook does not
+            // allocate
+            unsafe {
+                self.std_command.pre_exec(move || {
+                    for &(target, source) in &fds {
+                        // dup2 leaves the fd alone if it is the target already,
+                        // close-on-exec included
+                        let copied = match source == target {
+                            true => libc::fcntl(source, libc::F_SETFD, 0),
+                            false => libc::dup2(source, target),
+                        };
+                        if copied == -1 {
+                            return Err(io::Error::last_os_error());
+                        }
+                    }
+                    Ok(())
+                });
+            }
+        }
+        let output_io =
+            |redirect: &Option<(PathBuf, OpenOptions)>, mode: StdioMode| -> io::Result<Stdio> {
+                Ok(match (redirect, self.write_only) {
+                    (Some((path, options)), _) => options.open(path)?.into(),
+                    (None, true) => Stdio::null(),
+                    (None, false) => mode.stdio(),
+                })
+            };
+        let (stdout_io, stderr_io) = (
+            output_io(&self.redirect_stdout, self.stdout_mode)?,
+            output_io(&self.redirect_stderr, self.stderr_mode)?,
+        );
+        self.std_command
+            .stdin(stdin_io)
+            .stdout(stdout_io)
+            .stderr(stderr_io);
+        let tee = match (&self.tee_stdout, &self.redirect_stdout) {
+            (Some((dir, max_size)), None) => Some(RotatingLog::create(
+                dir.clone(),
+                "stdout",
+                *max_size,
+                self.keep_rotated,
+            )?),
+            _ => None,
+        };
+        let mut child = self.spawn_child()?;
+        #[cfg(unix)]
+        self.inherit_fds.clear();
+        self.has_run = true;
+        // releases the files of a redirect, the child has its own copies now
+        self.std_command
+            .stdin(Stdio::inherit())
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit());
+        let started = self.clock.now();
+        if let Some(on_spawn) = &self.on_spawn {
+            let context = self
+                .context
+                .as_ref()
+                .map(|(context, _)| Arc::clone(context));
+            with_callback_context(context, || on_spawn(child.id()));
+        }
+        let recording = self.record_invocation.then(|| Recording {
+            invocation: self.invocation_template(),
+            stdin: Arc::default(),
+        });
+        let mut workers: Vec<Worker> = Vec::new();
+        let (errors_tx, errors_rx) = channel::<Error>();
+        #[allow(unused_mut)]
+        let mut stdin_pipe = match self.write_only {
+            true => None,
+            false => child.stdin.take(),
+        };
+        #[cfg(feature = "tokio")]
+        let mut async_stdin = None;
+        #[cfg(feature = "tokio")]
+        if let Some(capacity) = self.async_stdin {
+            if let Some(stdin) = stdin_pipe.take() {
+                let record = recording
+                    .as_ref()
+                    .map(|recording| Arc::clone(&recording.stdin));
+                let map = self.take_stdin_map();
+                let (stdin, worker) = async_stdin::worker(
+                    cmd.clone(),
+                    stdin,
+                    map,
+                    record,
+                    capacity,
+                    errors_tx.clone(),
+                );
+                workers.push(worker);
+                async_stdin = Some(stdin);
+            }
+        }
+        let stdin = stdin_pipe.map(|stdin| {
+            let record = recording
+                .as_ref()
+                .map(|recording| Arc::clone(&recording.stdin));
+            let (stdin, worker) = stdin_worker(
+                cmd.clone(),
+                stdin,
+                self.take_stdin_map(),
+                record,
+                Arc::clone(&self.clock),
+                self.poll_interval,
+            );
+            let (write_failure, errors) = (Arc::clone(&stdin.write_failure), errors_tx.clone());
+            workers.push(Box::new(move || {
+                worker();
+                report_write_failure(&write_failure, &errors);
+            }));
+            stdin
+        });
+        let readers = Arc::<OpenReaders>::default();
+        let stderr_reader = Arc::<OpenReaders>::default();
+        let latest_line = Arc::default();
+        let first_stderr =
+            (self.fail_on_stderr || self.kill_on_stderr).then(SharedMessage::default);
+        let stderr_tail = self
+            .fail_on_nonzero
+            .then(|| Arc::new(Mutex::new(String::new())));
+        let (mut stdout_pipe, mut stderr_pipe) = (child.stdout.take(), child.stderr.take());
+        let child = Arc::new(Mutex::new(child));
+        let read_limit_reached = Arc::default();
+        #[cfg(feature = "bytes")]
+        if let Some(tx) = self.stdout_bytes_into.take() {
+            if let Some(pipe) = stdout_pipe.take() {
+                let quota = self
+                    .read_limit
+                    .map(|limit| Quota::new(limit, Arc::clone(&read_limit_reached)));
+                let (child, errors) = (Arc::clone(&child), errors_tx.clone());
+                let reader =
+                    bytes_reader_worker(cmd.clone(), "stdout", pipe, tx, quota, child, errors);
+                workers.push(readers.track(reader));
+            }
+        }
+        #[cfg(feature = "bytes")]
+        if let Some(tx) = self.stderr_bytes_into.take() {
+            if let Some(pipe) = stderr_pipe.take() {
+                let (child, errors) = (Arc::clone(&child), errors_tx.clone());
+                let reader =
+                    bytes_reader_worker(cmd.clone(), "stderr", pipe, tx, None, child, errors);
+                workers.push(readers.track(reader));
+            }
+        }
+        #[cfg(feature = "rtrb")]
+        let mut stdout_ring = None;
+        #[cfg(feature = "rtrb")]
+        if let Some(capacity) = self.stdout_ring {
+            if let Some(pipe) = stdout_pipe.take() {
+                let (producer, consumer) = rtrb::RingBuffer::new(capacity);
+                let reader = ring::reader_worker(cmd.clone(), pipe, producer, errors_tx.clone());
+                workers.push(readers.track(reader));
+                stdout_ring = Some(consumer);
+            }
+        }
+        if let Some(tx) = self.stdout_pairs_into.take() {
+            if let Some(pipe) = stdout_pipe.take() {
+                let errors = errors_tx.clone();
+                let reader = pairs_reader_worker(cmd.clone(), "stdout", pipe, tx, errors);
+                workers.push(readers.track(reader));
+            }
+        }
+        if let Some(tx) = self.stderr_pairs_into.take() {
+            if let Some(pipe) = stderr_pipe.take() {
+                let errors = errors_tx.clone();
+                let reader = pairs_reader_worker(cmd.clone(), "stderr", pipe, tx, errors);
+                workers.push(readers.track(reader));
+            }
+        }
+        if let Some(writer) = self.stdout_writer.take() {
+            if let Some(pipe) = stdout_pipe.take() {
+                let kill = self.kill_on_writer_error.then(|| Arc::clone(&child));
+                let reader =
+                    writer_reader_worker(cmd.clone(), pipe, writer, kill, errors_tx.clone());
+                workers.push(readers.track(reader));
+            }
+        }
+        let (stdout_backlog, stderr_backlog) = (Arc::default(), Arc::default());
+        let (stdout_peak_backlog, stderr_peak_backlog) = (Arc::default(), Arc::default());
+        let (stdout_unterminated, stderr_unterminated) = (Arc::default(), Arc::default());
+        let delivered = self.on_stall.is_some().then(Arc::<AtomicUsize>::default);
+        let mut stdout = None;
+        if let Some(pipe) = stdout_pipe {
+            let mut sink = OutputSink::new(Arc::clone(&child));
+            sink.errors = Some(errors_tx.clone());
+            sink.delivered = delivered.clone();
+            match self.stdout_into.take() {
+                Some(tx) => sink.tx = tx,
+                None => {
+                    let (tx, rx) = channel::<String>();
+                    let failure = ReadFailure::default();
+                    stdout = Some(StdoutReceiver(
+                        rx,
+                        Arc::clone(&stdout_backlog),
+                        Arc::clone(&failure),
+                        Cell::default(),
+                        None,
+                        self.line_mode,
+                    ));
+                    sink.tx = Box::new(tx);
+                    sink.failure = Some(failure);
+                    sink.backlog = Some(Arc::clone(&stdout_backlog));
+                    sink.peak_backlog = Some(Arc::clone(&stdout_peak_backlog));
+                    sink.high_water = self.high_water();
+                }
+            }
+            sink.quota = self
+                .read_limit
+                .map(|limit| Quota::new(limit, Arc::clone(&read_limit_reached)));
+            if self.line_mode {
+                sink.latest = Some(Arc::clone(&latest_line));
+            }
+            sink.tee = tee;
+            sink.lines = self.line_mode;
+            sink.unterminated = Some(Arc::clone(&stdout_unterminated));
+            sink.awaiting_response = stdin
+                .as_ref()
+                .map(|stdin| Arc::clone(&stdin.awaiting_response));
+            let config = ReaderConfig {
+                line_mode: self.line_mode,
+                normalize_newlines: self.normalize_newlines,
+                strip_ansi: self.strip_ansi,
+                dedup_consecutive: self.dedup_consecutive,
+                capture_after: self.capture_after.clone(),
+                record_sentinel: self.record_sentinel.clone(),
+                decode_threads: self.decode_threads,
+            };
+            let reader = reader_worker(cmd.clone(), "stdout", pipe, config, sink);
+            workers.push(readers.track(reader));
+        }
+        let mut stderr = None;
+        if let Some(pipe) = stderr_pipe {
+            let mut sink = OutputSink::new(Arc::clone(&child));
+            sink.errors = Some(errors_tx.clone());
+            sink.delivered = delivered.clone();
+            match self.stderr_into.take() {
+                Some(tx) => sink.tx = tx,
+                None => {
+                    let (tx, rx) = channel::<String>();
+                    stderr = Some(StderrReceiver(
+                        rx,
+                        Arc::clone(&stderr_backlog),
+                        Cell::default(),
+                        None,
+                    ));
+                    sink.tx = Box::new(tx);
+                    sink.backlog = Some(Arc::clone(&stderr_backlog));
+                    sink.peak_backlog = Some(Arc::clone(&stderr_peak_backlog));
+                    sink.high_water = self.high_water();
+                }
+            }
+            sink.first = first_stderr.clone();
+            sink.kill_on_first = self.kill_on_stderr;
+            sink.unterminated = Some(Arc::clone(&stderr_unterminated));
+            sink.tail = stderr_tail.clone().map(|text| TailBuffer {
+                text,
+                lines: self.line_mode || self.stderr_lines,
+            });
+            let config = ReaderConfig {
+                line_mode: self.line_mode || self.stderr_lines,
+                normalize_newlines: self.normalize_newlines,
+                strip_ansi: self.strip_ansi,
+                dedup_consecutive: self.dedup_consecutive,
+                decode_threads: self.decode_threads,
+                ..Default::default()
+            };
+            let reader = reader_worker(cmd.clone(), "stderr", pipe, config, sink);
+            workers.push(readers.track(stderr_reader.track(reader)));
+        }
+
+        let cancel = CancelWiring {
+            cmd: cmd.clone(),
+            child: Arc::clone(&child),
+            generation: Arc::default(),
+            cancelled: Arc::default(),
+            kill_on_drop: self.kill_on_canceller_drop,
+            signal: self.cancel_signal,
+            schedule: Arc::clone(&self.kill_schedule),
+            poll_interval: self.poll_interval,
+            clock: Arc::clone(&self.clock),
+            errors: Arc::new(Mutex::new(Some(errors_tx.clone()))),
+        };
+        workers.push(cancel.worker(canceller));
+
+        #[cfg(all(unix, feature = "signal-forwarding"))]
+        if !self.forward_signals.is_empty() {
+            let child = Arc::clone(&child);
+            workers.push(signal::forwarder(
+                cmd.clone(),
+                &self.forward_signals,
+                child,
+                self.poll_interval,
+            )?);
+        }
+
+        if let (Some((after, on_stall)), Some(delivered)) = (self.on_stall.take(), delivered) {
+            let cmd = cmd.clone();
+            let child = Arc::clone(&child);
+            let clock = Arc::clone(&self.clock);
+            let poll_interval = self.poll_interval;
+            workers.push(Box::new(move || {
+                let (mut seen, mut quiet_since, mut fired) = (0, clock.now(), false);
+                while let Ok(None) = child.lock().unwrap().try_wait() {
+                    let now = clock.now();
+                    let count = delivered.load(Ordering::Relaxed);
+                    if count != seen {
+                        (seen, quiet_since, fired) = (count, now, false);
+                    } else if !fired && now.saturating_duration_since(quiet_since) >= after {
+                        trace!("'{cmd:}' printed nothing for {after:?}");
+                        on_stall();
+                        fired = true;
+                    }
+                    thread::sleep(poll_interval);
+                }
+                trace!("exiting the stall watchdog thread of '{cmd:}'");
+            }));
+        }
+
+        {
+            let cmd = cmd.clone();
+            let child = Arc::clone(&child);
+            let clock = Arc::clone(&self.clock);
+            let poll_interval = self.poll_interval;
+            let on_exit = self.on_exit.take();
+            let errors = Arc::clone(&cancel.errors);
+            workers.push(Box::new(move || {
+                loop {
+                    match child.lock().unwrap().try_wait() {
+                        Ok(Some(status)) => {
+                            if let Some(on_exit) = on_exit {
+                                on_exit(status, clock.now() - started);
+                            }
+                            break;
+                        }
+                        Ok(None) => {}
+                        Err(_) => break,
+                    }
+                    thread::sleep(poll_interval);
+                }
+                errors.lock().unwrap().take();
+                trace!("exiting the monitor thread of '{cmd:}'");
+            }));
+        }
+
+        if self.context.is_some() || self.log_stderr.is_some() {
+            let context = self
+                .context
+                .as_ref()
+                .map(|(context, _)| Arc::clone(context));
+            workers = workers
+                .into_iter()
+                .map(|worker| in_span(worker, span.clone(), context.clone()))
+                .collect();
+        }
+        let panicked = self.catch_callback_panics.then(SharedMessage::default);
+        if let Some(panicked) = &panicked {
+            workers = workers
+                .into_iter()
+                .map(|worker| catch_panics(worker, panicked))
+                .collect();
+        }
+        if deferred {
+            thread::spawn(move || {
+                for worker in workers {
+                    thread::spawn(worker);
+                }
+            });
+        } else {
+            for worker in workers {
+                thread::spawn(worker);
+            }
+        }
+
+        trace!("exiting run '{cmd:}'");
+        Ok(ProcessHandle {
+            stdin,
+            #[cfg(feature = "tokio")]
+            async_stdin,
+            stdout,
+            #[cfg(feature = "rtrb")]
+            stdout_ring,
+            stderr,
+            errors: Some(ErrorReceiver(errors_rx)),
+            child: Some(child),
+            exit_status: None,
+            recording,
+            stdout_backlog,
+            stderr_backlog,
+            stdout_peak_backlog,
+            stderr_peak_backlog,
+            stdout_unterminated,
+            stderr_unterminated,
+            cancel: Some(cancel),
+            line_mode: self.line_mode,
+            trim_output: self.trim_output,
+            poll_interval: self.poll_interval,
+            clock: Arc::clone(&self.clock),
+            expect_buffer: Mutex::default(),
+            readers,
+            stderr_reader,
+            latest_line,
+            first_stderr,
+            nonzero: stderr_tail.map(|tail| (cmd.clone(), tail)),
+            read_limit_reached,
+            panicked,
+            context: self
+                .context
+                .as_ref()
+                .map(|(context, _)| Arc::clone(context)),
+            lazy: None,
+        })
+    }
+}
+
+/// POSIX word splitting for [`Command::parse`], the reason on failure.
+fn split_words(line: &str) -> Result<Vec<String>, String> {
+    let (mut words, mut word) = (Vec::new(), None::<String>);
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '\\' => match chars.next() {
+                // an escaped newline joins lines
+                Some('\n') => {}
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => return Err("trailing backslash".to_owned()),
+            },
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err("unbalanced single quote".to_owned()),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$' | '`')) => word.push(c),
+                            Some('\n') => {}
+                            Some(c) => word.extend(['\\', c]),
+                            None => return Err("unbalanced double quote".to_owned()),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err("unbalanced double quote".to_owned()),
+                    }
+                }
+            }
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// A canceller that never fires, for the helpers that do not take one. Keep the
+/// first half alive while the child runs: dropping it counts as a cancel with
+/// [`Command::kill_on_canceller_drop`].
+fn idle_canceller() -> (impl Sized, Canceller) {
+    let (broadcaster, subscriber) = broadcasting_channel("idle canceller");
+    (broadcaster, subscriber.into())
+}
+
+/// The end of `stderr` kept for an error, see [`STDERR_TAIL_LINES`].
+fn stderr_tail(stderr: &str) -> &str {
+    let mut start = stderr.len().saturating_sub(STDERR_TAIL_BYTES);
+    while !stderr.is_char_boundary(start) {
+        start += 1;
+    }
+    // a final newline ends the last line rather than starting another
+    let body = stderr.strip_suffix('\n').unwrap_or(stderr);
+    if let Some((at, _)) = body.rmatch_indices('\n').nth(STDERR_TAIL_LINES - 1) {
+        start = start.max(at + 1);
+    }
+    &stderr[start..]
+}
+
+/// The tail of [`Error::NonZeroExit`] as shown by its `Display`, nothing if the
+/// child wrote no stderr.
+fn stderr_suffix(stderr: &str) -> String {
+    match stderr.trim_end() {
+        "" => String::new(),
+        tail => format!(": {tail}"),
+    }
+}
+
+/// The span of a command, see [`Command::with_context`]. The level has to be a
+/// constant in `tracing`, hence the match.
+fn command_span(level: Level, program: &str, context: Option<&str>) -> Span {
+    match level {
+        Level::ERROR => error_span!("command", program = %program, context = context),
+        Level::WARN => warn_span!("command", program = %program, context = context),
+        Level::INFO => info_span!("command", program = %program, context = context),
+        Level::DEBUG => debug_span!("command", program = %program, context = context),
+        _ => trace_span!("command", program = %program, context = context),
+    }
+}
+
+/// Runs `worker` in `span`, on whatever thread it ends up on. The thread's
+/// events go to the subscriber the span belongs to, like the span itself.
+fn in_span(worker: Worker, span: Span, context: Option<Context>) -> Worker {
+    Box::new(move || {
+        let dispatch = span.with_subscriber(|(_, dispatch)| dispatch.clone());
+        let run = || {
+            let _entered = span.enter();
+            with_callback_context(context, worker)
+        };
+        match dispatch {
+            Some(dispatch) => tracing::dispatcher::with_default(&dispatch, run),
+            None => run(),
+        }
+    })
+}
+
+thread_local! {
+    /// The context of the command this thread serves, see [`callback_context`].
+    static CALLBACK_CONTEXT: RefCell<Option<Context>> = const { RefCell::new(None) };
+}
+
+/// Runs `f` with `context` as the [`callback_context`] of this thread.
+fn with_callback_context<R>(context: Option<Context>, f: impl FnOnce() -> R) -> R {
+    struct Restore(Option<Context>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            CALLBACK_CONTEXT.with(|current| *current.borrow_mut() = self.0.take());
+        }
+    }
+    let _restore = Restore(CALLBACK_CONTEXT.with(|current| current.replace(context)));
+    f()
+}
+
+/// Lets `worker` end quietly on a panic, leaving the message in `panicked`
+/// unless an earlier panic did already.
+fn catch_panics(worker: Worker, panicked: &SharedMessage) -> Worker {
+    let panicked = Arc::clone(panicked);
+    Box::new(move || {
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(worker)) {
+            let message = match (
+                payload.downcast_ref::<&str>(),
+                payload.downcast_ref::<String>(),
+            ) {
+                (Some(message), _) => message.to_string(),
+                (_, Some(message)) => message.clone(),
+                _ => "unknown panic payload".to_owned(),
+            };
+            trace!("caught a callback panic: {message}");
+            panicked.lock().unwrap().get_or_insert(message);
+        }
+    })
+}
+
+fn stdin_worker(
+    cmd: String,
+    stdin: ChildStdin,
+    stdin_map: Option<StdinMap>,
+    record: Option<Arc<Mutex<String>>>,
+    clock: Arc<dyn Clock>,
+    poll_interval: Duration,
+) -> (StdinSender, Worker) {
+    let (tx, rx) = channel::<String>();
+    let mut sender = StdinSender::new(tx, clock, poll_interval);
+    let pipe = Arc::new(Mutex::new(Some(Arc::new(stdin))));
+    sender.pipe = Some(Arc::clone(&pipe));
+    let worker = stdin_writer(
+        cmd,
+        pipe,
+        stdin_map,
+        record,
+        Arc::clone(&sender.bytes_written),
+        Arc::clone(&sender.write_failure),
+        move || rx.recv().ok(),
+    );
+    (sender, worker)
+}
+
+/// Why a send found the stdin thread gone: the write error it left, or else the
+/// child closing its stdin.
+fn stdin_gone(write_failure: &WriteFailure, input: String) -> Error {
+    match write_failure.lock().unwrap().as_ref() {
+        Some(e) => copy_io_error(e).into(),
+        None => Error::StdinClosed(input),
+    }
+}
+
+/// Whether the child closed its end of the pipe, so a write would fail with a
+/// broken pipe. Only Unix tells without writing.
+fn pipe_broken(pipe: &SharedPipe) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::fd::AsRawFd;
+        let Some(stdin) = pipe.lock().unwrap().clone() else {
+            return false;
+        };
+        let mut poll = libc::pollfd {
+            fd: stdin.as_raw_fd(),
+            events: libc::POLLOUT,
+            revents: 0,
+        };
+        // SAFETY: plain syscall on a descriptor we own, without waiting
+        let ready = unsafe { libc::poll(&mut poll, 1, 0) };
+        ready > 0 && poll.revents & (libc::POLLERR | libc::POLLHUP) != 0
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = pipe;
+        false
+    }
+}
+
+/// Passes the write error a finished stdin thread left on to `errors`.
+fn report_write_failure(write_failure: &WriteFailure, errors: &Sender<Error>) {
+    if let Some(e) = write_failure.lock().unwrap().as_ref() {
+        let _ = errors.send(copy_io_error(e).into());
+    }
+}
+
+/// `io::Error` is not `Clone`, this keeps its kind and message.
+fn copy_io_error(e: &io::Error) -> io::Error {
+    io::Error::new(e.kind(), e.to_string())
+}
+
+/// Writes every input `next` yields to the child's stdin, until it yields
+/// `None` or a write fails.
+fn stdin_writer(
+    cmd: String,
+    pipe: SharedPipe,
+    stdin_map: Option<StdinMap>,
+    record: Option<Arc<Mutex<String>>>,
+    bytes_written: Arc<AtomicUsize>,
+    write_failure: WriteFailure,
+    mut next: impl FnMut() -> Option<String> + Send + 'static,
+) -> Worker {
+    /// Closes stdin when the thread ends, even by unwinding.
+    struct ClosePipe(SharedPipe);
+    impl Drop for ClosePipe {
+        fn drop(&mut self) {
+            if let Ok(mut pipe) = self.0.lock() {
+                drop(pipe.take());
+            }
+        }
+    }
+    Box::new(move || {
+        let _close = ClosePipe(Arc::clone(&pipe));
+        #[cfg(unix)]
+        signal::block_sigpipe();
+        trace!("'{cmd:}' is in stdin recv");
+        while let Some(stdin_text) = next() {
+            let stdin_text: String = match &stdin_map {
+                Some(map) => map(stdin_text),
+                None => stdin_text,
+            };
+            trace!("'{cmd:}' received '{stdin_text}' in stdin thread");
+            let Some(stdin) = pipe.lock().unwrap().clone() else {
+                trace!("stdin of '{cmd:}' was closed");
+                break;
+            };
+            if let Err(e) = (&*stdin).write_all(stdin_text.as_bytes()) {
+                // dropping the receiver makes every further send fail
+                if e.kind() == io::ErrorKind::BrokenPipe {
+                    trace!("'{cmd:}' closed its stdin");
+                } else {
+                    trace!("writing stdin of '{cmd:}' failed: {e}");
+                    *write_failure.lock().unwrap() = Some(e);
+                }
+                break;
+            }
+            bytes_written.fetch_add(stdin_text.len(), Ordering::Relaxed);
+            if let Some(record) = &record {
+                record.lock().unwrap().push_str(&stdin_text);
+            }
+        }
+        trace!("exiting the stdin thread of '{cmd:}'");
+    })
+}
+
+/// Settings of one reader thread, taken from the [`Command`] at spawn time.
+#[derive(Clone, Default)]
+struct ReaderConfig {
+    line_mode: bool,
+    normalize_newlines: bool,
+    strip_ansi: bool,
+    dedup_consecutive: bool,
+    capture_after: Option<String>,
+    record_sentinel: Option<String>,
+    decode_threads: usize,
+}
+
+impl ReaderConfig {
+    fn transforms(&self) -> Vec<Box<dyn Transform>> {
+        let mut transforms: Vec<Box<dyn Transform>> = Vec::new();
+        if self.strip_ansi {
+            transforms.push(Box::<StripAnsi>::default());
+        }
+        if self.normalize_newlines {
+            transforms.push(Box::<NormalizeNewlines>::default());
+        }
+        if let Some(marker) = &self.capture_after {
+            transforms.push(Box::new(CaptureAfter::new(marker.clone())));
+        }
+        transforms
+    }
+}
+
+fn reader_worker(
+    cmd: String,
+    name: &'static str,
+    pipe: impl Read + Send + 'static,
+    config: ReaderConfig,
+    sink: OutputSink,
+) -> Worker {
+    Box::new(move || {
+        let delivery = Delivery {
+            transforms: config.transforms(),
+            framer: Framer {
+                lines: (config.line_mode || config.record_sentinel.is_some())
+                    .then(LineBuffer::default),
+                dedup: (config.line_mode && config.dedup_consecutive).then(Dedup::default),
+                records: config.record_sentinel.map(|sentinel| RecordBuffer {
+                    sentinel,
+                    record: None,
+                }),
+                sink,
+            },
+        };
+        trace!("'{cmd:}' is in {name} read");
+        match config.decode_threads {
+            0 => read_inline(&cmd, name, pipe, delivery),
+            decoders => read_pooled(&cmd, name, pipe, delivery, decoders),
+        }
+        trace!("exiting the {name} thread of '{cmd:}'");
+    })
+}
+
+/// Reads and decodes on the same thread.
+fn read_inline(cmd: &str, name: &str, mut pipe: impl Read, mut delivery: Delivery) {
+    let mut buf: [u8; 128] = [0; 128];
+    let mut utf8 = Utf8Carry::default();
+    loop {
+        let read_bytes = match pipe.read(&mut buf) {
+            Ok(0) => {
+                trace!("'{cmd:}' {name} closed");
+                break;
+            }
+            Ok(read_bytes) => read_bytes,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                trace!("'{cmd:}' {name} read failed: {e}");
+                delivery.framer.sink.fail(e);
+                break;
+            }
+        };
+        let text = String::from_utf8_lossy(&utf8.split(&buf[0..read_bytes])).into_owned();
+        trace!("'{cmd:}' received '{text}' in {name} thread");
+        if !delivery.push(text) {
+            trace!("'{cmd:}' {name} is not received anymore");
+            return;
+        }
+    }
+    delivery.finish(String::from_utf8_lossy(&utf8.0).into_owned());
+}
+
+/// Size of each read with [`Command::decode_threads`] and
+/// [`Command::stdout_pairs_into`].
+const DECODE_CHUNK: usize = 64 * 1024;
+
+/// Reads on this thread, decodes on `decoders` threads and delivers on yet
+/// another one, which puts the decoded chunks back in order. Returns once
+/// everything has been delivered.
+fn read_pooled(
+    cmd: &str,
+    name: &str,
+    mut pipe: impl Read,
+    mut delivery: Delivery,
+    decoders: usize,
+) {
+    let failure = delivery.framer.sink.failure.clone();
+    let errors = delivery.framer.sink.errors.clone();
+    let (job_tx, job_rx) = channel::<(usize, Vec<u8>)>();
+    let job_rx = Arc::new(Mutex::new(job_rx));
+    let (text_tx, text_rx) = channel::<(usize, String)>();
+    for _ in 0..decoders {
+        let (job_rx, text_tx) = (Arc::clone(&job_rx), text_tx.clone());
+        thread::spawn(move || loop {
+            let Ok((seq, bytes)) = job_rx.lock().unwrap().recv() else {
+                break;
+            };
+            let text = String::from_utf8(bytes)
+                .unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned());
+            if text_tx.send((seq, text)).is_err() {
+                break;
+            }
+        });
+    }
+    drop(text_tx);
+    let deliverer = thread::spawn(move || {
+        let (mut next, mut early) = (0, BTreeMap::new());
+        for (seq, text) in text_rx {
+            early.insert(seq, text);
+            while let Some(text) = early.remove(&next) {
+                next += 1;
+                if !delivery.push(text) {
+                    return;
+                }
+            }
+        }
+        delivery.finish(String::new());
+    });
+
+    let mut buf = vec![0; DECODE_CHUNK];
+    let mut utf8 = Utf8Carry::default();
+    let mut seq = 0;
+    loop {
+        let read_bytes = match pipe.read(&mut buf) {
+            Ok(0) => {
+                trace!("'{cmd:}' {name} closed");
+                break;
+            }
+            Ok(read_bytes) => read_bytes,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                trace!("'{cmd:}' {name} read failed: {e}");
+                if let Some(errors) = &errors {
+                    let _ = errors.send(copy_io_error(&e).into());
+                }
+                if let Some(failure) = &failure {
+                    *failure.lock().unwrap() = Some(e);
+                }
+                break;
+            }
+        };
+        if job_tx.send((seq, utf8.split(&buf[..read_bytes]))).is_err() {
+            trace!("'{cmd:}' {name} is not received anymore");
+            break;
+        }
+        seq += 1;
+    }
+    if !utf8.0.is_empty() {
+        let _ = job_tx.send((seq, utf8.0));
+    }
+    drop(job_tx);
+    if let Err(payload) = deliverer.join() {
+        panic::resume_unwind(payload);
+    }
+}
+
+/// Holds back a UTF-8 sequence cut in two by a read, so that it is decoded
+/// whole once the rest arrives.
+#[derive(Default)]
+struct Utf8Carry(Vec<u8>);
+
+impl Utf8Carry {
+    /// The bytes carried over and those of `read` up to the last complete
+    /// sequence.
+    fn split(&mut self, read: &[u8]) -> Vec<u8> {
+        self.0.extend_from_slice(read);
+        let complete = complete_utf8_len(&self.0);
+        let rest = self.0.split_off(complete);
+        std::mem::replace(&mut self.0, rest)
+    }
+}
+
+/// Length of `bytes` without a trailing incomplete UTF-8 sequence. Invalid bytes
+/// count as complete, decoding replaces them anyway.
+fn complete_utf8_len(bytes: &[u8]) -> usize {
+    for back in 1..=bytes.len().min(4) {
+        let lead = bytes[bytes.len() - back];
+        if lead & 0xC0 == 0x80 {
+            continue;
+        }
+        let len = match lead {
+            0xC0..=0xDF => 2,
+            0xE0..=0xEF => 3,
+            0xF0..=0xF7 => 4,
+            _ => 1,
+        };
+        return if len > back {
+            bytes.len() - back
+        } else {
+            bytes.len()
+        };
+    }
+    bytes.len()
+}
+
+/// The stages after decoding: the transforms, then framing into the sink.
+struct Delivery {
+    transforms: Vec<Box<dyn Transform>>,
+    framer: Framer,
+}
+
+impl Delivery {
+    /// Returns `false` once nothing more should be delivered.
+    fn push(&mut self, mut text: String) -> bool {
+        for transform in &mut self.transforms {
+            text = transform.push(&text);
+        }
+        self.framer.feed(text)
+    }
+
+    /// Delivers `tail` and whatever is held back, e.g. a last line lacking its
+    /// newline. Also done when the read failed.
+    fn finish(mut self, tail: String) {
+        let mut rest = tail;
+        for transform in &mut self.transforms {
+            rest = transform.push(&rest);
+            rest.push_str(&transform.finish());
+        }
+        if self.framer.feed(rest) {
+            self.framer.finish();
+        }
+    }
+}
+
+/// Size of each read with [`Command::stdout_bytes_into`].
+#[cfg(feature = "bytes")]
+const BYTES_CHUNK: usize = 64 * 1024;
+
+/// Reads `pipe` into a reused buffer and hands out each read as a slice of it,
+/// killing the child once `quota` is used up.
+#[cfg(feature = "bytes")]
+fn bytes_reader_worker(
+    cmd: String,
+    name: &'static str,
+    mut pipe: impl Read + Send + 'static,
+    tx: Sender<Bytes>,
+    mut quota: Option<Quota>,
+    child: Arc<Mutex<Child>>,
+    errors: Sender<Error>,
+) -> Worker {
+    Box::new(move || {
+        let mut buf = BytesMut::with_capacity(BYTES_CHUNK);
+        trace!("'{cmd:}' is in {name} read");
+        loop {
+            // reclaims the room of chunks the receiver has dropped already, and
+            // zeroes it so that the pipe is never handed uninitialized memory
+            buf.resize(BYTES_CHUNK, 0);
+            let chunk = match pipe.read(&mut buf) {
+                Ok(0) => break,
+                Ok(read_bytes) => {
+                    buf.truncate(read_bytes);
+                    buf.split().freeze()
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    trace!("'{cmd:}' {name} read failed: {e}");
+                    let _ = errors.send(e.into());
+                    break;
+                }
+            };
+            let (chunk, exhausted) = match &mut quota {
+                Some(quota) => quota.take_bytes(chunk),
+                None => (Some(chunk), false),
+            };
+            if let (true, Some(quota)) = (exhausted, &quota) {
+                quota.reached.store(true, Ordering::SeqCst);
+            }
+            if chunk.is_some_and(|chunk| tx.send(chunk).is_err()) {
+                break;
+            }
+            if exhausted {
+                trace!("read limit reached, killing the child");
+                if let Err(e) = child.lock().unwrap().kill() {
+                    let _ = errors.send(e.into());
+                }
+                break;
+            }
+        }
+        trace!("exiting the {name} thread of '{cmd:}'");
+    })
+}
+
+/// Hands out each read of `pipe` along with its decoded text.
+fn pairs_reader_worker(
+    cmd: String,
+    name: &'static str,
+    mut pipe: impl Read + Send + 'static,
+    tx: Sender<(Vec<u8>, String)>,
+    errors: Sender<Error>,
+) -> Worker {
+    Box::new(move || {
+        let mut buf = vec![0; DECODE_CHUNK];
+        let mut utf8 = Utf8Carry::default();
+        trace!("'{cmd:}' is in {name} read");
+        loop {
+            let read = match pipe.read(&mut buf) {
+                Ok(0) => break,
+                Ok(read_bytes) => &buf[..read_bytes],
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    trace!("'{cmd:}' {name} read failed: {e}");
+                    let _ = errors.send(e.into());
+                    break;
+                }
+            };
+            let text = String::from_utf8_lossy(&utf8.split(read)).into_owned();
+            if tx.send((read.to_vec(), text)).is_err() {
+                trace!("'{cmd:}' {name} is not received anymore");
+                return;
+            }
+        }
+        if !utf8.0.is_empty() {
+            let _ = tx.send((Vec::new(), String::from_utf8_lossy(&utf8.0).into_owned()));
+        }
+        trace!("exiting the {name} thread of '{cmd:}'");
+    })
+}
+
+/// Copies stdout into `writer` until either end fails, killing the child then if
+/// `kill` is given. A failed write is reported to `errors`.
+fn writer_reader_worker(
+    cmd: String,
+    mut pipe: impl Read + Send + 'static,
+    mut writer: Box<dyn Write + Send>,
+    kill: Option<Arc<Mutex<Child>>>,
+    errors: Sender<Error>,
+) -> Worker {
+    Box::new(move || {
+        #[cfg(unix)]
+        signal::block_sigpipe();
+        let mut buf = vec![0; DECODE_CHUNK];
+        trace!("'{cmd:}' is in stdout read");
+        loop {
+            let read = match pipe.read(&mut buf) {
+                Ok(0) => break,
+                Ok(read_bytes) => &buf[..read_bytes],
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    trace!("'{cmd:}' stdout read failed: {e}");
+                    break;
+                }
+            };
+            if let Err(e) = writer.write_all(read) {
+                trace!("writing the stdout of '{cmd:}' failed: {e}");
+                let _ = errors.send(e.into());
+                if let Some(child) = &kill {
+                    if let Err(e) = child.lock().unwrap().kill() {
+                        let _ = errors.send(e.into());
+                    }
+                }
+                return;
+            }
+        }
+        let _ = writer.flush();
+        trace!("exiting the stdout thread of '{cmd:}'");
+    })
+}
+
+/// A stateful rewrite of the decoded output, applied before line framing. It may
+/// hold text back, e.g. a sequence split across reads, until more arrives.
+trait Transform: Send {
+    fn push(&mut self, text: &str) -> String;
+
+    /// Whatever is still held back when the stream closes.
+    fn finish(&mut self) -> String {
+        String::new()
+    }
+}
+
+/// Drops ANSI escape sequences: CSI ones like colors, OSC ones like window
+/// titles, and the other `ESC` sequences.
+#[derive(Default)]
+struct StripAnsi {
+    state: AnsiState,
+}
+
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+enum AnsiState {
+    #[default]
+    Text,
+    /// Right after `ESC`, or in the intermediate bytes that may follow it.
+    Escape,
+    /// In the parameters of `ESC [`, up to the final byte.
+    Csi,
+    /// In the text of `ESC ]`, up to `BEL` or `ESC \`.
+    Osc,
+    /// An `ESC` within an OSC sequence.
+    OscEscape,
+}
+
+impl Transform for StripAnsi {
+    fn push(&mut self, text: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+        for c in text.chars() {
+            self.state = match (self.state, c) {
+                (AnsiState::Text, '\x1b') => AnsiState::Escape,
+                (AnsiState::Text, c) => {
+                    out.push(c);
+                    AnsiState::Text
+                }
+                (AnsiState::Escape, '[') => AnsiState::Csi,
+                (AnsiState::Escape, ']') => AnsiState::Osc,
+                (AnsiState::Escape, ' '..='/') => AnsiState::Escape,
+                (AnsiState::Csi, '@'..='~') => AnsiState::Text,
+                (AnsiState::Csi, _) => AnsiState::Csi,
+                (AnsiState::Osc, '\x07') => AnsiState::Text,
+                (AnsiState::Osc, '\x1b') => AnsiState::OscEscape,
+                (AnsiState::OscEscape, '\\') => AnsiState::Text,
+                (AnsiState::Osc | AnsiState::OscEscape, _) => AnsiState::Osc,
+                (AnsiState::Escape, _) => AnsiState::Text,
+            };
+        }
+        out
+    }
+
+    fn finish(&mut self) -> String {
+        // an unterminated sequence is dropped
+        self.state = AnsiState::Text;
+        String::new()
+    }
+}
+
+/// Rewrites `\r\n` and lone `\r` to `\n`.
+#[derive(Default)]
+struct NormalizeNewlines {
+    /// The previous read ended in `\r`.
+    carried_cr: bool,
+}
+
+impl Transform for NormalizeNewlines {
+    fn push(&mut self, text: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+        for c in text.chars() {
+            if std::mem::take(&mut self.carried_cr) {
+                out.push('\n');
+                if c == '\n' {
+                    continue;
+                }
+            }
+            match c {
+                '\r' => self.carried_cr = true,
+                c => out.push(c),
+            }
+        }
+        out
+    }
+
+    fn finish(&mut self) -> String {
+        if std::mem::take(&mut self.carried_cr) {
+            "\n".to_owned()
+        } else {
+            String::new()
+        }
+    }
+}
+
+/// Drops everything up to and including the line containing the marker.
+struct CaptureAfter {
+    marker: String,
+    pending: String,
+    open: bool,
+}
+
+impl CaptureAfter {
+    fn new(marker: String) -> Self {
+        Self {
+            marker,
+            pending: String::new(),
+            open: false,
+        }
+    }
+}
+
+impl Transform for CaptureAfter {
+    fn push(&mut self, text: &str) -> String {
+        if self.open {
+            return text.to_owned();
+        }
+        self.pending.push_str(text);
+        if let Some(pos) = self.pending.find(&self.marker) {
+            if let Some(eol) = self.pending[pos..].find('\n') {
+                self.open = true;
+                let rest = self.pending.split_off(pos + eol + 1);
+                self.pending.clear();
+                return rest;
+            }
+            self.pending.drain(..pos);
+        } else {
+            // only a marker split across reads needs to be kept
+            let mut keep = self.pending.len().saturating_sub(self.marker.len());
+            while !self.pending.is_char_boundary(keep) {
+                keep += 1;
+            }
+            self.pending.drain(..keep);
+        }
+        String::new()
+    }
+}
+
+/// Cuts the text into messages, lines in line mode or records with
+/// [`Command::record_sentinel`], and hands them to the sink.
+struct Framer {
+    lines: Option<LineBuffer>,
+    dedup: Option<Dedup>,
+    records: Option<RecordBuffer>,
+    sink: OutputSink,
+}
+
+impl Framer {
+    /// Returns `false` once nothing more should be delivered.
+    fn feed(&mut self, text: String) -> bool {
+        if text.is_empty() {
+            return true;
+        }
+        match &mut self.lines {
+            Some(lines) => lines.push(&text).into_iter().all(|line| self.line(line)),
+            None => self.sink.send(text),
+        }
+    }
+
+    fn line(&mut self, line: String) -> bool {
+        let Some(dedup) = &mut self.dedup else {
+            return self.deduped_line(line);
+        };
+        if dedup.last.as_ref() == Some(&line) {
+            dedup.repeats += 1;
+            return true;
+        }
+        let summary = dedup.summary();
+        dedup.last = Some(line.clone());
+        summary.is_none_or(|summary| self.deduped_line(summary)) && self.deduped_line(line)
+    }
+
+    fn deduped_line(&mut self, line: String) -> bool {
+        match &mut self.records {
+            Some(records) => match records.push(line) {
+                Some(record) => self.sink.send(record),
+                None => true,
+            },
+            None => self.sink.send(line),
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Some(rest) = self.lines.take().and_then(LineBuffer::finish) {
+            self.mark_unterminated(&rest);
+            if !self.line(rest) {
+                return;
+            }
+        }
+        if let Some(summary) = self.dedup.as_mut().and_then(Dedup::summary) {
+            if !self.deduped_line(summary) {
+                return;
+            }
+        }
+        if let Some(record) = self.records.take().and_then(|records| records.record) {
+            self.sink.send(record);
+        }
+    }
+
+    /// Tells the number `rest`, a final line lacking its newline, is sent as,
+    /// unless it is dropped as a repeat or goes into a record. Marked before the
+    /// send so that the receiver sees the mark along with the line.
+    fn mark_unterminated(&self, rest: &str) {
+        let (Some(unterminated), None) = (&self.sink.unterminated, &self.records) else {
+            return;
+        };
+        let summary = match &self.dedup {
+            Some(dedup) if dedup.last.as_deref() == Some(rest) => return,
+            Some(dedup) => usize::from(dedup.repeats > 0),
+            None => 0,
+        };
+        unterminated.store(self.sink.sent + summary + 1, Ordering::SeqCst);
+    }
+}
+
+/// Drops repeated lines, see [`Command::dedup_consecutive`].
+#[derive(Default)]
+struct Dedup {
+    last: Option<String>,
+    /// Copies of `last` dropped since it was delivered.
+    repeats: usize,
+}
+
+impl Dedup {
+    /// The line telling about the copies dropped so far, if any.
+    fn summary(&mut self) -> Option<String> {
+        match mem::take(&mut self.repeats) {
+            0 => None,
+            1 => Some("(repeated 1 time)".to_owned()),
+            n => Some(format!("(repeated {n} times)")),
+        }
+    }
+}
+
+/// Collects lines into records, see [`Command::record_sentinel`].
+struct RecordBuffer {
+    sentinel: String,
+    /// `None` until a line after the last sentinel arrives.
+    record: Option<String>,
+}
+
+impl RecordBuffer {
+    /// The finished record if `line` is the sentinel.
+    fn push(&mut self, line: String) -> Option<String> {
+        if line == self.sentinel {
+            return Some(self.record.take().unwrap_or_default());
+        }
+        match &mut self.record {
+            Some(record) => {
+                record.push('\n');
+                record.push_str(&line);
+            }
+            None => self.record = Some(line),
+        }
+        None
+    }
+}
+
+/// The sending half of the channel a reader thread delivers output into. It is
+/// implemented for the `std::sync::mpsc` senders used by default; implement it to
+/// plug in another channel, e.g. one that exposes its queue depth, and hand it to
+/// [`Command::stdout_into`] or [`Command::stderr_into`].
+pub trait OutputSender: Send + 'static {
+    /// Delivers `msg`, returning `false` once the receiving side is gone.
+    fn send(&self, msg: String) -> bool;
+}
+
+impl OutputSender for Sender<String> {
+    fn send(&self, msg: String) -> bool {
+        Sender::send(self, msg).is_ok()
+    }
+}
+
+impl OutputSender for SyncSender<String> {
+    fn send(&self, msg: String) -> bool {
+        SyncSender::send(self, msg).is_ok()
+    }
+}
+
+/// Turns messages into `tracing` events, see [`Command::log_stderr`].
+struct TracingSender {
+    program: String,
+    level: Level,
+}
+
+impl OutputSender for TracingSender {
+    fn send(&self, msg: String) -> bool {
+        let program = &self.program;
+        match self.level {
+            Level::ERROR => tracing::error!(program = %program, "{msg}"),
+            Level::WARN => tracing::warn!(program = %program, "{msg}"),
+            Level::INFO => tracing::info!(program = %program, "{msg}"),
+            Level::DEBUG => tracing::debug!(program = %program, "{msg}"),
+            _ => trace!(program = %program, "{msg}"),
+        }
+        true
+    }
+}
+
+/// Parses messages as JSON, see [`Command::run_json_lines_into`].
+#[cfg(feature = "serde")]
+struct JsonLinesSender<T>(Sender<Result<T, Error>>);
+
+#[cfg(feature = "serde")]
+impl<T: serde::de::DeserializeOwned + Send + 'static> OutputSender for JsonLinesSender<T> {
+    fn send(&self, msg: String) -> bool {
+        if msg.trim().is_empty() {
+            return true;
+        }
+        let parsed = serde_json::from_str(&msg).map_err(Error::from);
+        self.0.send(parsed).is_ok()
+    }
+}
+
+/// Where a reader thread delivers its messages.
+struct OutputSink {
+    tx: Box<dyn OutputSender>,
+    /// Messages sent but not received yet, unknown for a custom `OutputSender`.
+    backlog: Option<Arc<AtomicUsize>>,
+    /// The largest backlog so far.
+    peak_backlog: Option<Arc<AtomicUsize>>,
+    high_water: Option<HighWater>,
+    quota: Option<Quota>,
+    /// Set for the built-in channel so its receiver can report a read error.
+    failure: Option<ReadFailure>,
+    /// Keeps a copy of the last message, see [`ProcessHandle::latest_line`].
+    latest: Option<SharedMessage>,
+    /// Keeps the first non-empty message, see [`Command::fail_on_stderr`].
+    first: Option<SharedMessage>,
+    /// Keeps the last ones, see [`Command::fail_on_nonzero`].
+    tail: Option<TailBuffer>,
+    /// Gets a copy of every message, see [`Command::tee_stdout_rotating`].
+    tee: Option<RotatingLog>,
+    /// Delivers lines without their terminator, which the tee adds back.
+    lines: bool,
+    kill_on_first: bool,
+    /// See [`ProcessHandle::errors`].
+    errors: Option<Sender<Error>>,
+    /// Cleared by every message, see [`StdinSender::send_if_idle`].
+    awaiting_response: Option<Arc<AtomicBool>>,
+    /// Counts every message, see [`Command::on_stall`].
+    delivered: Option<Arc<AtomicUsize>>,
+    /// Messages sent successfully so far.
+    sent: usize,
+    /// Gets the number of the final line if it lacks its newline.
+    unterminated: Option<Arc<AtomicUsize>>,
+    child: Arc<Mutex<Child>>,
+}
+
+impl OutputSink {
+    fn new(child: Arc<Mutex<Child>>) -> Self {
+        Self {
+            tx: Box::new(channel::<String>().0),
+            backlog: None,
+            peak_backlog: None,
+            high_water: None,
+            quota: None,
+            failure: None,
+            latest: None,
+            first: None,
+            tail: None,
+            tee: None,
+            lines: false,
+            kill_on_first: false,
+            awaiting_response: None,
+            delivered: None,
+            sent: 0,
+            unterminated: None,
+            errors: None,
+            child,
+        }
+    }
+
+    /// Returns `false` once nothing more should be delivered.
+    fn send(&mut self, msg: String) -> bool {
+        if let Some(tee) = &mut self.tee {
+            let written = match self.lines {
+                true => tee.write(format!("{msg}\n").as_bytes()),
+                false => tee.write(msg.as_bytes()),
+            };
+            if let Err(e) = written {
+                trace!("writing the stdout copy failed, stopping it: {e}");
+                self.tee = None;
+            }
+        }
+        if let Some(tail) = &self.tail {
+            tail.push(&msg);
+        }
+        if let (Some(first), false) = (&self.first, msg.is_empty()) {
+            let mut first = first.lock().unwrap();
+            if first.is_none() {
+                *first = Some(msg.clone());
+                if self.kill_on_first {
+                    trace!("output on a strict stream, killing the child");
+                    self.kill_child();
+                }
+            }
+        }
+        let Some(quota) = &mut self.quota else {
+            return self.deliver(msg);
+        };
+        let (msg, exhausted) = quota.take(msg);
+        if exhausted {
+            quota.reached.store(true, Ordering::SeqCst);
+        }
+        let sent = msg.is_none_or(|msg| self.deliver(msg));
+        if exhausted {
+            trace!("read limit reached, killing the child");
+            self.kill_child();
+        }
+        sent && !exhausted
+    }
+
+    fn fail(&self, e: io::Error) {
+        self.report(copy_io_error(&e));
+        if let Some(failure) = &self.failure {
+            *failure.lock().unwrap() = Some(e);
+        }
+    }
+
+    fn kill_child(&self) {
+        if let Err(e) = self.child.lock().unwrap().kill() {
+            self.report(e);
+        }
+    }
+
+    fn report(&self, e: io::Error) {
+        if let Some(errors) = &self.errors {
+            let _ = errors.send(e.into());
+        }
+    }
+
+    fn deliver(&mut self, msg: String) -> bool {
+        if let Some(awaiting) = &self.awaiting_response {
+            awaiting.store(false, Ordering::SeqCst);
+        }
+        if let Some(delivered) = &self.delivered {
+            delivered.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(latest) = &self.latest {
+            *latest.lock().unwrap() = Some(msg.clone());
+        }
+        // counted up front, the receiver may take the message before send returns
+        if let Some(backlog) = &self.backlog {
+            let queued = backlog.fetch_add(1, Ordering::Relaxed) + 1;
+            if let Some(peak) = &self.peak_backlog {
+                peak.fetch_max(queued, Ordering::Relaxed);
+            }
+            if let Some(high_water) = &mut self.high_water {
+                high_water.check(queued);
+            }
+        }
+        let sent = self.tx.send(msg);
+        if sent {
+            self.sent += 1;
+        } else if let Some(backlog) = &self.backlog {
+            backlog.fetch_sub(1, Ordering::Relaxed);
+        }
+        sent
+    }
+}
+
+/// Tells [`Command::on_backlog`] about a backlog rising above its threshold.
+struct HighWater {
+    threshold: usize,
+    callback: OnBacklog,
+    /// Already called for the current crossing.
+    above: bool,
+}
+
+impl HighWater {
+    fn check(&mut self, queued: usize) {
+        match (queued > self.threshold, self.above) {
+            (true, false) => {
+                trace!("backlog of {} is above {}", queued, self.threshold);
+                self.above = true;
+                (self.callback.lock().unwrap())();
+            }
+            (false, true) => self.above = false,
+            _ => {}
+        }
+    }
+}
+
+/// The end of a stream, cut like [`Error::NonZeroExit`] keeps it.
+struct TailBuffer {
+    text: Arc<Mutex<String>>,
+    /// Messages are lines without their terminator.
+    lines: bool,
+}
+
+impl TailBuffer {
+    fn push(&self, msg: &str) {
+        let mut text = self.text.lock().unwrap();
+        text.push_str(msg);
+        if self.lines {
+            text.push('\n');
+        }
+        let cut = text.len() - stderr_tail(&text).len();
+        text.drain(..cut);
+    }
+}
+
+/// How much output to read before the child is cancelled, see
+/// [`Command::read_limit`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadLimit {
+    /// Number of messages, i.e. lines in [`Command::line_mode`].
+    Lines(usize),
+    /// Number of bytes; the message crossing the limit is truncated.
+    Bytes(usize),
+}
+
+struct Quota {
+    limit: ReadLimit,
+    used: usize,
+    /// Set when the quota is used up, for [`RunResult::OutputLimited`].
+    reached: Arc<AtomicBool>,
+}
+
+impl Quota {
+    fn new(limit: ReadLimit, reached: Arc<AtomicBool>) -> Self {
+        Self {
+            limit,
+            used: 0,
+            reached,
+        }
+    }
+
+    /// Trims `msg` to what is left of the quota, `None` if nothing is, and
+    /// tells if it is used up.
+    fn take(&mut self, mut msg: String) -> (Option<String>, bool) {
+        match self.limit {
+            ReadLimit::Lines(limit) if self.used >= limit => (None, true),
+            ReadLimit::Lines(limit) => {
+                self.used += 1;
+                (Some(msg), self.used >= limit)
+            }
+            ReadLimit::Bytes(limit) => {
+                let mut left = limit.saturating_sub(self.used);
+                if msg.len() >= left {
+                    while !msg.is_char_boundary(left) {
+                        left -= 1;
+                    }
+                    msg.truncate(left);
+                    self.used = limit;
+                    (Some(msg).filter(|msg| !msg.is_empty()), true)
+                } else {
+                    self.used += msg.len();
+                    (Some(msg), false)
+                }
+            }
+        }
+    }
+
+    /// Like [`Quota::take`], for chunks that may be cut anywhere.
+    #[cfg(feature = "bytes")]
+    fn take_bytes(&mut self, mut chunk: Bytes) -> (Option<Bytes>, bool) {
+        let exhausted = match self.limit {
+            ReadLimit::Lines(limit) if self.used >= limit => return (None, true),
+            ReadLimit::Lines(limit) => {
+                self.used += 1;
+                self.used >= limit
+            }
+            ReadLimit::Bytes(limit) => {
+                chunk.truncate(limit.saturating_sub(self.used));
+                self.used += chunk.len();
+                self.used >= limit
+            }
+        };
+        (Some(chunk).filter(|chunk| !chunk.is_empty()), exhausted)
+    }
+}
+
+/// Reassembles lines from chunks that may end anywhere within a line.
+#[derive(Default)]
+struct LineBuffer {
+    partial: String,
+}
+
+impl LineBuffer {
+    fn push(&mut self, chunk: &str) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut rest = chunk;
+        while let Some(pos) = rest.find('\n') {
+            self.partial.push_str(&rest[..pos]);
+            if self.partial.ends_with('\r') {
+                self.partial.pop();
+            }
+            lines.push(std::mem::take(&mut self.partial));
+            rest = &rest[pos + 1..];
+        }
+        self.partial.push_str(rest);
+        lines
+    }
+
+    fn finish(self) -> Option<String> {
+        (!self.partial.is_empty()).then_some(self.partial)
+    }
+}
+
+#[derive(Debug, ThisError)]
+pub enum Error {
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+    #[error(transparent)]
+    SendError(#[from] mpsc::SendError<String>),
+    #[error("thread could not join")]
+    ThreadCouldNotJoin(String),
+    #[error("handle has neither a child process nor an exit status")]
+    NoChild,
+    #[error("race needs at least one command")]
+    NoCommands,
+    /// Displayed with the stderr tail appended, so a `?`-propagated failure
+    /// explains itself in logs.
+    #[error("'{program}' exited with {status}{}", stderr_suffix(.stderr))]
+    NonZeroExit {
+        program: String,
+        status: ExitStatus,
+        /// The last 20 lines of stderr, at most 2 KiB of them.
+        stderr: String,
+    },
+    #[error("child does not accept stdin anymore")]
+    StdinClosed(String),
+    #[error("a callback panicked: {0}")]
+    CallbackPanicked(String),
+    #[error("child wrote to stderr: {0}")]
+    StderrProduced(String),
+    #[error("'{0}' was run already")]
+    AlreadyRun(String),
+    #[error("spawning took longer than {0:?}")]
+    SpawnTimeout(Duration),
+    #[error("timed out waiting for '{pattern}'")]
+    ExpectTimeout { pattern: String, seen: String },
+    #[error("output ended before '{pattern}'")]
+    ExpectEof { pattern: String, seen: String },
+    #[error("cannot split the command line: {0}")]
+    InvalidCommandLine(String),
+    #[error("no user named '{0}'")]
+    UnknownUser(String),
+    #[error("could not start the child as uid {uid:?}, gid {gid:?}")]
+    PrivilegeDrop {
+        uid: Option<u32>,
+        gid: Option<u32>,
+        source: io::Error,
+    },
+    #[cfg(feature = "serde")]
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+impl ProcessHandle {
+    /// Builds a handle that is not backed by a process, e.g. to return canned
+    /// output from a [`CommandRunner`] mock.
+    pub fn from_channels(
+        stdin: Option<Sender<String>>,
+        stdout: Option<Receiver<String>>,
+        stderr: Option<Receiver<String>>,
+    ) -> Self {
+        let (stdout_backlog, stderr_backlog) = (Arc::default(), Arc::default());
+        Self {
+            stdin: stdin.map(StdinSender::from),
+            #[cfg(feature = "tokio")]
+            async_stdin: None,
+            #[cfg(feature = "rtrb")]
+            stdout_ring: None,
+            stdout: stdout.map(|rx| {
+                StdoutReceiver(
+                    rx,
+                    Arc::clone(&stdout_backlog),
+                    Arc::default(),
+                    Cell::default(),
+                    None,
+                    false,
+                )
+            }),
+            stderr: stderr
+                .map(|rx| StderrReceiver(rx, Arc::clone(&stderr_backlog), Cell::default(), None)),
+            errors: None,
+            child: None,
+            exit_status: None,
+            recording: None,
+            stdout_backlog,
+            stderr_backlog,
+            stdout_peak_backlog: Arc::default(),
+            stderr_peak_backlog: Arc::default(),
+            stdout_unterminated: Arc::default(),
+            stderr_unterminated: Arc::default(),
+            cancel: None,
+            line_mode: false,
+            trim_output: false,
+            poll_interval: POLL_INTERVAL,
+            clock: Arc::new(SystemClock),
+            expect_buffer: Mutex::default(),
+            readers: Arc::default(),
+            stderr_reader: Arc::default(),
+            latest_line: Arc::default(),
+            first_stderr: None,
+            nonzero: None,
+            read_limit_reached: Arc::default(),
+            panicked: None,
+            context: None,
+            lazy: None,
+        }
+    }
+
+    /// A handle on the same child and state, without the stdio ends, the errors,
+    /// the recording and the expect buffer, so that a lazy handle can wait on its
+    /// child without holding the lock on it.
+    fn share(&self) -> Self {
+        Self {
+            stdin: None,
+            #[cfg(feature = "tokio")]
+            async_stdin: None,
+            #[cfg(feature = "rtrb")]
+            stdout_ring: None,
+            stdout: None,
+            stderr: None,
+            errors: None,
+            child: self.child.clone(),
+            exit_status: self.exit_status,
+            recording: None,
+            stdout_backlog: Arc::clone(&self.stdout_backlog),
+            stderr_backlog: Arc::clone(&self.stderr_backlog),
+            stdout_peak_backlog: Arc::clone(&self.stdout_peak_backlog),
+            stderr_peak_backlog: Arc::clone(&self.stderr_peak_backlog),
+            stdout_unterminated: Arc::clone(&self.stdout_unterminated),
+            stderr_unterminated: Arc::clone(&self.stderr_unterminated),
+            cancel: self.cancel.clone(),
+            line_mode: self.line_mode,
+            trim_output: self.trim_output,
+            poll_interval: self.poll_interval,
+            clock: Arc::clone(&self.clock),
+            expect_buffer: Mutex::default(),
+            readers: Arc::clone(&self.readers),
+            stderr_reader: Arc::clone(&self.stderr_reader),
+            latest_line: Arc::clone(&self.latest_line),
+            first_stderr: self.first_stderr.clone(),
+            nonzero: self.nonzero.clone(),
+            read_limit_reached: Arc::clone(&self.read_limit_reached),
+            panicked: self.panicked.clone(),
+            context: self.context.clone(),
+            lazy: None,
+        }
+    }
+
+    /// Sets the status that [`ProcessHandle::wait`] reports for a handle built from
+    /// channels.
+    pub fn with_exit_status(mut self, status: ExitStatus) -> Self {
+        self.exit_status = Some(status);
+        self
+    }
+
+    /// OS process id of the child, `None` for handles built from channels.
+    pub fn id(&self) -> Option<u32> {
+        if let Some(lazy) = &self.lazy {
+            return lazy.with_handle(|handle| Ok(handle.id())).ok().flatten();
+        }
+        self.child.as_ref().map(|child| child.lock().unwrap().id())
+    }
+
+    /// Runs `f` with the child locked, for `std::process::Child` methods this crate
+    /// does not wrap. The lock is shared with the canceller thread and with
+    /// [`ProcessHandle::wait`], so `f` must not call back into this handle, and
+    /// while `f` runs the canceller cannot kill the child.
+    pub fn with_child<R>(&self, f: impl FnOnce(&mut Child) -> R) -> Result<R, Error> {
+        if let Some(lazy) = &self.lazy {
+            return lazy.with_handle(|handle| handle.with_child(f));
+        }
+        let child = self.child.as_ref().ok_or(Error::NoChild)?;
+        let mut child = child.lock().unwrap();
+        Ok(f(&mut child))
+    }
+
+    /// Delivers `signal` to the child, see [`Signal`] for other platforms. Does
+    /// nothing once the child has exited.
+    pub fn signal(&self, signal: Signal) -> Result<(), Error> {
+        Ok(self.with_child(|child| signal::send(child, signal))??)
+    }
+
+    /// Replaces the canceller given to `run` (or the previous `set_canceller`).
+    /// A replaced canceller is disarmed: its thread exits without killing within
+    /// [`Command::poll_interval`], letting go of the canceller. If the
+    /// child was already cancelled or has exited, the new canceller has nothing
+    /// left to kill.
+    pub fn set_canceller(&self, canceller: impl Into<Canceller>) -> Result<(), Error> {
+        if let Some(lazy) = &self.lazy {
+            return lazy.with_handle(|handle| handle.set_canceller(canceller));
+        }
+        let cancel = self.cancel.as_ref().ok_or(Error::NoChild)?;
+        thread::spawn(cancel.worker(canceller.into()));
+        Ok(())
+    }
+
+    /// Whether a canceller (or dropping it, with [`Command::kill_on_canceller_drop`])
+    /// killed the child. A child that had exited on its own by then counts as not
+    /// cancelled, whatever its exit status.
+    pub fn was_cancelled(&self) -> bool {
+        if let Some(lazy) = &self.lazy {
+            return lazy.if_started(ProcessHandle::was_cancelled) == Some(true);
+        }
+        self.cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.cancelled.load(Ordering::SeqCst))
+    }
+
+    /// Starts an [`Expect`] script waiting for `pattern` on stdout.
+    pub fn expect(&self, pattern: impl Into<String>) -> Expect<'_> {
+        Expect::new(self).expect(pattern)
+    }
+
+    /// The context attached with [`Command::with_context`], if it is a `C`.
+    pub fn context<C: 'static>(&self) -> Option<&C> {
+        self.context.as_ref()?.downcast_ref()
+    }
+
+    /// The last stdout line read so far, whether or not it has been received yet;
+    /// the live one-line status of a progress-reporting child. Always `None`
+    /// without [`Command::line_mode`].
+    pub fn latest_line(&self) -> Option<String> {
+        if let Some(lazy) = &self.lazy {
+            return lazy.if_started(ProcessHandle::latest_line).flatten();
+        }
+        self.latest_line.lock().unwrap().clone()
+    }
+
+    /// Messages delivered to the stdout receiver but not received from it yet.
+    /// Always 0 with [`Command::stdout_into`] and the undecoded modes.
+    pub fn stdout_backlog(&self) -> usize {
+        self.stdout_backlog.load(Ordering::Relaxed)
+    }
+
+    /// Messages delivered to the stderr receiver but not received from it yet.
+    /// Always 0 with [`Command::stderr_into`] and the undecoded modes.
+    pub fn stderr_backlog(&self) -> usize {
+        self.stderr_backlog.load(Ordering::Relaxed)
+    }
+
+    /// The largest [`ProcessHandle::stdout_backlog`] seen so far, i.e. how far the
+    /// consumer fell behind at worst.
+    pub fn stdout_peak_backlog(&self) -> usize {
+        self.stdout_peak_backlog.load(Ordering::Relaxed)
+    }
+
+    /// The largest [`ProcessHandle::stderr_backlog`] seen so far.
+    pub fn stderr_peak_backlog(&self) -> usize {
+        self.stderr_peak_backlog.load(Ordering::Relaxed)
+    }
+
+    /// Blocks until the child exits. The child is polled rather than waited on,
+    /// so the canceller can still kill it meanwhile.
+    pub fn wait(&self) -> Result<ExitStatus, Error> {
+        if let Some(lazy) = &self.lazy {
+            return lazy.with_handle(ProcessHandle::wait);
+        }
+        loop {
+            if let Some(status) = self.poll_status()? {
+                return Ok(status);
+            }
+            thread::sleep(self.poll_interval);
+        }
+    }
+
+    /// The exit status once the child has exited, checked like
+    /// [`ProcessHandle::wait`] does. Starts the child of a lazy handle.
+    fn poll_status(&self) -> Result<Option<ExitStatus>, Error> {
+        if let Some(lazy) = &self.lazy {
+            return lazy.with_handle(ProcessHandle::poll_status);
+        }
+        self.try_status()?
+            .map(|status| self.check_status(status))
+            .transpose()
+    }
+
+    /// Like [`ProcessHandle::wait`], but only fails for a callback panic, not
+    /// for [`Command::fail_on_nonzero`] or [`Command::fail_on_stderr`], for a
+    /// child killed at a deadline whose output is returned all the same.
+    fn wait_unchecked(&self) -> Result<ExitStatus, Error> {
+        loop {
+            if let Some(status) = self.try_status()? {
+                self.check_panics()?;
+                return Ok(status);
+            }
+            thread::sleep(self.poll_interval);
+        }
+    }
+
+    /// Fails for a callback panic, stderr output or a nonzero status if the
+    /// command asked for that.
+    fn check_status(&self, status: ExitStatus) -> Result<ExitStatus, Error> {
+        self.check_panics()?;
+        self.check_stderr()?;
+        self.check_nonzero(status).map(|()| status)
+    }
+
+    /// Shuts the child down the way a service manager does: waits up to
+    /// `term_after` for it to exit on its own, then sends [`Signal::Term`] and
+    /// waits up to `kill_after` more, then kills it. Returns the final status,
+    /// checked like [`ProcessHandle::wait`] does.
+    pub fn shutdown(
+        &self,
+        term_after: Duration,
+        kill_after: Duration,
+    ) -> Result<ExitStatus, Error> {
+        if let Some(lazy) = &self.lazy {
+            return lazy.with_handle(|handle| handle.shutdown(term_after, kill_after));
+        }
+        if self.wait_timeout(term_after)?.is_none() {
+            trace!("child did not exit within {term_after:?}, terminating it");
+            self.signal(Signal::Term)?;
+            if self.wait_timeout(kill_after)?.is_none() {
+                trace!("child ignored the termination, killing it");
+                self.signal(Signal::Kill)?;
+            }
+        }
+        self.wait()
+    }
+
+    /// Polls for the exit status for up to `timeout`.
+    fn wait_timeout(&self, timeout: Duration) -> Result<Option<ExitStatus>, Error> {
+        let deadline = self.clock.now() + timeout;
+        loop {
+            let status = self.try_status()?;
+            let left = deadline.saturating_duration_since(self.clock.now());
+            if status.is_some() || left.is_zero() {
+                return Ok(status);
+            }
+            thread::sleep(left.min(self.poll_interval));
+        }
+    }
+
+    /// With [`Command::fail_on_stderr`], fails if the child wrote to stderr. Unless
+    /// that was seen already, waits for the stderr reader to reach the end of
+    /// the pipe, up to [`STDERR_GRACE`] in case a grandchild holds it open.
+    fn check_stderr(&self) -> Result<(), Error> {
+        let Some(first) = &self.first_stderr else {
+            return Ok(());
+        };
+        if first.lock().unwrap().is_none() {
+            self.stderr_reader.wait_closed(STDERR_GRACE);
+        }
+        match first.lock().unwrap().clone() {
+            Some(first) => Err(Error::StderrProduced(first)),
+            None => Ok(()),
+        }
+    }
+
+    /// With [`Command::fail_on_nonzero`], fails on an unsuccessful `status` once
+    /// the stderr reader reached the end of the pipe, so the tail holds the
+    /// child's last words; again giving up after [`STDERR_GRACE`].
+    fn check_nonzero(&self, status: ExitStatus) -> Result<(), Error> {
+        let Some((program, tail)) = &self.nonzero else {
+            return Ok(());
+        };
+        if status.success() {
+            return Ok(());
+        }
+        self.stderr_reader.wait_closed(STDERR_GRACE);
+        Err(Error::NonZeroExit {
+            program: program.clone(),
+            status,
+            stderr: tail.lock().unwrap().clone(),
+        })
+    }
+
+    fn check_panics(&self) -> Result<(), Error> {
+        let Some(panicked) = &self.panicked else {
+            return Ok(());
+        };
+        match panicked.lock().unwrap().clone() {
+            Some(message) => Err(Error::CallbackPanicked(message)),
+            None => Ok(()),
+        }
+    }
+
+    fn try_status(&self) -> Result<Option<ExitStatus>, Error> {
+        let Some(child) = &self.child else {
+            return self.exit_status.map(Some).ok_or(Error::NoChild);
+        };
+        Ok(child.lock().unwrap().try_wait()?)
+    }
+
+    /// Blocks until the reader threads have delivered all output to the
+    /// receivers (or output senders), up to `grace`. The child exiting does not
+    /// imply this: the readers may still be busy with its last output, so call
+    /// this after [`ProcessHandle::wait`] before treating what was received as
+    /// complete. Returns `false` if `grace` ran out first, e.g. because a
+    /// grandchild still holds the pipes open.
+    pub fn wait_for_output_drain(&self, grace: Duration) -> bool {
+        if let Some(lazy) = &self.lazy {
+            let drained = lazy.if_started(|handle| handle.wait_for_output_drain(grace));
+            return drained != Some(false);
+        }
+        self.readers.wait_closed(grace)
+    }
+
+    /// Closes stdin, drains stdout and stderr until they close and waits for the
+    /// child. The usual last step when driving a filter-style command.
+    pub fn finish_and_collect(self) -> Result<Output, Error> {
+        self.collect(|_, _| {})
+    }
+
+    /// Closes stdin and yields every stdout line, then once stdout has closed the
+    /// exit status as by [`ProcessHandle::wait`], so one loop sees both. Expects
+    /// [`Command::line_mode`]. A failed read is yielded as an `Err` before the
+    /// exit, a failed wait in place of it; either way the exit comes last.
+    /// Take stderr first to read it alongside.
+    pub fn items(mut self) -> impl Iterator<Item = Result<Item, Error>> {
+        drop(self.stdin.take());
+        let stdout = self.stdout.take();
+        stdout
+            .into_iter()
+            .flat_map(StdoutReceiver::lines)
+            .map(|line| line.map(Item::Line))
+            .chain(iter::once_with(move || self.wait().map(Item::Exit)))
+    }
+
+    /// Like [`ProcessHandle::finish_and_collect`], but gives up once `deadline`
+    /// has passed: the child is then killed and the output read so far returned
+    /// with [`Output::timed_out`] true, even with [`Command::fail_on_nonzero`] or
+    /// [`Command::fail_on_stderr`]. Unlike a cancel this does not wait for the
+    /// pipes to close, so a grandchild holding them cannot hold it up either.
+    pub fn join_with_deadline(self, deadline: Duration) -> Result<Output, Error> {
+        let deadline = self.clock.now() + deadline;
+        self.collect_until(Some(deadline), |_, _| {})
+    }
+
+    fn collect(self, on_chunk: impl FnMut(StreamKind, &str)) -> Result<Output, Error> {
+        self.collect_until(None, on_chunk)
+    }
+
+    fn collect_until(
+        mut self,
+        deadline: Option<Instant>,
+        on_chunk: impl FnMut(StreamKind, &str),
+    ) -> Result<Output, Error> {
+        if let Some(lazy) = self.lazy.take() {
+            return lazy.take_handle(self)?.collect_until(deadline, on_chunk);
+        }
+        let (stdout, stderr, timed_out) = self.gather(deadline, on_chunk);
+        self.into_output(stdout, stderr, timed_out)
+    }
+
+    /// Closes stdin and reads stdout and stderr until they close, or until
+    /// `deadline`, which kills the child; returns whether it did.
+    fn gather(
+        &mut self,
+        deadline: Option<Instant>,
+        mut on_chunk: impl FnMut(StreamKind, &str),
+    ) -> (String, String, bool) {
+        drop(self.stdin.take());
+        let (tx, rx) = channel::<(StreamKind, NumberedLine)>();
+        if let Some(stdout) = self.stdout.take() {
+            let tx = tx.clone();
+            thread::spawn(move || {
+                while let Ok(chunk) = stdout.recv_numbered() {
+                    if tx.send((StreamKind::Stdout, chunk)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        if let Some(stderr) = self.stderr.take() {
+            let tx = tx.clone();
+            thread::spawn(move || {
+                while let Ok(chunk) = stderr.recv_numbered() {
+                    if tx.send((StreamKind::Stderr, chunk)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(tx);
+        let (mut stdout, mut stderr) = (String::new(), String::new());
+        let mut timed_out = false;
+        loop {
+            let received = match deadline {
+                None => rx.recv().ok(),
+                Some(deadline) => {
+                    let recv = |timeout| rx.recv_timeout(timeout);
+                    match clock::recv_until(&*self.clock, deadline, self.poll_interval, recv) {
+                        Err(RecvTimeoutError::Timeout) if !timed_out => {
+                            // whatever was read before the kill is still returned
+                            timed_out = true;
+                            let _ = self.with_child(|child| child.kill());
+                            rx.try_recv().ok()
+                        }
+                        Err(RecvTimeoutError::Timeout) => rx.try_recv().ok(),
+                        received => received.ok(),
+                    }
+                }
+            };
+            let Some((kind, chunk)) = received else {
+                break;
+            };
+            on_chunk(kind, &chunk.data);
+            let (text, unterminated) = match kind {
+                StreamKind::Stdout => (&mut stdout, &self.stdout_unterminated),
+                StreamKind::Stderr => (&mut stderr, &self.stderr_unterminated),
+            };
+            text.push_str(&chunk.data);
+            if self.line_mode && chunk.stream_line_no != unterminated.load(Ordering::SeqCst) {
+                text.push('\n');
+            }
+        }
+        (stdout, stderr, timed_out)
+    }
+
+    /// Waits for the child and puts the output together. After a deadline kill
+    /// the status is taken unchecked, so that the output is not lost to
+    /// [`Command::fail_on_nonzero`] or [`Command::fail_on_stderr`].
+    fn into_output(
+        mut self,
+        mut stdout: String,
+        mut stderr: String,
+        timed_out: bool,
+    ) -> Result<Output, Error> {
+        let status = match timed_out {
+            true => self.wait_unchecked()?,
+            false => self.wait()?,
+        };
+        let invocation = self.recording.take().map(|recording| Invocation {
+            stdin: recording.stdin.lock().unwrap().clone(),
+            stdout: stdout.clone(),
+            stderr: stderr.clone(),
+            exit_code: status.code(),
+            ..recording.invocation
+        });
+        if self.trim_output {
+            for text in [&mut stdout, &mut stderr] {
+                text.truncate(text.trim_end().len());
+            }
+        }
+        let result = if timed_out {
+            RunResult::TimedOut
+        } else if self.was_cancelled() {
+            RunResult::Cancelled
+        } else if self.read_limit_reached.load(Ordering::SeqCst) {
+            RunResult::OutputLimited
+        } else {
+            RunResult::Completed(status)
+        };
+        Ok(Output {
+            status,
+            stdout,
+            stderr,
+            invocation,
+            result,
+        })
+    }
+}
+
+/// The context attached with [`Command::with_context`] to the command whose
+/// callback is running, if it is a `C`. Set on every thread serving such a
+/// command and around [`Command::on_spawn`], `None` elsewhere.
+pub fn callback_context<C: Any + Send + Sync>() -> Option<Arc<C>> {
+    let context = CALLBACK_CONTEXT.with(|current| current.borrow().clone())?;
+    context.downcast().ok()
+}
+
+/// Waits for all `handles` at once, so this takes as long as the slowest child
+/// rather than the sum, and returns their results in the same order, each checked
+/// like [`ProcessHandle::wait`] does. Lazy handles are started. Polls at the
+/// shortest [`Command::poll_interval`] among them.
+pub fn join_all(handles: Vec<ProcessHandle>) -> Vec<Result<ExitStatus, Error>> {
+    let poll_interval = handles
+        .iter()
+        .map(|handle| handle.poll_interval)
+        .min()
+        .unwrap_or(POLL_INTERVAL);
+    let mut results: Vec<Option<Result<ExitStatus, Error>>> =
+        handles.iter().map(|_| None).collect();
+    loop {
+        for (handle, result) in handles.iter().zip(&mut results) {
+            if result.is_none() {
+                *result = handle.poll_status().transpose();
+            }
+        }
+        if results.iter().all(Option::is_some) {
+            return results.into_iter().flatten().collect();
+        }
+        thread::sleep(poll_interval);
+    }
+}
+
+/// Runs all `commands` at once, stdin on the null device, and returns the index
+/// and output of the first to exit successfully, e.g. to take whichever of two
+/// mirrors answers first. The others are then killed with [`Signal::Kill`],
+/// whatever their [`Command::cancel_signal`], and their threads joined before
+/// this returns. If none succeeds, or `canceller` fires and all are killed, the
+/// first to finish is returned. A command that fails to spawn just drops out of
+/// the race. An empty `commands` returns index 0 with [`Error::NoCommands`].
+pub fn race(commands: Vec<Command>, canceller: Canceller) -> (usize, Result<Output, Error>) {
+    if commands.is_empty() {
+        return (0, Err(Error::NoCommands));
+    }
+    let poll_interval = commands
+        .iter()
+        .map(|cmd| cmd.poll_interval)
+        .min()
+        .unwrap_or(POLL_INTERVAL);
+    let (tx, rx) = channel();
+    let (mut idle, mut cancels) = (Vec::new(), Vec::new());
+    for (index, mut cmd) in commands.into_iter().enumerate() {
+        let (broadcaster, idle_cancel) = idle_canceller();
+        idle.push(broadcaster);
+        let handle = match cmd.stdin_null().start(idle_cancel, false) {
+            Ok(handle) => handle,
+            Err(e) => {
+                let _ = tx.send((index, Err(e)));
+                continue;
+            }
+        };
+        cancels.extend(handle.cancel.clone());
+        let tx = tx.clone();
+        thread::spawn(move || {
+            let _ = tx.send((index, handle.finish_and_collect()));
+        });
+    }
+    drop(tx);
+    let kill_all = |cancels: &[CancelWiring]| {
+        for cancel in cancels {
+            cancel.send(Signal::Kill);
+        }
+    };
+    let (watched, done) = (cancels.clone(), Arc::new(AtomicBool::new(false)));
+    let watching = Arc::clone(&done);
+    thread::spawn(move || {
+        // checks `done` so that it lets go of the children once `race` returned
+        while !watching.load(Ordering::SeqCst) {
+            match canceller.0.recv_timeout(poll_interval) {
+                Ok(()) => {
+                    kill_all(&watched);
+                    break;
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+                Err(RecvTimeoutError::Timeout) => {}
+            }
+        }
+        trace!("exiting the race canceller thread");
+    });
+    let mut first = None;
+    let mut winner = None;
+    // every collecting thread reports, so the losers are joined by the end
+    for (index, result) in rx {
+        let won = matches!(&result, Ok(output) if output.status.success());
+        if won && winner.is_none() {
+            trace!("command {index} won the race");
+            kill_all(&cancels);
+            winner = Some((index, result));
+        } else if first.is_none() {
+            first = Some((index, result));
+        }
+    }
+    done.store(true, Ordering::SeqCst);
+    // every command reports, and there is at least one
+    winner.or(first).unwrap_or((0, Err(Error::NoCommands)))
+}
+
+impl From<Sender<String>> for StdinSender {
+    fn from(tx: Sender<String>) -> Self {
+        Self::new(tx, Arc::new(SystemClock), POLL_INTERVAL)
+    }
+}
+
+impl StdinSender {
+    fn new(tx: Sender<String>, clock: Arc<dyn Clock>, poll_interval: Duration) -> Self {
+        let idle = IdleClose {
+            last_send: clock.now(),
+            after: None,
+            closed: false,
+        };
+        Self {
+            tx,
+            bytes_written: Arc::default(),
+            write_failure: Arc::default(),
+            pipe: None,
+            idle: Arc::new(Mutex::new(idle)),
+            created: clock.now(),
+            clock,
+            poll_interval,
+            awaiting_response: Arc::default(),
+            lazy: None,
+        }
+    }
+
+    /// Queues `input` for the child's stdin. Fails with [`Error::StdinClosed`],
+    /// handing the input back, once the stdin thread is gone because the child
+    /// closed its stdin (a broken pipe) or exited, or once stdin was closed for
+    /// being idle. That usually just means the child is done reading. If the
+    /// thread is gone because a write failed otherwise, that error is returned
+    /// instead as [`Error::IoError`]. On Unix a send fails right away once the
+    /// child has closed its stdin, rather than queueing input that is bound to
+    /// be lost; only input queued before is.
+    pub fn send(&self, input: String) -> Result<(), Error> {
+        if let Some(lazy) = &self.lazy {
+            return lazy.send(input);
+        }
+        let mut idle = self.idle.lock().unwrap();
+        if idle.closed || self.pipe.as_ref().is_some_and(pipe_broken) {
+            return Err(Error::StdinClosed(input));
+        }
+        idle.last_send = self.clock.now();
+        self.tx
+            .send(input)
+            .map_err(|SendError(input)| stdin_gone(&self.write_failure, input))
+    }
+
+    /// Sends `input` only if no response is outstanding, for lock-step protocols
+    /// that must not pipeline requests, and returns whether it sent. The state
+    /// machine is one of two states: idle, where this sends and moves to
+    /// awaiting, and awaiting, where this returns `false` until the next stdout
+    /// message moves back to idle. Any message counts as the response, so with
+    /// [`Command::line_mode`] one line answers one request, and the state changes
+    /// as the message is delivered, before it is received. Plain sends do not
+    /// take part. Never leaves awaiting for stdout read undecoded, as by
+    /// [`Command::stdout_pairs_into`], or for a mocked handle. A failed send
+    /// stays idle.
+    pub fn send_if_idle(&self, input: String) -> Result<bool, Error> {
+        if let Some(lazy) = &self.lazy {
+            return lazy.send_if_idle(input);
+        }
+        let awaiting = &self.awaiting_response;
+        if awaiting.swap(true, Ordering::SeqCst) {
+            return Ok(false);
+        }
+        self.send(input).map(|()| true).inspect_err(|_| {
+            awaiting.store(false, Ordering::SeqCst);
+        })
+    }
+
+    /// Whether a [`StdinSender::send_if_idle`] is still waiting for its response.
+    pub fn is_awaiting_response(&self) -> bool {
+        if let Some(lazy) = &self.lazy {
+            return lazy.is_awaiting_response();
+        }
+        self.awaiting_response.load(Ordering::SeqCst)
+    }
+
+    /// Serves stdin through `std::io::Write`, for `write!`, `BufWriter` or
+    /// serializers writing to a writer. Each complete line is sent as soon as it
+    /// is written, the rest on `flush` or when the writer is dropped; wrap it in
+    /// a `BufWriter` for fewer, larger messages. A character cut in two by a
+    /// write is held back until it is complete, bytes that are not UTF-8 fail
+    /// with `InvalidData`. A failed send fails the write, with `BrokenPipe` for
+    /// [`Error::StdinClosed`], and keeps none of its bytes, so that `write_all`
+    /// or a `BufWriter` retrying it does not send them twice.
+    pub fn as_writer(&self) -> impl Write + '_ {
+        ChannelWriter {
+            tx: self,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Sends each input and then waits its delay before sending the next, to pace
+    /// a prompt-driven program. Fails like [`StdinSender::send`] as soon as the
+    /// child no longer takes input, e.g. because it was killed: a delay is cut
+    /// short once the stdin thread is gone. Delays are measured on the command's
+    /// [`Clock`], checked at [`Command::poll_interval`], and block the caller.
+    pub fn send_sequence(&self, items: Vec<(String, Duration)>) -> Result<(), Error> {
+        for (input, delay) in items {
+            if self.stdin_thread_gone() {
+                return Err(stdin_gone(&self.write_failure, input));
+            }
+            self.send(input)?;
+            let until = self.clock.now() + delay;
+            while let Some(left) = until.checked_duration_since(self.clock.now()) {
+                if left.is_zero() || self.stdin_thread_gone() {
+                    break;
+                }
+                thread::sleep(left.min(self.poll_interval));
+            }
+        }
+        Ok(())
+    }
+
+    /// The stdin thread closes the pipe when it ends; a mocked handle has none.
+    fn stdin_thread_gone(&self) -> bool {
+        self.pipe
+            .as_ref()
+            .is_some_and(|pipe| pipe.lock().unwrap().is_none())
+    }
+
+    /// Closes the child's stdin, so it sees EOF, once `send` has not been called
+    /// for `after`, counted from the last send or from this call, whichever is
+    /// later. Sends fail with [`Error::StdinClosed`] from then on. Calling this
+    /// again replaces the window. Checked at [`Command::poll_interval`]. On a
+    /// lazy handle that was not started yet, the window counts from the spawn.
+    pub fn auto_close_after_idle(&self, after: Duration) {
+        if let Some(lazy) = &self.lazy {
+            lazy.auto_close_after_idle(after);
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        idle.last_send = self.clock.now();
+        if idle.after.replace(after).is_some() {
+            return;
+        }
+        let (state, pipe) = (Arc::clone(&self.idle), self.pipe.clone());
+        let (clock, poll_interval) = (Arc::clone(&self.clock), self.poll_interval);
+        thread::spawn(move || loop {
+            thread::sleep(poll_interval);
+            if let Some(pipe) = &pipe {
+                if pipe.lock().unwrap().is_none() {
+                    break;
+                }
+            }
+            {
+                let mut idle = state.lock().unwrap();
+                let after = idle.after.unwrap_or_default();
+                if clock.now().saturating_duration_since(idle.last_send) < after {
+                    continue;
+                }
+                trace!("closing stdin after {after:?} without input");
+                idle.closed = true;
+            }
+            // not under the idle lock, which a send may be waiting for
+            if let Some(pipe) = &pipe {
+                drop(pipe.lock().unwrap().take());
+            }
+            break;
+        });
+    }
+
+    /// Number of bytes written to the child's stdin so far, after `map_stdin`.
+    pub fn bytes_written(&self) -> usize {
+        match &self.lazy {
+            Some(lazy) => lazy.bytes_written(),
+            None => self.bytes_written.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The average rate of [`StdinSender::bytes_written`] since spawning, in bytes
+    /// per second, on the command's clock.
+    pub fn write_rate(&self) -> f64 {
+        let elapsed = self.clock.now().saturating_duration_since(self.created);
+        match elapsed.as_secs_f64() {
+            secs if secs > 0.0 => self.bytes_written() as f64 / secs,
+            _ => 0.0,
+        }
+    }
+}
+
+/// Counts a message off the backlog and onto the received ones. Receivers built
+/// from plain channels see messages that were never counted, hence the
+/// saturation.
+fn received(backlog: &AtomicUsize, count: &Cell<usize>) {
+    let _ = backlog.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+    count.set(count.get() + 1);
+}
+
+/// Spawns the child of a lazy handle, see [`Command::lazy`].
+fn start_lazy(lazy: &Option<Arc<LazyStart>>) {
+    if let Some(lazy) = lazy {
+        lazy.start();
+    }
+}
+
+impl StdoutReceiver {
+    pub fn recv(&self) -> Result<String, RecvError> {
+        start_lazy(&self.4);
+        let msg = self.0.recv()?;
+        received(&self.1, &self.3);
+        Ok(msg)
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<String, RecvTimeoutError> {
+        start_lazy(&self.4);
+        let msg = self.0.recv_timeout(timeout)?;
+        received(&self.1, &self.3);
+        Ok(msg)
+    }
+
+    /// Receives the next message with its number within stdout, counted from
+    /// the start of the stream, so messages taken by `recv` before count too.
+    /// With [`Command::line_mode`] this is the stdout line number.
+    pub fn recv_numbered(&self) -> Result<NumberedLine, RecvError> {
+        let data = self.recv()?;
+        Ok(NumberedLine {
+            stream_line_no: self.3.get(),
+            data,
+        })
+    }
+
+    /// Yields every further message as by [`StdoutReceiver::recv_numbered`].
+    pub fn numbered(self) -> impl Iterator<Item = NumberedLine> {
+        iter::from_fn(move || self.recv_numbered().ok())
+    }
+
+    /// Yields each message with its 1-based number. With [`Command::line_mode`]
+    /// this is the stdout line number, also for messages received before.
+    pub fn numbered_lines(self) -> impl Iterator<Item = (usize, String)> {
+        self.numbered().map(|line| (line.stream_line_no, line.data))
+    }
+
+    /// Blocks for each stdout line until the stream ends. Without
+    /// [`Command::line_mode`] the chunks are split into lines here, a final line
+    /// lacking its newline included. EOF ends the iteration; if reading the pipe
+    /// failed instead, the error is yielded once as the last item.
+    pub fn lines(self) -> impl Iterator<Item = Result<String, Error>> {
+        let mut framer = (!self.5).then(LineBuffer::default);
+        let (mut ready, mut failure, mut done) = (VecDeque::new(), None, false);
+        iter::from_fn(move || loop {
+            if let Some(line) = ready.pop_front() {
+                return Some(Ok(line));
+            }
+            if done {
+                return failure.take().map(|e: io::Error| Err(e.into()));
+            }
+            match self.recv() {
+                Ok(chunk) => match &mut framer {
+                    Some(framer) => ready.extend(framer.push(&chunk)),
+                    None => return Some(Ok(chunk)),
+                },
+                Err(RecvError) => {
+                    done = true;
+                    ready.extend(framer.take().and_then(LineBuffer::finish));
+                    failure = self.2.lock().unwrap().take();
+                }
+            }
+        })
+    }
+
+    /// Serves stdout through `std::io::Read`, for code that consumes a reader.
+    /// The bytes are those of the messages as they arrive, so without
+    /// [`Command::line_mode`] this is exactly what the child wrote. A failed read
+    /// of the pipe is reported once after the last byte.
+    pub fn into_reader(self) -> impl Read {
+        ChannelReader {
+            rx: self,
+            chunk: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+/// See [`StdinSender::as_writer`].
+struct ChannelWriter<'a> {
+    tx: &'a StdinSender,
+    pending: Vec<u8>,
+}
+
+impl ChannelWriter<'_> {
+    /// Sends the first `len` pending bytes, short of a trailing partial character.
+    /// They stay pending if that fails.
+    fn send_pending(&mut self, len: usize) -> io::Result<()> {
+        let len = match std::str::from_utf8(&self.pending[..len]) {
+            Ok(_) => len,
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        };
+        if len == 0 {
+            return Ok(());
+        }
+        let text = String::from_utf8(self.pending[..len].to_vec()).unwrap();
+        self.tx.send(text).map_err(|e| match e {
+            Error::IoError(e) => e,
+            e => io::Error::new(io::ErrorKind::BrokenPipe, e.to_string()),
+        })?;
+        self.pending.drain(..len);
+        Ok(())
+    }
+}
+
+impl Write for ChannelWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let held = self.pending.len();
+        self.pending.extend_from_slice(buf);
+        if let Some(end) = self.pending.iter().rposition(|&b| b == b'\n') {
+            // a failed write takes none of `buf`, lest a retry send it twice
+            if let Err(e) = self.send_pending(end + 1) {
+                self.pending.truncate(held);
+                return Err(e);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_pending(self.pending.len())
+    }
+}
+
+impl Drop for ChannelWriter<'_> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// See [`StdoutReceiver::into_reader`].
+struct ChannelReader {
+    rx: StdoutReceiver,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.rx.recv() {
+                Ok(chunk) => (self.chunk, self.pos) = (chunk.into_bytes(), 0),
+                Err(RecvError) => {
+                    return match self.rx.2.lock().unwrap().take() {
+                        Some(e) => Err(e),
+                        None => Ok(0),
+                    }
+                }
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl StdoutReceiver {
+    /// Parses every stdout line as JSON, for tools emitting newline-delimited JSON.
+    /// Expects [`Command::line_mode`], which also delivers a final line lacking its
+    /// newline. Blank lines are skipped; a malformed line yields an `Err` and the
+    /// iteration carries on with the next line.
+    pub fn json_lines<T: serde::de::DeserializeOwned>(
+        &self,
+    ) -> impl Iterator<Item = Result<T, Error>> + '_ {
+        iter::from_fn(|| self.recv().ok())
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| Ok(serde_json::from_str(&line)?))
+    }
+}
+
+impl ErrorReceiver {
+    pub fn recv(&self) -> Result<Error, RecvError> {
+        self.0.recv()
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Error, RecvTimeoutError> {
+        self.0.recv_timeout(timeout)
+    }
+
+    pub fn try_recv(&self) -> Result<Error, TryRecvError> {
+        self.0.try_recv()
+    }
+}
+
+impl StderrReceiver {
+    pub fn recv(&self) -> Result<String, RecvError> {
+        start_lazy(&self.3);
+        let msg = self.0.recv()?;
+        received(&self.1, &self.2);
+        Ok(msg)
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<String, RecvTimeoutError> {
+        start_lazy(&self.3);
+        let msg = self.0.recv_timeout(timeout)?;
+        received(&self.1, &self.2);
+        Ok(msg)
+    }
+
+    /// Receives the next message with its number within stderr, see
+    /// [`StdoutReceiver::recv_numbered`].
+    pub fn recv_numbered(&self) -> Result<NumberedLine, RecvError> {
+        let data = self.recv()?;
+        Ok(NumberedLine {
+            stream_line_no: self.2.get(),
+            data,
+        })
+    }
+
+    /// Yields every further message as by [`StderrReceiver::recv_numbered`].
+    pub fn numbered(self) -> impl Iterator<Item = NumberedLine> {
+        iter::from_fn(move || self.recv_numbered().ok())
+    }
+
+    /// Yields each message with its 1-based number. With [`Command::line_mode`]
+    /// this is the stderr line number, also for messages received before.
+    pub fn numbered_lines(self) -> impl Iterator<Item = (usize, String)> {
+        self.numbered().map(|line| (line.stream_line_no, line.data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result as AnyResult;
+    // use pretty_assertions::assert_eq;
+    use std::{
+        thread,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn kill_test() -> AnyResult<()> {
+        let _ = tracing_subscriber::fmt::try_init();
+        let (broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("managed-command-test-process");
+        std_cmd.env("PATH", "testing");
+        let mut cmd = Command::from(std_cmd);
+        let _handle = cmd.run(subscriber.into())?;
+        trace!("will wait for 1 sec");
+        thread::sleep(Duration::from_secs(1));
+        trace!("will kill the process now. and sleep for 1 more sec");
+        broadcaster.broadcast(())?;
+        thread::sleep(Duration::from_secs(1));
+        Ok(())
+    }
+
+    #[test]
+    fn check_input_output() -> AnyResult<()> {
+        let _ = tracing_subscriber::fmt::try_init();
+        let (broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("managed-command-test-process");
+        std_cmd.env("PATH", "testing");
+        let mut cmd = Command::from(std_cmd);
+        let mut process = cmd.run(subscriber.into())?;
+        let stdin = process.stdin.take().unwrap();
+        let stdout = process.stdout.take().unwrap();
+        let handle = thread::spawn(move || {
+            while let Ok(out) = stdout.recv() {
+                trace!("received: '{}'", out.trim());
+            }
+        });
+        stdin.send("second input\n".to_owned())?;
+        stdin.send("first input\n".to_owned())?;
+        thread::sleep(Duration::from_secs(2));
+        broadcaster.broadcast(())?;
+        // refused once the kill has closed the pipe
+        assert!(matches!(
+            stdin.send("second input\n".to_owned()),
+            Ok(()) | Err(Error::StdinClosed(_))
+        ));
+        let _ = handle.join();
+        Ok(())
+    }
+
+    #[test]
+    fn check_only_output() -> AnyResult<()> {
+        let _ = tracing_subscriber::fmt::try_init();
+        let (broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("managed-command-test-process");
+        std_cmd.env("PATH", "testing");
+        let mut cmd = Command::from(std_cmd);
+        let mut process = cmd.stdin_null().run(subscriber.into())?;
+        assert!(process.stdin.is_none());
+        let stdout = process.stdout.take().unwrap();
+        let thread_handle = thread::spawn(move || loop {
+            let out = stdout.recv().unwrap();
+            trace!("received: '{}'", out.trim());
+            //assert_eq!(out.trim(), "this is the default output!");
+        });
+        thread::sleep(Duration::from_secs(2));
+        broadcaster.broadcast(())?;
+        let _ = thread_handle.join();
+        Ok(())
+    }
+
+    #[test]
+    fn check_map_stdin() -> AnyResult<()> {
+        let _ = tracing_subscriber::fmt::try_init();
+        let (broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("managed-command-test-process");
+        std_cmd.env("PATH", "testing");
+        let mut cmd = Command::from(std_cmd);
+        cmd.map_stdin(|input| format!("{input}\n"));
+        let mut process = cmd.run(subscriber.into())?;
+        let stdin = process.stdin.take().unwrap();
+        let stdout = process.stdout.take().unwrap();
+        stdin.send("first input".to_owned())?;
+        let mut responded = false;
+        while let Ok(out) = stdout.recv() {
+            if out.contains("responding to 'first input'") {
+                responded = true;
+                break;
+            }
+        }
+        broadcaster.broadcast(())?;
+        assert!(responded);
+        assert_eq!(stdin.bytes_written(), "first input\n".len());
+        Ok(())
+    }
+
+    struct MockRunner;
+
+    impl CommandRunner for MockRunner {
+        fn run(&mut self, _canceller: Canceller) -> Result<ProcessHandle, Error> {
+            let (tx_out, rx_out) = channel();
+            tx_out.send("canned output".to_owned())?;
+            Ok(ProcessHandle::from_channels(None, Some(rx_out), None))
+        }
+    }
+
+    fn first_output(runner: &mut impl CommandRunner, canceller: Canceller) -> Option<String> {
+        let mut process = runner.run(canceller).ok()?;
+        process.stdout.take()?.recv().ok()
+    }
+
+    #[test]
+    fn check_mock_runner() {
+        let (_broadcaster, subscriber) = broadcasting_channel("test the mock runner");
+        let out = first_output(&mut MockRunner, subscriber.into());
+        assert_eq!(out.as_deref(), Some("canned output"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_finish_and_collect() -> AnyResult<()> {
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "cat; echo done >&2"]);
+        let mut cmd = Command::from(std_cmd);
+        let process = cmd.run(subscriber.into())?;
+        process
+            .stdin
+            .as_ref()
+            .unwrap()
+            .send("filtered\n".to_owned())?;
+        let output = process.finish_and_collect()?;
+        assert!(output.status.success());
+        assert_eq!(output.stdout, "filtered\n");
+        assert_eq!(output.stderr, "done\n");
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_run_collecting() -> AnyResult<()> {
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "echo out; echo err >&2"]);
+        let mut cmd = Command::from(std_cmd);
+        let mut chunks = Vec::new();
+        let output = cmd.run_collecting(subscriber.into(), |kind, chunk| {
+            chunks.push((kind, chunk.to_owned()));
+        })?;
+        assert!(output.status.success());
+        assert_eq!(output.stdout, "out\n");
+        assert_eq!(output.stderr, "err\n");
+        assert!(chunks.contains(&(StreamKind::Stdout, "out\n".to_owned())));
+        assert!(chunks.contains(&(StreamKind::Stderr, "err\n".to_owned())));
+        Ok(())
+    }
+
+    #[test]
+    fn kill_on_canceller_drop_test() -> AnyResult<()> {
+        let _ = tracing_subscriber::fmt::try_init();
+        let (broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("managed-command-test-process");
+        std_cmd.env("PATH", "testing");
+        let mut cmd = Command::from(std_cmd);
+        cmd.kill_on_canceller_drop(true);
+        let process = cmd.run(subscriber.into())?;
+        drop(broadcaster);
+        let status = process.wait()?;
+        assert!(!status.success());
+        Ok(())
+    }
+
+    #[test]
+    fn check_stdin_closed() {
+        let (tx_in, rx_in) = channel();
+        let process = ProcessHandle::from_channels(Some(tx_in), None, None);
+        drop(rx_in);
+        let res = process.stdin.unwrap().send("lost input".to_owned());
+        assert!(matches!(res, Err(Error::StdinClosed(input)) if input == "lost input"));
+    }
+
+    #[test]
+    fn check_line_buffer() {
+        let mut lines = LineBuffer::default();
+        assert_eq!(lines.push("first li"), Vec::<String>::new());
+        assert_eq!(
+            lines.push("ne\r\nsecond line\nthi"),
+            ["first line", "second line"]
+        );
+        assert_eq!(lines.push("rd"), Vec::<String>::new());
+        assert_eq!(lines.finish().as_deref(), Some("third"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_numbered_lines() -> AnyResult<()> {
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "printf 'one\\ntwo\\nthree'"]);
+        let mut cmd = Command::from(std_cmd);
+        cmd.line_mode(true);
+        let mut process = cmd.run(subscriber.into())?;
+        let lines: Vec<_> = process.stdout.take().unwrap().numbered_lines().collect();
+        assert_eq!(
+            lines,
+            [
+                (1, "one".to_owned()),
+                (2, "two".to_owned()),
+                (3, "three".to_owned())
+            ]
+        );
+
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "echo one; echo two >&2; echo three >&2"]);
+        let mut process = Command::from(std_cmd)
+            .line_mode(true)
+            .run(subscriber.into())?;
+        let stderr = process.stderr.take().unwrap();
+        assert_eq!(stderr.recv()?, "two");
+        let lines: Vec<_> = stderr.numbered_lines().collect();
+        assert_eq!(lines, [(2, "three".to_owned())]);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_stdin_null() -> AnyResult<()> {
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut cmd = Command::from(std::process::Command::new("cat"));
+        cmd.stdin_null();
+        let process = cmd.run(subscriber.into())?;
+        assert!(process.stdin.is_none());
+        let output = process.finish_and_collect()?;
+        assert!(output.status.success());
+        assert_eq!(output.stdout, "");
+        Ok(())
+    }
+
+    #[test]
+    fn check_lifecycle_hooks() -> AnyResult<()> {
+        let _ = tracing_subscriber::fmt::try_init();
+        let (broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("managed-command-test-process");
+        std_cmd.env("PATH", "testing");
+        let mut cmd = Command::from(std_cmd);
+        let (tx_spawn, rx_spawn) = channel();
+        let (tx_exit, rx_exit) = channel();
+        cmd.on_spawn(move |pid| {
+            let _ = tx_spawn.send(pid);
+        });
+        cmd.on_exit(move |status, elapsed| {
+            let _ = tx_exit.send((status, elapsed));
+        });
+        let process = cmd.run(subscriber.into())?;
+        assert_eq!(Some(rx_spawn.recv()?), process.id());
+        thread::sleep(Duration::from_millis(500));
+        broadcaster.broadcast(())?;
+        let (status, elapsed) = rx_exit.recv()?;
+        assert!(!status.success());
+        assert!(elapsed >= Duration::from_millis(500));
+        Ok(())
+    }
+
+    #[test]
+    fn check_read_limit() -> AnyResult<()> {
+        let _ = tracing_subscriber::fmt::try_init();
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("managed-command-test-process");
+        std_cmd.env("PATH", "testing");
+        let mut cmd = Command::from(std_cmd);
+        cmd.line_mode(true).read_limit(ReadLimit::Lines(2));
+        let mut process = cmd.run(subscriber.into())?;
+        let lines: Vec<_> = process.stdout.take().unwrap().numbered_lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(!process.wait()?.success());
+        Ok(())
+    }
+
+    #[test]
+    fn check_custom_output_sender() -> AnyResult<()> {
+        let _ = tracing_subscriber::fmt::try_init();
+        let (broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("managed-command-test-process");
+        std_cmd.env("PATH", "testing");
+        let mut cmd = Command::from(std_cmd);
+        let (tx_out, rx_out) = mpsc::sync_channel(1);
+        cmd.line_mode(true).stdout_into(tx_out);
+        let process = cmd.run(subscriber.into())?;
+        assert!(process.stdout.is_none());
+        assert_eq!(rx_out.recv()?, "this is the default output!");
+        broadcaster.broadcast(())?;
+        Ok(())
+    }
+
+    #[test]
+    fn check_capture_after() {
+        let mut capture = CaptureAfter::new("--- BEGIN ---".to_owned());
+        assert_eq!(capture.push("banner\n--- BE"), "");
+        assert_eq!(capture.push("GIN ---"), "");
+        assert_eq!(capture.push(" here\nwanted\n"), "wanted\n");
+        assert_eq!(capture.push("more"), "more");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_signal() -> AnyResult<()> {
+        use std::os::unix::process::ExitStatusExt;
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sleep");
+        std_cmd.arg("10");
+        let mut cmd = Command::from(std_cmd);
+        let process = cmd.run(subscriber.into())?;
+        process.signal(Signal::Term)?;
+        assert_eq!(process.wait()?.signal(), Some(libc::SIGTERM));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_stdin_broken_pipe() -> AnyResult<()> {
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "exec 0<&-; sleep 1"]);
+        let mut cmd = Command::from(std_cmd);
+        let mut process = cmd.run(subscriber.into())?;
+        let stdin = process.stdin.take().unwrap();
+        let mut res = Ok(());
+        for _ in 0..100 {
+            res = stdin.send("ignored\n".to_owned());
+            if res.is_err() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(matches!(res, Err(Error::StdinClosed(_))));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_record_invocation() -> AnyResult<()> {
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("cat");
+        std_cmd.env("RECORDED", "yes");
+        let mut cmd = Command::from(std_cmd);
+        cmd.record_invocation(true);
+        let process = cmd.run(subscriber.into())?;
+        process.stdin.as_ref().unwrap().send("fed\n".to_owned())?;
+        let invocation = process.finish_and_collect()?.invocation.unwrap();
+        assert_eq!(invocation.program, "cat");
+        assert_eq!(
+            invocation.env,
+            [("RECORDED".to_owned(), Some("yes".to_owned()))]
+        );
+        assert_eq!(invocation.stdin, "fed\n");
+        assert_eq!(invocation.stdout, "fed\n");
+        assert_eq!(invocation.exit_code, Some(0));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_backlog() -> AnyResult<()> {
+        let _ = tracing_subscriber::fmt::try_init();
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "echo one; echo two; echo three"]);
+        let mut cmd = Command::from(std_cmd);
+        cmd.line_mode(true);
+        let process = cmd.run(subscriber.into())?;
+        assert!(process.wait()?.success());
+        assert!(process.wait_for_output_drain(Duration::from_secs(5)));
+        assert_eq!(process.stdout_backlog(), 3);
+        process.stdout.as_ref().unwrap().recv()?;
+        assert_eq!(process.stdout_backlog(), 2);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_drain_after_cancel() -> AnyResult<()> {
+        let _ = tracing_subscriber::fmt::try_init();
+        let marker = env::temp_dir().join(format!("managed-command-drain-{}", std::process::id()));
+        let _ = std::fs::remove_file(&marker);
+        let (broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd
+            .args([
+                "-c",
+                "echo one; echo two; echo last; touch \"$0\"; exec sleep 10",
+            ])
+            .arg(&marker);
+        let mut cmd = Command::from(std_cmd);
+        cmd.line_mode(true).drain_on_cancel(true);
+        let mut process = cmd.run(subscriber.into())?;
+        // nothing is received before the cancel, so all of it has to be drained
+        let started = Instant::now();
+        while !marker.exists() {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+        broadcaster.broadcast(())?;
+        assert!(!process.wait()?.success());
+        let lines: Vec<_> = process.stdout.take().unwrap().numbered_lines().collect();
+        assert_eq!(
+            lines,
+            [(1, "one".into()), (2, "two".into()), (3, "last".into())]
+        );
+        std::fs::remove_file(&marker)?;
+        Ok(())
+    }
+
+    #[test]
+    fn check_set_canceller() -> AnyResult<()> {
+        let _ = tracing_subscriber::fmt::try_init();
+        let (first, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("managed-command-test-process");
+        std_cmd.env("PATH", "testing");
+        let mut cmd = Command::from(std_cmd);
+        let process = cmd.run(subscriber.into())?;
+        let child = Arc::clone(process.child.as_ref().unwrap());
+        let holders = Arc::strong_count(&child);
+        let (second, subscriber) = broadcasting_channel("replacing canceller");
+        process.set_canceller(subscriber)?;
+        // the replaced thread retires unprompted, releasing its share of the child
+        let started = Instant::now();
+        while Arc::strong_count(&child) > holders {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+        first.broadcast(())?;
+        thread::sleep(Duration::from_millis(200));
+        assert!(process.with_child(|child| child.try_wait())??.is_none());
+        assert!(!process.was_cancelled());
+        second.broadcast(())?;
+        assert!(!process.wait()?.success());
+        assert!(process.was_cancelled());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_stdout_string() -> AnyResult<()> {
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "echo result; echo"]);
+        assert_eq!(Command::from(std_cmd).stdout_string()?, "result");
+        // collecting keeps a final line without its newline as it is
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "printf 'result\\nlast'; printf err >&2"]);
+        let output = Command::from(std_cmd)
+            .line_mode(true)
+            .run_collecting(subscriber.into(), |_, _| {})?;
+        assert_eq!(output.stdout, "result\nlast");
+        assert_eq!(output.stderr, "err");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "echo broken >&2; exit 3"]);
+        let err = Command::from(std_cmd).stdout_string().unwrap_err();
+        assert_eq!(err.to_string(), "'sh' exited with exit status: 3: broken");
+        assert!(matches!(err, Error::NonZeroExit { status, stderr, .. }
+            if status.code() == Some(3) && stderr == "broken\n"));
+        let err = Command::from(std::process::Command::new("false"))
+            .stdout_string()
+            .unwrap_err();
+        assert_eq!(err.to_string(), "'false' exited with exit status: 1");
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn check_reset_signal_handlers() -> AnyResult<()> {
+        let usr1_ignored = |cmd: &mut Command| -> AnyResult<bool> {
+            let out = cmd.stdout_string()?;
+            let mask = u64::from_str_radix(out.trim_start_matches("SigIgn:").trim(), 16)?;
+            Ok(mask & (1 << (libc::SIGUSR1 - 1)) != 0)
+        };
+        // SAFETY: only SIGUSR1 is touched, nothing in the test binary uses it
+        let previous = unsafe { libc::signal(libc::SIGUSR1, libc::SIG_IGN) };
+        let grep = || {
+            let mut std_cmd = std::process::Command::new("grep");
+            std_cmd.args(["SigIgn", "/proc/self/status"]);
+            Command::from(std_cmd)
+        };
+        let inherited = usr1_ignored(&mut grep());
+        let reset = usr1_ignored(grep().reset_signal_handlers(true));
+        // SAFETY: puts back what the call above replaced
+        unsafe { libc::signal(libc::SIGUSR1, previous) };
+        assert!(inherited?);
+        assert!(!reset?);
+        Ok(())
+    }
+
+    #[test]
+    fn check_lines() -> AnyResult<()> {
+        let _ = tracing_subscriber::fmt::try_init();
+        let (tx, rx) = channel();
+        let failure = ReadFailure::default();
+        let stdout = StdoutReceiver(
+            rx,
+            Arc::default(),
+            Arc::clone(&failure),
+            Cell::default(),
+            None,
+            true,
+        );
+        for line in ["first", "second"] {
+            tx.send(line.to_owned())?;
+        }
+        drop(tx);
+        let lines: Vec<_> = stdout.lines().collect::<Result<_, _>>()?;
+        assert_eq!(lines, ["first", "second"]);
+
+        let (tx, rx) = channel();
+        let stdout = StdoutReceiver(
+            rx,
+            Arc::default(),
+            Arc::clone(&failure),
+            Cell::default(),
+            None,
+            true,
+        );
+        tx.send("partial".to_owned())?;
+        drop(tx);
+        *failure.lock().unwrap() = Some(io::Error::other("pipe went away"));
+        let mut lines = stdout.lines();
+        assert_eq!(lines.next().transpose()?.as_deref(), Some("partial"));
+        assert!(matches!(lines.next(), Some(Err(Error::IoError(_)))));
+        assert!(lines.next().is_none());
+
+        // raw chunks are split into lines, the failure still coming last
+        let (tx, rx) = channel();
+        let stdout = StdoutReceiver(
+            rx,
+            Arc::default(),
+            Arc::clone(&failure),
+            Cell::default(),
+            None,
+            false,
+        );
+        for chunk in ["first li", "ne\r\nsecond\n", "", "third\nfou", "rth"] {
+            tx.send(chunk.to_owned())?;
+        }
+        drop(tx);
+        *failure.lock().unwrap() = Some(io::Error::other("pipe went away"));
+        let mut lines = stdout.lines();
+        for line in ["first line", "second", "third", "fourth"] {
+            assert_eq!(lines.next().transpose()?.as_deref(), Some(line));
+        }
+        assert!(matches!(lines.next(), Some(Err(Error::IoError(_)))));
+        assert!(lines.next().is_none());
+
+        let (broadcaster, subscriber) = broadcasting_channel("lines");
+        let mut std_cmd = StdCommand::new("managed-command-test-process");
+        std_cmd.env("PATH", "testing");
+        let mut process = Command::from(std_cmd)
+            .line_mode(true)
+            .run(subscriber.into())?;
+        let mut lines = process.stdout.take().unwrap().lines();
+        assert_eq!(
+            lines.next().transpose()?.as_deref(),
+            Some("this is the default output!")
+        );
+        broadcaster.broadcast(())?;
+        Ok(())
+    }
+
+    #[test]
+    fn check_strip_ansi() {
+        let mut strip = StripAnsi::default();
+        assert_eq!(strip.push("\x1b[1;31mred\x1b[0m plain"), "red plain");
+        // sequences cut by read boundaries
+        assert_eq!(strip.push("a\x1b"), "a");
+        assert_eq!(strip.push("[3"), "");
+        assert_eq!(strip.push("2mgreen\x1b]0;ti"), "green");
+        assert_eq!(strip.push("tle\x1b"), "");
+        assert_eq!(strip.push("\\b\x1b(Bc\x1b]2;x\x07d\x1b=e"), "bcde");
+        assert_eq!(strip.push("\x1b[2"), "");
+        assert_eq!(strip.finish(), "");
+        assert_eq!(strip.push("f"), "f");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_strip_ansi_output() -> AnyResult<()> {
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args([
+            "-c",
+            r"printf '\033[32mok\033[0m\n'; printf '\033[1mbad\033[m\n' >&2",
+        ]);
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let output = Command::from(std_cmd)
+            .strip_ansi(true)
+            .run(subscriber.into())?
+            .finish_and_collect()?;
+        assert_eq!((&*output.stdout, &*output.stderr), ("ok\n", "bad\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn check_normalize_newlines() {
+        let mut normalize = NormalizeNewlines::default();
+        assert_eq!(normalize.push("a\r\nb\rc\r"), "a\nb\nc");
+        assert_eq!(normalize.push("\nd\r"), "\nd");
+        assert_eq!(normalize.push("\re"), "\n\ne");
+        assert_eq!(normalize.push("\r"), "");
+        assert_eq!(normalize.finish(), "\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_join_all() -> AnyResult<()> {
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let subscribers = [subscriber.clone(), subscriber.clone(), subscriber];
+        let started = Instant::now();
+        let handles = subscribers
+            .into_iter()
+            .zip(["exit 0", "true", "exit 3"])
+            .map(|(subscriber, script)| {
+                let mut std_cmd = std::process::Command::new("sh");
+                std_cmd.args(["-c", &format!("sleep 1; {script}")]);
+                Command::from(std_cmd).run(subscriber.into())
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let codes = join_all(handles)
+            .into_iter()
+            .map(|status| Ok(status?.code()))
+            .collect::<Result<Vec<_>, Error>>()?;
+        assert_eq!(codes, [Some(0), Some(0), Some(3)]);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(matches!(
+            join_all(vec![ProcessHandle::from_channels(None, None, None)])[..],
+            [Err(Error::NoChild)]
+        ));
+
+        // a lazy handle is started, and the statuses are checked like `wait` does
+        let sh = |script: &str| {
+            let mut std_cmd = std::process::Command::new("sh");
+            std_cmd.args(["-c", script]);
+            Command::from(std_cmd)
+        };
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let lazy = sh("exit 0").lazy(true).run(subscriber.clone().into())?;
+        let nonzero = sh("exit 2").fail_on_nonzero(true).run(subscriber.into())?;
+        let results = join_all(vec![lazy, nonzero]);
+        assert!(matches!(&results[0], Ok(status) if status.success()));
+        assert!(matches!(&results[1], Err(Error::NonZeroExit { .. })));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_run_as() -> AnyResult<()> {
+        assert_eq!(user::lookup("root")?, Some((0, 0)));
+        let mut std_cmd = std::process::Command::new("id");
+        std_cmd.arg("-u");
+        let mut cmd = Command::from(std_cmd);
+        assert!(matches!(
+            cmd.run_as("no-such-user-here"),
+            Err(Error::UnknownUser(name)) if name == "no-such-user-here"
+        ));
+        // SAFETY: getuid and getgid cannot fail
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        cmd.uid(uid).gid(gid);
+        assert_eq!(cmd.stdout_string()?, uid.to_string());
+        if uid != 0 {
+            let mut cmd = Command::from(std::process::Command::new("id"));
+            assert!(matches!(
+                cmd.uid(0).stdout_string(),
+                Err(Error::PrivilegeDrop { uid: Some(0), .. })
+            ));
+        }
+        Ok(())
+    }
+
+    #[cfg(all(unix, feature = "bytes"))]
+    #[test]
+    fn check_bytes_into() -> AnyResult<()> {
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "head -c 300000 /dev/zero; printf err >&2"]);
+        let (stdout_tx, stdout_rx) = channel();
+        let (stderr_tx, stderr_rx) = channel();
+        let process = Command::from(std_cmd)
+            .stdout_bytes_into(stdout_tx)
+            .stderr_bytes_into(stderr_tx)
+            .run(subscriber.into())?;
+        assert!(process.stdout.is_none() && process.stderr.is_none());
+        let chunks: Vec<Bytes> = stdout_rx.iter().collect();
+        assert!(chunks.iter().all(|chunk| chunk.len() <= BYTES_CHUNK));
+        assert_eq!(
+            chunks.iter().map(|chunk| chunk.len()).sum::<usize>(),
+            300000
+        );
+        assert!(chunks.iter().all(|chunk| chunk.iter().all(|&b| b == 0)));
+        assert_eq!(
+            stderr_rx
+                .iter()
+                .flat_map(|c| c.to_vec())
+                .collect::<Vec<_>>(),
+            b"err"
+        );
+        assert!(process.wait()?.success());
+
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let (tx, rx) = channel();
+        let process = Command::from(std::process::Command::new("yes"))
+            .stdout_bytes_into(tx)
+            .read_limit(ReadLimit::Bytes(1000))
+            .run(subscriber.into())?;
+        let read: usize = rx.iter().map(|chunk| chunk.len()).sum();
+        assert_eq!(read, 1000);
+        assert_eq!(
+            process.finish_and_collect()?.result,
+            RunResult::OutputLimited
+        );
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_was_cancelled() -> AnyResult<()> {
+        let (broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let process = Command::from(std::process::Command::new("true")).run(subscriber.into())?;
+        assert!(process.wait()?.success());
+        broadcaster.broadcast(())?;
+        thread::sleep(Duration::from_millis(100));
+        assert!(!process.was_cancelled());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_wait_for_output_drain() -> AnyResult<()> {
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "echo last"]);
+        let process = Command::from(std_cmd).run(subscriber.clone().into())?;
+        assert!(process.wait()?.success());
+        assert!(process.wait_for_output_drain(Duration::from_secs(5)));
+        let stdout = process.stdout.as_ref().unwrap();
+        assert_eq!(stdout.0.try_recv()?, "last\n");
+
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "sleep 2 & echo started"]);
+        let process = Command::from(std_cmd).run(subscriber.into())?;
+        assert!(process.wait()?.success());
+        assert!(!process.wait_for_output_drain(Duration::from_millis(100)));
+        assert!(
+            ProcessHandle::from_channels(None, None, None).wait_for_output_drain(Duration::ZERO)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn check_already_run() -> AnyResult<()> {
+        let (broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("managed-command-test-process");
+        std_cmd.env("PATH", "testing");
+        let mut cmd = Command::from(std_cmd);
+        let _process = cmd.run(subscriber.clone().into())?;
+        assert!(matches!(
+            cmd.run(subscriber.clone().into()),
+            Err(Error::AlreadyRun(program)) if program == "managed-command-test-process"
+        ));
+        assert!(matches!(
+            cmd.run_deferred(subscriber.into()),
+            Err(Error::AlreadyRun(_))
+        ));
+        broadcaster.broadcast(())?;
+        Ok(())
+    }
+
+    #[test]
+    fn check_spawned_argv() {
+        let mut std_cmd = std::process::Command::new("managed-command-test-process");
+        std_cmd.args(["--flag", "a value"]).env("PATH", "testing");
+        assert_eq!(
+            Command::from(std_cmd).spawned_argv(),
+            ["managed-command-test-process", "--flag", "a value"]
+        );
+    }
+
+    #[test]
+    fn check_manual_clock() -> AnyResult<()> {
+        let clock = clock::ManualClock::new();
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("managed-command-test-process");
+        std_cmd.env("PATH", "testing");
+        let mut cmd = Command::from(std_cmd);
+        let (tx_exit, rx_exit) = channel();
+        let advanced = clock.clone();
+        cmd.clock(clock)
+            .on_spawn(move |_| advanced.advance(Duration::from_secs(90)))
+            .on_exit(move |_, elapsed| {
+                let _ = tx_exit.send(elapsed);
+            });
+        let process = cmd.run(subscriber.into())?;
+        process.with_child(|child| child.kill())??;
+        assert_eq!(rx_exit.recv()?, Duration::from_secs(90));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_clock_timeouts() -> AnyResult<()> {
+        use std::os::unix::process::ExitStatusExt;
+        // each of these would wait an hour of real time on the system clock
+        let hour = Duration::from_secs(3600);
+        let ticking = || {
+            let clock = clock::ManualClock::new();
+            let advanced = clock.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    thread::sleep(Duration::from_millis(50));
+                    advanced.advance(hour);
+                }
+            });
+            clock
+        };
+        let ignoring_term = || {
+            let mut std_cmd = std::process::Command::new("sh");
+            std_cmd.args([
+                "-c",
+                "trap '' TERM; echo ready; while :; do sleep 0.05; done",
+            ]);
+            let mut cmd = Command::from(std_cmd);
+            cmd.clock(ticking())
+                .poll_interval(Duration::from_millis(10))
+                .stdin_null();
+            cmd
+        };
+        let started = Instant::now();
+
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let process = ignoring_term().run(subscriber.into())?;
+        assert_eq!(process.shutdown(hour, hour)?.signal(), Some(libc::SIGKILL));
+
+        let (broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let process = ignoring_term()
+            .kill_schedule(vec![(Duration::ZERO, Signal::Term), (hour, Signal::Kill)])
+            .run(subscriber.into())?;
+        assert_eq!(process.stdout.as_ref().unwrap().recv()?, "ready\n");
+        broadcaster.broadcast(())?;
+        assert_eq!(process.wait()?.signal(), Some(libc::SIGKILL));
+
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let process = ignoring_term().run(subscriber.into())?;
+        let output = process.join_with_deadline(hour)?;
+        assert_eq!(output.result, RunResult::TimedOut);
+
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let process = ignoring_term().run(subscriber.into())?;
+        let expected = process.expect("never").timeout(hour).run();
+        assert!(matches!(expected, Err(Error::ExpectTimeout { .. })));
+        process.with_child(|child| child.kill())??;
+
+        assert!(started.elapsed() < Duration::from_secs(5));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_latest_line() -> AnyResult<()> {
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "echo 10%; echo 50%; printf done"]);
+        let process = Command::from(std_cmd)
+            .line_mode(true)
+            .run(subscriber.into())?;
+        assert!(process.wait()?.success());
+        assert!(process.wait_for_output_drain(Duration::from_secs(5)));
+        assert_eq!(process.latest_line().as_deref(), Some("done"));
+        assert_eq!(process.stdout_backlog(), 3);
+        assert_eq!(process.stdout_peak_backlog(), 3);
+        for _ in 0..3 {
+            process.stdout.as_ref().unwrap().recv()?;
+        }
+        assert_eq!(process.stdout_backlog(), 0);
+        assert_eq!(process.stdout_peak_backlog(), 3);
+        assert_eq!(process.stderr_peak_backlog(), 0);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_poll_interval() -> AnyResult<()> {
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let (tx_exit, rx_exit) = channel();
+        let mut std_cmd = std::process::Command::new("sleep");
+        std_cmd.arg("0.1");
+        let process = Command::from(std_cmd)
+            .poll_interval(Duration::from_millis(300))
+            .on_exit(move |status, _| {
+                let _ = tx_exit.send(status);
+            })
+            .run(subscriber.into())?;
+        let started = Instant::now();
+        assert!(process.wait()?.success());
+        assert!(rx_exit.recv()?.success());
+        // the first poll finds the child still sleeping
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_fail_on_stderr() -> AnyResult<()> {
+        let sh = |script: &str| {
+            let mut std_cmd = std::process::Command::new("sh");
+            std_cmd.args(["-c", script]);
+            Command::from(std_cmd)
+        };
+        assert!(sh("echo fine").fail_on_stderr(true).stdout_string().is_ok());
+        let err = sh("echo warning >&2; echo done")
+            .fail_on_stderr(true)
+            .stdout_string()
+            .unwrap_err();
+        assert!(matches!(err, Error::StderrProduced(first) if first == "warning\n"));
+
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let started = Instant::now();
+        let process = sh("echo warning >&2; sleep 10")
+            .kill_on_stderr(true)
+            .run(subscriber.into())?;
+        assert!(matches!(process.wait(), Err(Error::StderrProduced(_))));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        Ok(())
+    }
+
+    #[cfg(all(unix, feature = "tokio"))]
+    #[tokio::test]
+    async fn check_async_stdin() -> AnyResult<()> {
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut process = Command::from(std::process::Command::new("cat"))
+            .async_stdin(1)
+            .run(subscriber.into())?;
+        assert!(process.stdin.is_none());
+        let stdin = process.async_stdin.take().unwrap();
+        for line in ["one\n", "two\n"] {
+            stdin.send(line.to_owned()).await?;
+        }
+        stdin.close().await;
+        let output = process.finish_and_collect()?;
+        assert_eq!(output.stdout, "one\ntwo\n");
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_sync_pwd() -> AnyResult<()> {
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "echo \"$PWD\""]);
+        let mut cmd = Command::from(std_cmd);
+        cmd.current_dir("/tmp").sync_pwd(true);
+        assert_eq!(cmd.stdout_string()?, "/tmp");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "echo \"$PWD\""]).current_dir("src");
+        let here = env::current_dir()?.join("src");
+        let mut cmd = Command::from(std_cmd);
+        assert_eq!(cmd.sync_pwd(true).stdout_string()?, here.to_string_lossy());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_feed_and_wait() -> AnyResult<()> {
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "test \"$(wc -c)\" -eq 6 && echo ignored"]);
+        let status =
+            Command::from(std_cmd).feed_and_wait(&b"a\nb\x00c\n"[..], subscriber.clone().into())?;
+        assert!(status.success());
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "exit 4"]);
+        let input = io::repeat(b'x').take(1 << 20);
+        let mut cmd = Command::from(std_cmd);
+        let status = cmd.feed_and_wait(input, subscriber.into())?;
+        assert_eq!(status.code(), Some(4));
+        assert!(!cmd.write_only);
+        // the calling thread gets its signal mask back
+        // SAFETY: the set is filled in by pthread_sigmask before it is read
+        let sigpipe_blocked = unsafe {
+            let mut mask = std::mem::MaybeUninit::<libc::sigset_t>::uninit();
+            libc::pthread_sigmask(libc::SIG_BLOCK, std::ptr::null(), mask.as_mut_ptr());
+            libc::sigismember(mask.as_ptr(), libc::SIGPIPE) == 1
+        };
+        assert!(!sigpipe_blocked);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_catch_callback_panics() -> AnyResult<()> {
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut process = Command::from(std::process::Command::new("cat"))
+            .catch_callback_panics(true)
+            .map_stdin(|input| {
+                if input.contains("bad") {
+                    panic!("cannot map {input:?}");
+                }
+                input
+            })
+            .run(subscriber.into())?;
+        let stdin = process.stdin.take().unwrap();
+        stdin.send("bad input".to_owned())?;
+        thread::sleep(Duration::from_millis(200));
+        assert!(matches!(
+            stdin.send("more".to_owned()),
+            Err(Error::StdinClosed(_))
+        ));
+        let err = process.wait().unwrap_err();
+        assert!(matches!(err, Error::CallbackPanicked(message)
+            if message == "cannot map \"bad input\""));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_log_stderr() -> AnyResult<()> {
+        let _ = tracing_subscriber::fmt::try_init();
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "echo logged >&2; echo kept"]);
+        let output = Command::from(std_cmd)
+            .log_stderr(Level::WARN)
+            .run(subscriber.into())?
+            .finish_and_collect()?;
+        assert_eq!(
+            (output.stdout.as_str(), output.stderr.as_str()),
+            ("kept\n", "")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn check_tracing_sender() {
+        use std::collections::BTreeMap;
+        use tracing::{field, span, Event, Metadata, Subscriber};
+        type Events = Arc<Mutex<Vec<(Level, BTreeMap<String, String>)>>>;
+        struct Capture(Events);
+        struct Fields(BTreeMap<String, String>);
+        impl field::Visit for Fields {
+            fn record_debug(&mut self, field: &field::Field, value: &dyn Debug) {
+                self.0.insert(field.name().to_owned(), format!("{value:?}"));
+            }
+        }
+        impl Subscriber for Capture {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+            fn new_span(&self, _: &span::Attributes<'_>) -> span::Id {
+                span::Id::from_u64(1)
+            }
+            fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+            fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+            fn event(&self, event: &Event<'_>) {
+                let mut fields = Fields(BTreeMap::new());
+                event.record(&mut fields);
+                let level = *event.metadata().level();
+                self.0.lock().unwrap().push((level, fields.0));
+            }
+            fn enter(&self, _: &span::Id) {}
+            fn exit(&self, _: &span::Id) {}
+        }
+
+        let events = Events::default();
+        let sender = TracingSender {
+            program: "backup".to_owned(),
+            level: Level::WARN,
+        };
+        let sent = tracing::subscriber::with_default(Capture(Arc::clone(&events)), || {
+            sender.send("disk almost full".to_owned())
+        });
+        assert!(sent);
+        let fields = BTreeMap::from([
+            ("message".to_owned(), "disk almost full".to_owned()),
+            ("program".to_owned(), "backup".to_owned()),
+        ]);
+        assert_eq!(*events.lock().unwrap(), [(Level::WARN, fields)]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_log_stderr_span() -> AnyResult<()> {
+        use std::collections::HashMap;
+        use tracing::{field, span, Event, Metadata, Subscriber};
+        /// Filters at WARN like a usual setup, and notes the spans each thread
+        /// is in.
+        #[derive(Clone, Default)]
+        struct Capture(Arc<Captured>);
+        #[derive(Default)]
+        struct Captured {
+            spans: Mutex<Vec<(&'static str, Level)>>,
+            entered: Mutex<HashMap<thread::ThreadId, Vec<u64>>>,
+            events: Mutex<Vec<(String, Vec<u64>)>>,
+        }
+        struct Message(String);
+        impl field::Visit for Message {
+            fn record_debug(&mut self, field: &field::Field, value: &dyn Debug) {
+                if field.name() == "message" {
+                    self.0 = format!("{value:?}");
+                }
+            }
+        }
+        impl Subscriber for Capture {
+            fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+                *metadata.level() <= Level::WARN
+            }
+            fn new_span(&self, attributes: &span::Attributes<'_>) -> span::Id {
+                let metadata = attributes.metadata();
+                let mut spans = self.0.spans.lock().unwrap();
+                spans.push((metadata.name(), *metadata.level()));
+                span::Id::from_u64(spans.len() as u64)
+            }
+            fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+            fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+            fn event(&self, event: &Event<'_>) {
+                let mut message = Message(String::new());
+                event.record(&mut message);
+                let entered = self.0.entered.lock().unwrap();
+                let spans = entered.get(&thread::current().id()).cloned();
+                let event = (message.0, spans.unwrap_or_default());
+                self.0.events.lock().unwrap().push(event);
+            }
+            fn enter(&self, id: &span::Id) {
+                let mut entered = self.0.entered.lock().unwrap();
+                let spans = entered.entry(thread::current().id()).or_default();
+                spans.push(id.into_u64());
+            }
+            fn exit(&self, _: &span::Id) {
+                let mut entered = self.0.entered.lock().unwrap();
+                if let Some(spans) = entered.get_mut(&thread::current().id()) {
+                    spans.pop();
+                }
+            }
+        }
+
+        let capture = Capture::default();
+        let output = tracing::subscriber::with_default(capture.clone(), || {
+            let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+            let mut std_cmd = std::process::Command::new("sh");
+            std_cmd.args(["-c", "echo logged >&2"]);
+            Command::from(std_cmd)
+                .log_stderr(Level::WARN)
+                .run(subscriber.into())?
+                .finish_and_collect()
+        })?;
+        assert!(output.status.success());
+        assert_eq!(*capture.0.spans.lock().unwrap(), [("command", Level::WARN)]);
+        assert_eq!(
+            *capture.0.events.lock().unwrap(),
+            [("logged".to_owned(), vec![1])]
+        );
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_secure_env() -> AnyResult<()> {
+        let mut std_cmd = std::process::Command::new("env");
+        std_cmd.env("EARLY", "1");
+        let mut cmd = Command::from(std_cmd);
+        cmd.secure_env(&["HOME", "NOT_SET_ANYWHERE"])
+            .env("LATE", "2");
+        let mut vars: Vec<_> = cmd.stdout_string()?.lines().map(str::to_owned).collect();
+        vars.sort();
+        let home = format!("HOME={}", env::var("HOME")?);
+        assert_eq!(vars, ["EARLY=1", home.as_str(), "LATE=2"]);
+        Ok(())
+    }
+
+    /// A [`clock::ManualClock`] that counts its reads, so that a test can tell
+    /// when a thread polling it has seen the time it was advanced to.
+    #[derive(Clone, Default)]
+    struct ObservedClock {
+        manual: clock::ManualClock,
+        reads: Arc<AtomicUsize>,
+    }
+
+    impl ObservedClock {
+        /// Advances by `by`, then waits until the clock was read three more
+        /// times, for up to five seconds.
+        fn advance(&self, by: Duration) {
+            let reads = self.reads.load(Ordering::SeqCst);
+            self.manual.advance(by);
+            let started = Instant::now();
+            while self.reads.load(Ordering::SeqCst) < reads + 3
+                && started.elapsed() < Duration::from_secs(5)
+            {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+    }
+
+    impl Clock for ObservedClock {
+        fn now(&self) -> Instant {
+            let now = self.manual.now();
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            now
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_auto_close_after_idle() -> AnyResult<()> {
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let clock = ObservedClock::default();
+        let mut process = Command::from(std::process::Command::new("cat"))
+            .clock(clock.clone())
+            .run(subscriber.into())?;
+        let stdin = process.stdin.take().unwrap();
+        stdin.auto_close_after_idle(Duration::from_secs(5));
+        stdin.send("first\n".to_owned())?;
+        clock.advance(Duration::from_secs(4));
+        stdin.send("second\n".to_owned())?;
+        clock.advance(Duration::from_secs(4));
+        stdin.send("third\n".to_owned())?;
+        // the watcher stops reading the clock once it closed stdin
+        clock.manual.advance(Duration::from_secs(5));
+        let output = process.finish_and_collect()?;
+        assert_eq!(output.stdout, "first\nsecond\nthird\n");
+        assert!(matches!(
+            stdin.send("late".to_owned()),
+            Err(Error::StdinClosed(input)) if input == "late"
+        ));
+
+        // a write stuck on a full pipe holds up neither the close nor sends
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let clock = clock::ManualClock::new();
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "sleep 30"]);
+        let mut process = Command::from(std_cmd)
+            .clock(clock.clone())
+            .run(subscriber.into())?;
+        let stdin = process.stdin.take().unwrap();
+        stdin.auto_close_after_idle(Duration::from_secs(5));
+        stdin.send("x".repeat(1 << 20))?;
+        clock.advance(Duration::from_secs(5));
+        let started = Instant::now();
+        while !stdin.stdin_thread_gone() {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(matches!(
+            stdin.send("late".to_owned()),
+            Err(Error::StdinClosed(_))
+        ));
+        process.with_child(|child| child.kill())??;
+        Ok(())
+    }
+
+    #[test]
+    fn check_into_reader() -> AnyResult<()> {
+        let (tx, rx) = channel();
+        let failure = ReadFailure::default();
+        let stdout = StdoutReceiver(
+            rx,
+            Arc::default(),
+            Arc::clone(&failure),
+            Cell::default(),
+            None,
+            false,
+        );
+        for chunk in ["first li", "", "ne\nsecond", " line\n"] {
+            tx.send(chunk.to_owned())?;
+        }
+        drop(tx);
+        let mut reader = io::BufReader::with_capacity(4, stdout.into_reader());
+        let mut line = String::new();
+        io::BufRead::read_line(&mut reader, &mut line)?;
+        assert_eq!(line, "first line\n");
+        *failure.lock().unwrap() = Some(io::Error::other("pipe went away"));
+        let mut rest = String::new();
+        assert!(reader.read_to_string(&mut rest).is_err());
+        assert_eq!(rest, "second line\n");
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_final_partial_line() -> AnyResult<()> {
+        struct Failing(Vec<&'static [u8]>);
+        impl Read for Failing {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let Some(chunk) = self.0.pop() else {
+                    return Err(io::Error::other("pipe went away"));
+                };
+                buf[..chunk.len()].copy_from_slice(chunk);
+                Ok(chunk.len())
+            }
+        }
+        let child = Arc::new(Mutex::new(std::process::Command::new("true").spawn()?));
+        let (tx, rx) = channel();
+        let failure = ReadFailure::default();
+        let mut sink = OutputSink::new(child);
+        sink.tx = Box::new(tx);
+        sink.failure = Some(Arc::clone(&failure));
+        let config = ReaderConfig {
+            line_mode: true,
+            normalize_newlines: true,
+            ..Default::default()
+        };
+        reader_worker(
+            "test".to_owned(),
+            "stdout",
+            Failing(vec![b"tial\r", b"one\npar"]),
+            config,
+            sink,
+        )();
+        let stdout = StdoutReceiver(rx, Arc::default(), failure, Cell::default(), None, true);
+        let mut lines = stdout.lines();
+        assert_eq!(lines.next().transpose()?.as_deref(), Some("one"));
+        assert_eq!(lines.next().transpose()?.as_deref(), Some("partial"));
+        assert!(matches!(lines.next(), Some(Err(Error::IoError(_)))));
+        Ok(())
+    }
+
+    #[test]
+    fn check_pairs_read_error() -> AnyResult<()> {
+        struct Failing(bool);
+        impl Read for Failing {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if mem::replace(&mut self.0, true) {
+                    return Err(io::Error::other("pipe went away"));
+                }
+                buf[..3].copy_from_slice(b"ok\n");
+                Ok(3)
+            }
+        }
+        let (tx, rx) = channel();
+        let (errors_tx, errors_rx) = channel();
+        pairs_reader_worker("test".to_owned(), "stdout", Failing(false), tx, errors_tx)();
+        assert_eq!(rx.recv()?, (b"ok\n".to_vec(), "ok\n".to_owned()));
+        assert!(rx.recv().is_err());
+        assert!(matches!(
+            errors_rx.recv()?,
+            Error::IoError(e) if e.to_string() == "pipe went away"
+        ));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_final_line_without_newline() -> AnyResult<()> {
+        for (line_mode, capture_after) in [(true, None), (true, Some("start")), (false, None)] {
+            let mut std_cmd = std::process::Command::new("sh");
+            std_cmd.args(["-c", "echo start; printf 'last'; printf 'error' >&2"]);
+            let mut cmd = Command::from(std_cmd);
+            cmd.line_mode(line_mode);
+            if let Some(marker) = capture_after {
+                cmd.capture_after(marker.to_owned());
+            }
+            let (_broadcaster, canceller) = idle_canceller();
+            let output = cmd.run_collecting(canceller, |_, _| {})?;
+            let expected = if capture_after.is_some() {
+                "last"
+            } else {
+                "start\nlast"
+            };
+            assert_eq!(output.stdout, expected);
+            assert_eq!(output.stderr, "error");
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_redirect_to_files() -> AnyResult<()> {
+        let dir = env::temp_dir().join(format!("managed-command-redirect-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let (out, err) = (dir.join("out.log"), dir.join("err.log"));
+        std::fs::write(&out, "earlier\n")?;
+        let mut append = OpenOptions::new();
+        append.append(true).create(true);
+        let mut truncate = OpenOptions::new();
+        truncate.write(true).create(true).truncate(true);
+        for _ in 0..2 {
+            let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+            let mut std_cmd = std::process::Command::new("sh");
+            std_cmd.args(["-c", "echo out; echo err >&2"]);
+            let process = Command::from(std_cmd)
+                .redirect_stdout(&out, append.clone())
+                .redirect_stderr(&err, truncate.clone())
+                .run(subscriber.into())?;
+            assert!(process.stdout.is_none() && process.stderr.is_none());
+            assert!(process.wait()?.success());
+        }
+        assert_eq!(std::fs::read_to_string(&out)?, "earlier\nout\nout\n");
+        assert_eq!(std::fs::read_to_string(&err)?, "err\n");
+
+        let mut cmd = Command::from(std::process::Command::new("true"));
+        cmd.redirect_stderr(dir.join("missing").join("err.log"), truncate);
+        assert!(matches!(cmd.stdout_string(), Err(Error::IoError(_))));
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn check_utf8_carry() {
+        let mut utf8 = Utf8Carry::default();
+        let text = "añ€😀";
+        let bytes = text.as_bytes();
+        let mut decoded = Vec::new();
+        for chunk in bytes.chunks(1) {
+            decoded.extend(utf8.split(chunk));
+        }
+        assert!(utf8.0.is_empty());
+        assert_eq!(decoded, bytes);
+        assert_eq!(utf8.split(&bytes[..3]), "añ".as_bytes());
+        assert_eq!(utf8.split(&bytes[3..5]), b"");
+        assert_eq!(utf8.split(&bytes[5..]), "€😀".as_bytes());
+        assert_eq!(utf8.split(b"\xff\xfe"), b"\xff\xfe");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_decode_threads() -> AnyResult<()> {
+        let script = "i=0; while [ $i -lt 3000 ]; do echo \"line $i ünïcödé €\"; i=$((i+1)); done";
+        let expected: String = (0..3000).map(|i| format!("line {i} ünïcödé €\n")).collect();
+        for (decoders, line_mode) in [(1, false), (4, false), (3, true)] {
+            let mut std_cmd = std::process::Command::new("sh");
+            std_cmd.args(["-c", script]);
+            let (_broadcaster, canceller) = idle_canceller();
+            let output = Command::from(std_cmd)
+                .decode_threads(decoders)
+                .line_mode(line_mode)
+                .run_collecting(canceller, |_, _| {})?;
+            assert!(
+                output.stdout == expected,
+                "{decoders} decoders lost the order"
+            );
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_pairs_into() -> AnyResult<()> {
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args([
+            "-c",
+            "printf 'caf\\303'; sleep 0.1; printf '\\251\\n'; printf 'x\\377' >&2",
+        ]);
+        let (stdout_tx, stdout_rx) = channel();
+        let (stderr_tx, stderr_rx) = channel();
+        let process = Command::from(std_cmd)
+            .stdout_pairs_into(stdout_tx)
+            .stderr_pairs_into(stderr_tx)
+            .run(subscriber.into())?;
+        assert!(process.stdout.is_none() && process.stderr.is_none());
+        let (bytes, text): (Vec<Vec<u8>>, Vec<String>) = stdout_rx.iter().unzip();
+        assert_eq!(bytes, [b"caf\xc3".to_vec(), b"\xa9\n".to_vec()]);
+        assert_eq!(text, ["caf", "é\n"]);
+        let (bytes, text): (Vec<Vec<u8>>, Vec<String>) = stderr_rx.iter().unzip();
+        assert_eq!(bytes.concat(), b"x\xff");
+        assert_eq!(text.concat(), "x\u{fffd}");
+        assert!(process.wait()?.success());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_run_with_deadline() -> AnyResult<()> {
+        use std::os::unix::process::ExitStatusExt;
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "echo partial; echo oops >&2; exec sleep 10"]);
+        let started = Instant::now();
+        let (output, expired) =
+            Command::from(std_cmd).run_with_deadline(Duration::from_millis(300))?;
+        assert!(expired);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(!output.status.success());
+        assert_eq!(output.stdout, "partial\n");
+        assert_eq!(output.stderr, "oops\n");
+
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "echo quick"]);
+        let (output, expired) = Command::from(std_cmd).run_with_deadline(Duration::from_secs(5))?;
+        assert!(!expired);
+        assert!(output.status.success());
+        assert_eq!(output.stdout, "quick\n");
+
+        // the deadline does not settle for the cancel signal
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "trap '' TERM; while :; do sleep 0.05; done"]);
+        let (output, expired) = Command::from(std_cmd)
+            .cancel_signal(Signal::Term)
+            .run_with_deadline(Duration::from_millis(300))?;
+        assert!(expired);
+        assert_eq!(output.status.signal(), Some(libc::SIGKILL));
+
+        // the kill is no failure to report instead of the output
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "echo partial; exec sleep 10"]);
+        let (output, expired) = Command::from(std_cmd)
+            .fail_on_nonzero(true)
+            .run_with_deadline(Duration::from_millis(300))?;
+        assert!(expired);
+        assert_eq!(output.result, RunResult::TimedOut);
+        assert_eq!(output.stdout, "partial\n");
+
+        // a failure the deadline has nothing to do with is still reported
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "exit 2"]);
+        let failed = Command::from(std_cmd)
+            .fail_on_nonzero(true)
+            .run_with_deadline(Duration::from_secs(5));
+        assert!(matches!(failed, Err(Error::NonZeroExit { .. })));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_cancel_signal() -> AnyResult<()> {
+        let (broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args([
+            "-c",
+            "trap 'echo bye; exit 3' TERM; echo ready; while :; do sleep 0.05; done",
+        ]);
+        let process = Command::from(std_cmd)
+            .cancel_signal(Signal::Term)
+            .line_mode(true)
+            .run(subscriber.into())?;
+        assert_eq!(process.stdout.as_ref().unwrap().recv()?, "ready");
+        broadcaster.broadcast(())?;
+        assert_eq!(process.wait()?.code(), Some(3));
+        assert!(process.was_cancelled());
+        assert_eq!(process.stdout.as_ref().unwrap().recv()?, "bye");
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_join_with_deadline() -> AnyResult<()> {
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        // the grandchild keeps the pipes open after sh is killed
+        std_cmd.args(["-c", "echo partial; sleep 10; echo never"]);
+        let started = Instant::now();
+        let output = Command::from(std_cmd)
+            .run(subscriber.into())?
+            .join_with_deadline(Duration::from_millis(300))?;
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(output.timed_out());
+        assert_eq!(output.result, RunResult::TimedOut);
+        assert!(!output.status.success());
+        assert_eq!(output.stdout, "partial\n");
+
+        // the kill is no failure to report instead of the output
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "echo partial; echo oops >&2; exec sleep 10"]);
+        let output = Command::from(std_cmd)
+            .fail_on_nonzero(true)
+            .fail_on_stderr(true)
+            .run(subscriber.into())?
+            .join_with_deadline(Duration::from_millis(300))?;
+        assert_eq!(output.result, RunResult::TimedOut);
+        assert_eq!(
+            (output.stdout.as_str(), output.stderr.as_str()),
+            ("partial\n", "oops\n")
+        );
+
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "echo done"]);
+        let output = Command::from(std_cmd)
+            .run(subscriber.into())?
+            .join_with_deadline(Duration::from_secs(5))?;
+        assert!(!output.timed_out());
+        assert_eq!(output.result, RunResult::Completed(output.status));
+        assert!(output.status.success());
+        assert_eq!(output.stdout, "done\n");
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_run_with_env() -> AnyResult<()> {
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "echo \"$BASE $MANAGED_COMMAND_ONE_OFF\""]);
+        let setups: [fn(&mut Command); 3] = [
+            |_| {},
+            |cmd| {
+                cmd.env_clear();
+            },
+            |cmd| {
+                cmd.secure_env(&["HOME"]);
+            },
+        ];
+        for setup in setups {
+            let mut cmd = Command::from(std::process::Command::new("sh"));
+            cmd.std_command.args(std_cmd.get_args());
+            setup(&mut cmd);
+            cmd.env("BASE", "base");
+            let before = cmd.effective_env();
+            let output = cmd
+                .run_with_env(
+                    &[("BASE", "over"), ("MANAGED_COMMAND_ONE_OFF", "x")],
+                    subscriber.clone().into(),
+                )?
+                .finish_and_collect()?;
+            assert_eq!(output.stdout, "over x\n");
+            assert_eq!(cmd.effective_env(), before);
+            // nor does the override come back as an explicit variable
+            cmd.secure_env(&[]);
+            let vars = cmd.effective_env();
+            assert!(vars.contains(&("BASE".into(), "base".into())));
+            assert!(!vars.iter().any(|(key, _)| key == "MANAGED_COMMAND_ONE_OFF"));
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_stdin_closed_on_broken_pipe() -> AnyResult<()> {
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        // closes its stdin but keeps running, so only the pipe is broken
+        std_cmd.args(["-c", "exec 0<&-; echo closed; sleep 2"]);
+        let process = Command::from(std_cmd)
+            .line_mode(true)
+            .run(subscriber.into())?;
+        assert_eq!(process.stdout.as_ref().unwrap().recv()?, "closed");
+        let stdin = process.stdin.as_ref().unwrap();
+        let refused = stdin.send("more\n".to_owned()).unwrap_err();
+        assert!(matches!(refused, Error::StdinClosed(input) if input == "more\n"));
+        let failure = WriteFailure::default();
+        *failure.lock().unwrap() = Some(io::Error::other("disk on fire"));
+        assert!(
+            matches!(stdin_gone(&failure, String::new()), Error::IoError(e) if e.to_string() == "disk on fire")
+        );
+        Ok(())
+    }
+
+    #[cfg(all(unix, feature = "rtrb"))]
+    #[test]
+    fn check_stdout_ring() -> AnyResult<()> {
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("head");
+        std_cmd.args(["-c", "300000", "/dev/urandom"]);
+        let mut process = Command::from(std_cmd)
+            .stdout_ring(1000)
+            .run(subscriber.into())?;
+        assert!(process.stdout.is_none());
+        let mut ring = process.stdout_ring.take().unwrap();
+        let mut read = 0;
+        loop {
+            let abandoned = ring.is_abandoned();
+            let available = ring.slots();
+            if available > 0 {
+                let chunk = ring.read_chunk(available)?;
+                let (first, second) = chunk.as_slices();
+                read += first.len() + second.len();
+                chunk.commit_all();
+            } else if abandoned {
+                break;
+            } else {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        assert_eq!(read, 300000);
+        assert!(process.wait()?.success());
+        Ok(())
+    }
+
+    #[cfg(feature = "rtrb")]
+    #[test]
+    fn check_ring_read_error() -> AnyResult<()> {
+        struct Failing;
+        impl Read for Failing {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("pipe went away"))
+            }
+        }
+        let (producer, ring) = rtrb::RingBuffer::new(16);
+        let (errors_tx, errors_rx) = channel();
+        ring::reader_worker("test".to_owned(), Failing, producer, errors_tx)();
+        assert!(ring.is_abandoned());
+        assert!(matches!(
+            errors_rx.recv()?,
+            Error::IoError(e) if e.to_string() == "pipe went away"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn check_with_context() -> AnyResult<()> {
+        #[derive(Debug, PartialEq)]
+        struct JobId(u32);
+        let (broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("managed-command-test-process");
+        std_cmd.env("PATH", "testing");
+        let (tx, rx) = channel();
+        let exit_tx = tx.clone();
+        let process = Command::from(std_cmd)
+            .with_context(JobId(7))
+            .on_spawn(move |_| {
+                let _ = tx.send(("spawn", callback_context::<JobId>()));
+            })
+            .on_exit(move |_, _| {
+                let _ = exit_tx.send(("exit", callback_context::<JobId>()));
+            })
+            .run(subscriber.into())?;
+        assert_eq!(process.context::<JobId>(), Some(&JobId(7)));
+        assert_eq!(process.context::<u32>(), None);
+        assert_eq!(callback_context::<JobId>(), None);
+        broadcaster.broadcast(())?;
+        process.wait()?;
+        let seen: Vec<_> = rx.iter().collect();
+        let job = Some(Arc::new(JobId(7)));
+        assert_eq!(seen, [("spawn", job.clone()), ("exit", job)]);
+        Ok(())
+    }
+
+    #[cfg(all(unix, feature = "serde"))]
+    #[test]
+    fn check_run_json_lines_into() -> AnyResult<()> {
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "echo 1; echo; echo oops; printf 3"]);
+        let (tx, rx) = channel::<Result<u32, Error>>();
+        let process = Command::from(std_cmd).run_json_lines_into(tx, subscriber.into())?;
+        assert!(process.stdout.is_none());
+        let values: Vec<_> = rx.iter().collect();
+        assert_eq!(values.len(), 3);
+        assert!(matches!(values[0], Ok(1)));
+        assert!(matches!(values[1], Err(Error::Json(_))));
+        assert!(matches!(values[2], Ok(3)));
+        assert!(process.wait()?.success());
+        Ok(())
+    }
+
+    #[cfg(all(unix, feature = "signal-forwarding"))]
+    #[test]
+    fn check_forward_signals() -> AnyResult<()> {
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args([
+            "-c",
+            "trap 'echo relayed; exit 0' USR2; echo ready; while :; do sleep 0.05; done",
+        ]);
+        let mut process = Command::from(std_cmd)
+            .line_mode(true)
+            .forward_signals(&[Signal::Usr2])
+            .run(subscriber.into())?;
+        let stdout = process.stdout.take().unwrap();
+        assert_eq!(stdout.recv()?, "ready");
+        // SAFETY: plain syscall, the handler forward_signals installed catches it
+        assert_eq!(unsafe { libc::kill(libc::getpid(), libc::SIGUSR2) }, 0);
+        assert_eq!(stdout.recv()?, "relayed");
+        assert!(process.wait()?.success());
+        Ok(())
+    }
+
+    #[cfg(all(unix, feature = "serde"))]
+    #[test]
+    fn check_json_lines() -> AnyResult<()> {
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "echo 1; echo; echo oops; printf 3"]);
+        let mut process = Command::from(std_cmd)
+            .line_mode(true)
+            .run(subscriber.into())?;
+        let stdout = process.stdout.take().unwrap();
+        let mut values = stdout.json_lines::<u32>();
+        assert!(matches!(values.next(), Some(Ok(1))));
+        assert!(matches!(values.next(), Some(Err(Error::Json(_)))));
+        assert!(matches!(values.next(), Some(Ok(3))));
+        assert!(values.next().is_none());
+        assert!(process.wait()?.success());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_expect() -> AnyResult<()> {
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args([
+            "-c",
+            "printf 'Pass'; sleep 0.1; printf 'word: '; read pw; echo \"Welcome $pw\"; \
+             echo warn >&2; read never",
+        ]);
+        let process = Command::from(std_cmd).run(subscriber.into())?;
+        process
+            .expect("Password:")
+            .send("secret\n")
+            .expect("Welcome secret")
+            .expect_stderr("warn")
+            .timeout(Duration::from_secs(5))
+            .run()?;
+        let started = Instant::now();
+        let timed_out = process
+            .expect("more")
+            .timeout(Duration::from_millis(200))
+            .run();
+        assert!(
+            matches!(timed_out, Err(Error::ExpectTimeout { pattern, .. }) if pattern == "more")
+        );
+        assert!(started.elapsed() < Duration::from_secs(2));
+        process.stdin.as_ref().unwrap().send("\n".to_owned())?;
+        let ended = process.expect("more").run();
+        assert!(matches!(ended, Err(Error::ExpectEof { seen, .. }) if seen.trim().is_empty()));
+        assert!(process.wait()?.success());
+
+        // what a script read past its match is left for the next one
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "printf 'one two'"]);
+        let process = Command::from(std_cmd).run(subscriber.into())?;
+        process.expect("one").run()?;
+        process.expect("two").run()?;
+        assert!(process.wait()?.success());
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn check_new_pid_namespace() -> AnyResult<()> {
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "sh -c 'echo $$'"]);
+        let mut cmd = Command::from(std_cmd);
+        cmd.new_user_namespace(true).new_pid_namespace(true);
+        match cmd.stdout_string() {
+            Ok(pid) => assert_eq!(pid, "1"),
+            // user namespaces are disabled on this system
+            Err(Error::IoError(e)) if e.kind() == io::ErrorKind::PermissionDenied => {}
+            Err(e) => return Err(e.into()),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn check_stderr_tail() {
+        assert_eq!(stderr_tail(""), "");
+        assert_eq!(stderr_tail("denied\n"), "denied\n");
+        let lines: String = (1..=30).map(|i| format!("line {i}\n")).collect();
+        let tail = stderr_tail(&lines);
+        assert!(tail.starts_with("line 11\n") && tail.ends_with("line 30\n"));
+        assert_eq!(tail.lines().count(), STDERR_TAIL_LINES);
+        let long = "é".repeat(STDERR_TAIL_BYTES);
+        assert_eq!(stderr_tail(&long).len(), STDERR_TAIL_BYTES);
+    }
+
+    #[test]
+    fn check_fd_count() {
+        let mut cmd = Command::from(std::process::Command::new("true"));
+        assert_eq!(cmd.fd_count(), 3);
+        cmd.stdin_null();
+        assert_eq!(cmd.fd_count(), 2);
+        cmd.redirect_stderr("/dev/null", OpenOptions::new().write(true).clone());
+        assert_eq!(cmd.fd_count(), 1);
+        let mut feed = Command::from(std::process::Command::new("true"));
+        feed.write_only = true;
+        assert_eq!(feed.fd_count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_shutdown() -> AnyResult<()> {
+        use std::os::unix::process::ExitStatusExt;
+        let shutdown = |script: &str| -> AnyResult<(ExitStatus, Duration)> {
+            let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+            let mut std_cmd = std::process::Command::new("sh");
+            std_cmd.args(["-c", script]);
+            let process = Command::from(std_cmd).stdin_null().run(subscriber.into())?;
+            let started = Instant::now();
+            let status =
+                process.shutdown(Duration::from_millis(300), Duration::from_millis(300))?;
+            Ok((status, started.elapsed()))
+        };
+        let (status, spent) = shutdown("exit 4")?;
+        assert_eq!(status.code(), Some(4));
+        assert!(spent < Duration::from_millis(300));
+        let (status, spent) = shutdown("trap 'exit 3' TERM; while :; do sleep 0.05; done")?;
+        assert_eq!(status.code(), Some(3));
+        assert!(spent >= Duration::from_millis(300) && spent < Duration::from_millis(600));
+        let (status, spent) = shutdown("trap '' TERM; while :; do sleep 0.05; done")?;
+        assert_eq!(status.signal(), Some(libc::SIGKILL));
+        assert!(spent >= Duration::from_millis(600));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_record_sentinel() -> AnyResult<()> {
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args([
+            "-c",
+            "printf 'a\\nb\\n---END---\\n---END---\\nc\\n---EN'; sleep 0.1; printf 'D---\\nlast\\ntail'",
+        ]);
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let process = Command::from(std_cmd)
+            .record_sentinel("---END---".to_owned())
+            .run(subscriber.into())?;
+        let records: Vec<String> = iter::from_fn(|| process.stdout.as_ref()?.recv().ok()).collect();
+        assert_eq!(records, ["a\nb", "", "c", "last\ntail"]);
+        assert!(process.wait()?.success());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_fail_on_nonzero() -> AnyResult<()> {
+        let run = |script: &str| -> Result<ExitStatus, Error> {
+            let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+            let mut std_cmd = std::process::Command::new("sh");
+            std_cmd.args(["-c", script]);
+            let process = Command::from(std_cmd)
+                .fail_on_nonzero(true)
+                .line_mode(true)
+                .run(subscriber.into())?;
+            let stderr = process.stderr.as_ref().unwrap();
+            while stderr.recv().is_ok() {}
+            assert!(process.stdout.as_ref().unwrap().recv().is_err());
+            process.wait()
+        };
+        let err = run("echo 'bad argument' >&2; exit 2").unwrap_err();
+        assert!(matches!(err, Error::NonZeroExit { status, stderr, .. }
+            if status.code() == Some(2) && stderr == "bad argument\n"));
+        assert!(run("true")?.success());
+
+        // only the end of stderr is waited for, a grandchild may keep stdout open
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "sleep 3 2>/dev/null & echo late >&2; exit 3"]);
+        let process = Command::from(std_cmd)
+            .fail_on_nonzero(true)
+            .line_mode(true)
+            .run(subscriber.into())?;
+        let started = Instant::now();
+        let err = process.wait().unwrap_err();
+        assert!(started.elapsed() < STDERR_GRACE);
+        assert!(matches!(err, Error::NonZeroExit { stderr, .. } if stderr == "late\n"));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_numbered() -> AnyResult<()> {
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "echo o1; echo e1 >&2; echo o2; echo e2 >&2; echo o3"]);
+        let mut process = Command::from(std_cmd)
+            .line_mode(true)
+            .run(subscriber.into())?;
+        let stdout = process.stdout.take().unwrap();
+        assert_eq!(stdout.recv()?, "o1");
+        let numbered: Vec<_> = stdout.numbered().collect();
+        assert_eq!(
+            numbered,
+            [
+                NumberedLine {
+                    stream_line_no: 2,
+                    data: "o2".to_owned()
+                },
+                NumberedLine {
+                    stream_line_no: 3,
+                    data: "o3".to_owned()
+                },
+            ]
+        );
+        let stderr = process.stderr.take().unwrap();
+        let numbered: Vec<_> = stderr.numbered().map(|line| line.stream_line_no).collect();
+        assert_eq!(numbered, [1, 2]);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_stdio() -> AnyResult<()> {
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "cat; echo out; echo err >&2"]);
+        let mut cmd = Command::from(std_cmd);
+        cmd.redirect_stdout("/dev/null", OpenOptions::new().write(true).clone())
+            .stdio(StdioMode::Null, StdioMode::Piped, StdioMode::Null);
+        assert_eq!(cmd.fd_count(), 1);
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let process = cmd.run(subscriber.into())?;
+        assert!(process.stdin.is_none() && process.stderr.is_none());
+        assert_eq!(process.finish_and_collect()?.stdout, "out\n");
+
+        let mut cmd = Command::from(std::process::Command::new("true"));
+        assert_eq!(cmd.inherit_all().fd_count(), 0);
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let process = cmd.run(subscriber.into())?;
+        assert!(process.stdin.is_none() && process.stdout.is_none() && process.stderr.is_none());
+        assert!(process.wait()?.success());
+        assert_eq!(
+            Command::from(std::process::Command::new("true"))
+                .inherit_all()
+                .capture_all()
+                .fd_count(),
+            3
+        );
+
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "echo out; echo err >&2"]);
+        let mut cmd = Command::from(std_cmd);
+        cmd.stdin_null().discard_stderr();
+        assert_eq!(cmd.fd_count(), 1);
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let process = cmd.run(subscriber.into())?;
+        assert!(process.stderr.is_none());
+        let output = process.finish_and_collect()?;
+        assert_eq!((&*output.stdout, &*output.stderr), ("out\n", ""));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_on_backlog() -> AnyResult<()> {
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "echo 1; echo 2; echo 3; echo 4; echo 5"]);
+        let (tx, rx) = channel();
+        let process = Command::from(std_cmd)
+            .line_mode(true)
+            .on_backlog(2, move || {
+                let _ = tx.send(());
+            })
+            .run(subscriber.into())?;
+        assert!(process.wait()?.success());
+        assert!(process.wait_for_output_drain(Duration::from_secs(5)));
+        assert_eq!(process.stdout_backlog(), 5);
+        assert_eq!(rx.try_iter().count(), 1);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_send_sequence() -> AnyResult<()> {
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "read a; echo got $a"]);
+        let mut process = Command::from(std_cmd).run(subscriber.into())?;
+        let stdin = process.stdin.take().unwrap();
+        let started = Instant::now();
+        let sent = stdin.send_sequence(vec![
+            ("a\n".to_owned(), Duration::from_millis(300)),
+            ("b\n".to_owned(), Duration::from_millis(300)),
+            ("c\n".to_owned(), Duration::from_secs(30)),
+        ]);
+        assert!(matches!(sent, Err(Error::StdinClosed(input)) if input == "b\n"));
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert_eq!(process.finish_and_collect()?.stdout, "got a\n");
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_spawn_timeout() -> AnyResult<()> {
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "echo spawned"]);
+        let mut cmd = Command::from(std_cmd);
+        let process = cmd
+            .spawn_timeout(Duration::from_secs(10))
+            .run(subscriber.clone().into())?;
+        assert_eq!(process.finish_and_collect()?.stdout, "spawned\n");
+
+        // the spawn only returns once the child execs, two seconds in
+        let mut std_cmd = std::process::Command::new("true");
+        // SAFETY: sleep is async-signal-safe
+        unsafe {
+            std::os::unix::process::CommandExt::pre_exec(&mut std_cmd, || {
+                libc::sleep(2);
+                Ok(())
+            });
+        }
+        let mut cmd = Command::from(std_cmd);
+        cmd.spawn_timeout(Duration::from_millis(100));
+        assert!(matches!(
+            cmd.run(subscriber.clone().into()),
+            Err(Error::SpawnTimeout(timeout)) if timeout == Duration::from_millis(100)
+        ));
+        assert!(matches!(
+            cmd.run(subscriber.into()),
+            Err(Error::AlreadyRun(_))
+        ));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_run_result() -> AnyResult<()> {
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let output = Command::from(std::process::Command::new("yes"))
+            .line_mode(true)
+            .read_limit(ReadLimit::Lines(2))
+            .run_collecting(subscriber.into(), |_, _| {})?;
+        assert_eq!(output.result, RunResult::OutputLimited);
+        assert_eq!(output.stdout, "y\ny\n");
+
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let output = Command::from(std::process::Command::new("yes"))
+            .line_mode(true)
+            .read_limit(ReadLimit::Lines(0))
+            .run_collecting(subscriber.into(), |_, _| {})?;
+        assert_eq!(output.result, RunResult::OutputLimited);
+        assert_eq!(output.stdout, "");
+
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "printf '\\n\\nlast\\n'; exec sleep 10"]);
+        let output = Command::from(std_cmd)
+            .line_mode(true)
+            .read_limit(ReadLimit::Lines(2))
+            .run_collecting(subscriber.into(), |_, _| {})?;
+        assert_eq!(output.result, RunResult::OutputLimited);
+        assert_eq!(output.stdout, "\n\n");
+
+        let (broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "exec sleep 10"]);
+        let process = Command::from(std_cmd).run(subscriber.into())?;
+        broadcaster.broadcast(())?;
+        assert_eq!(process.finish_and_collect()?.result, RunResult::Cancelled);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_tee_stdout_rotating() -> AnyResult<()> {
+        let dir = env::temp_dir().join(format!("managed-command-tee-{}", std::process::id()));
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "for i in 1 2 3 4 5; do echo line$i; done"]);
+        let output = Command::from(std_cmd)
+            .line_mode(true)
+            .tee_stdout_rotating(&dir, 12)
+            .keep_rotated(2)
+            .run_collecting(subscriber.into(), |_, _| {})?;
+        assert_eq!(output.stdout.lines().count(), 5);
+        assert!(!dir.join("stdout.0.log").exists());
+        assert_eq!(
+            std::fs::read_to_string(dir.join("stdout.1.log"))?,
+            "line3\nline4\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("stdout.2.log"))?,
+            "line5\n"
+        );
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_stdout_to_tcp() -> AnyResult<()> {
+        use std::net::{TcpListener, TcpStream};
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "echo over; echo tcp"]);
+        let process = Command::from(std_cmd)
+            .stdout_to_writer(TcpStream::connect(addr)?)
+            .run(subscriber.clone().into())?;
+        let (mut peer, _) = listener.accept()?;
+        assert!(process.stdout.is_none());
+        assert!(process.wait()?.success());
+        assert!(process.wait_for_output_drain(Duration::from_secs(5)));
+        drop(process);
+        let mut received = String::new();
+        peer.read_to_string(&mut received)?;
+        assert_eq!(received, "over\ntcp\n");
+
+        // the peer hangs up while the child is still writing
+        let process = Command::from(std::process::Command::new("yes"))
+            .stdout_to_writer(TcpStream::connect(addr)?)
+            .kill_on_writer_error(true)
+            .run(subscriber.into())?;
+        drop(listener.accept()?);
+        drop(listener);
+        let status = process.wait()?;
+        assert!(!status.success());
+        let errors = process.errors.as_ref().unwrap();
+        assert!(matches!(
+            errors.recv_timeout(Duration::from_secs(5)),
+            Ok(Error::IoError(_))
+        ));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_errors_close() -> AnyResult<()> {
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "echo done"]);
+        let mut process = Command::from(std_cmd).run(subscriber.into())?;
+        drop(process.stdin.take());
+        assert_eq!(process.stdout.as_ref().unwrap().recv()?, "done\n");
+        assert!(process.wait()?.success());
+        let errors = process.errors.as_ref().unwrap();
+        assert_eq!(
+            errors.recv_timeout(Duration::from_secs(5)).unwrap_err(),
+            RecvTimeoutError::Disconnected
+        );
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_inherit_fd() -> AnyResult<()> {
+        use std::os::unix::net::UnixStream;
+        let (ours, theirs) = UnixStream::pair()?;
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "echo out of band >&3"]);
+        let process = Command::from(std_cmd)
+            .inherit_fd(3, theirs.into())
+            .run(subscriber.into())?;
+        assert!(process.wait()?.success());
+        let mut received = String::new();
+        (&ours).read_to_string(&mut received)?;
+        assert_eq!(received, "out of band\n");
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn check_resource_limits() -> AnyResult<()> {
+        let mut std_cmd = std::process::Command::new("grep");
+        std_cmd.args(["-E", "Max (cpu time|address space)", "/proc/self/limits"]);
+        let limits = Command::from(std_cmd)
+            .memory_limit(1 << 30)
+            .cpu_time_limit(Duration::from_secs(30))
+            .stdout_string()?;
+        let limits: Vec<Vec<_>> = limits
+            .lines()
+            .map(|line| line.split_whitespace().skip(3).take(2).collect())
+            .collect();
+        assert_eq!(limits, [["30", "31"], ["1073741824", "1073741824"]]);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_cancellation_token() -> AnyResult<()> {
+        let token = CancellationToken::new();
+        let mut std_cmd = std::process::Command::new("sleep");
+        std_cmd.arg("10");
+        let process = Command::from(std_cmd).run(token.canceller())?;
+        token.cancel();
+        assert!(!process.wait()?.success());
+        assert!(process.was_cancelled() && token.is_cancelled());
+        // a token cancelled already fires right away
+        let mut std_cmd = std::process::Command::new("sleep");
+        std_cmd.arg("10");
+        let process = Command::from(std_cmd).run((&token).into())?;
+        assert!(!process.wait()?.success());
+        assert!(process.was_cancelled());
+
+        let flag = Arc::new(AtomicBool::new(false));
+        let mut std_cmd = std::process::Command::new("sleep");
+        std_cmd.arg("10");
+        let process = Command::from(std_cmd)
+            .run(flag_canceller(Arc::clone(&flag), Duration::from_millis(10)))?;
+        flag.store(true, Ordering::SeqCst);
+        assert!(!process.wait()?.success());
+        assert!(process.was_cancelled());
+        Ok(())
+    }
+
+    #[test]
+    fn check_parse() -> AnyResult<()> {
+        let cmd = Command::parse(r#"git commit -m "initial commit" 'it''s' a\ b "q\"\x" '' "#)?;
+        assert_eq!(
+            cmd.spawned_argv(),
+            [
+                "git",
+                "commit",
+                "-m",
+                "initial commit",
+                "its",
+                "a b",
+                "q\"\\x",
+                ""
+            ]
+        );
+        for (line, reason) in [
+            ("echo 'open", "unbalanced single quote"),
+            ("echo \"open", "unbalanced double quote"),
+            ("echo \\", "trailing backslash"),
+            ("  ", "no program"),
+        ] {
+            assert!(matches!(
+                Command::parse(line),
+                Err(Error::InvalidCommandLine(r)) if r == reason
+            ));
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_trim_output() -> AnyResult<()> {
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "printf ' out \\n\\n'; echo err >&2"]);
+        let output = Command::from(std_cmd)
+            .trim_output(true)
+            .record_invocation(true)
+            .run_collecting(subscriber.into(), |_, _| {})?;
+        assert_eq!(
+            (output.stdout.as_str(), output.stderr.as_str()),
+            (" out", "err")
+        );
+        assert_eq!(output.invocation.unwrap().stdout, " out \n\n");
+        Ok(())
+    }
+
+    #[cfg(all(unix, feature = "regex"))]
+    #[test]
+    fn check_router() -> AnyResult<()> {
+        let (router, mut routes) = Router::new([
+            ("errors", regex::Regex::new("^ERROR")?),
+            ("warnings", regex::Regex::new("^WARN")?),
+        ]);
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args([
+            "-c",
+            "echo 'WARN low disk'; echo started; echo 'ERROR no disk'",
+        ]);
+        let process = Command::from(std_cmd)
+            .line_mode(true)
+            .stdout_into(router)
+            .run(subscriber.into())?;
+        assert!(process.wait()?.success());
+        let errors = routes.take("errors").unwrap();
+        assert!(routes.take("errors").is_none());
+        assert_eq!(errors.iter().collect::<Vec<_>>(), ["ERROR no disk"]);
+        assert_eq!(routes.take("warnings").unwrap().recv()?, "WARN low disk");
+        assert_eq!(routes.default.iter().collect::<Vec<_>>(), ["started"]);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_run_script() -> AnyResult<()> {
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("python3");
+        std_cmd.arg("-");
+        let output = Command::from(std_cmd).run_script(
+            "import sys\nprint(6 * 7)\nprint('to stderr', file=sys.stderr)\n",
+            subscriber.into(),
+        )?;
+        assert!(output.status.success());
+        assert_eq!(
+            (output.stdout.as_str(), output.stderr.as_str()),
+            ("42\n", "to stderr\n")
+        );
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_stdin_rate_limit() -> AnyResult<()> {
+        let limiter = RateLimiter::new(1000);
+        let started = Instant::now();
+        let mut processes = Vec::new();
+        for _ in 0..2 {
+            let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+            let mut process = Command::from(std::process::Command::new("wc"))
+                .stdin_rate_limit(limiter.clone())
+                .run(subscriber.into())?;
+            let stdin = process.stdin.take().unwrap();
+            stdin.send("x".repeat(500))?;
+            stdin.send("x".repeat(500))?;
+            processes.push((process, stdin));
+        }
+        for (process, stdin) in processes {
+            while stdin.bytes_written() < 1000 {
+                thread::sleep(Duration::from_millis(10));
+            }
+            assert!(stdin.write_rate() > 0.0);
+            drop(stdin);
+            assert!(process.wait()?.success());
+        }
+        // 2000 bytes at 1000 bytes/s with a full bucket to start with
+        assert!(started.elapsed() >= Duration::from_millis(900));
+        Ok(())
+    }
+
+    #[test]
+    fn check_rate_limiter_clock() -> AnyResult<()> {
+        let clock = Arc::new(clock::ManualClock::new());
+        let limiter = RateLimiter::new(1000);
+        let poll_interval = Duration::from_millis(1);
+        // the full bucket covers the first second worth of bytes
+        limiter.acquire(1000, &*clock, poll_interval);
+        let acquire = |bytes| {
+            let (clock, limiter) = (Arc::clone(&clock), limiter.clone());
+            let (tx, rx) = channel();
+            thread::spawn(move || {
+                limiter.acquire(bytes, &*clock, poll_interval);
+                tx.send(()).unwrap();
+            });
+            rx
+        };
+        // a single large write waits for all of its bytes
+        let done = acquire(1500);
+        clock.advance(Duration::from_millis(1000));
+        assert!(done.recv_timeout(Duration::from_millis(50)).is_err());
+        clock.advance(Duration::from_millis(499));
+        assert!(done.recv_timeout(Duration::from_millis(50)).is_err());
+        clock.advance(Duration::from_millis(1));
+        done.recv_timeout(Duration::from_secs(5))?;
+        // idle time refills no more than the bucket holds
+        clock.advance(Duration::from_secs(10));
+        limiter.acquire(1000, &*clock, poll_interval);
+        let done = acquire(100);
+        assert!(done.recv_timeout(Duration::from_millis(50)).is_err());
+        clock.advance(Duration::from_millis(100));
+        done.recv_timeout(Duration::from_secs(5))?;
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_race() -> AnyResult<()> {
+        let sh = |script: &str| {
+            let mut std_cmd = std::process::Command::new("sh");
+            std_cmd.args(["-c", script]);
+            Command::from(std_cmd)
+        };
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let started = Instant::now();
+        let (index, output) = race(
+            vec![
+                sh("exec sleep 10"),
+                sh("exit 1"),
+                sh("sleep 0.2; echo mirror"),
+            ],
+            subscriber.into(),
+        );
+        assert_eq!((index, output?.stdout.as_str()), (2, "mirror\n"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let (broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let racing = thread::spawn(move || race(vec![sh("exec sleep 10")], subscriber.into()));
+        thread::sleep(Duration::from_millis(200));
+        broadcaster.broadcast(())?;
+        let (index, output) = racing.join().unwrap();
+        assert_eq!(index, 0);
+        assert_eq!(output?.result, RunResult::Cancelled);
+
+        // losers ignoring their cancel signal are killed all the same
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let started = Instant::now();
+        let mut stubborn = sh("trap '' TERM; exec sleep 10");
+        stubborn.cancel_signal(Signal::Term);
+        let (index, output) = race(
+            vec![stubborn, sh("sleep 0.2; echo mirror")],
+            subscriber.into(),
+        );
+        assert_eq!((index, output?.stdout.as_str()), (1, "mirror\n"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        Ok(())
+    }
+
+    #[test]
+    fn check_race_empty() {
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let (index, output) = race(Vec::new(), subscriber.into());
+        assert_eq!(index, 0);
+        assert!(matches!(output, Err(Error::NoCommands)));
+    }
+
+    #[test]
+    fn check_nt_status() {
+        assert_eq!(
+            NtStatus::from_code(0xC000_0005u32),
+            Some(NtStatus::AccessViolation)
+        );
+        // std reports it as a negative i32
+        let code = NtStatus::from_code(0xC000_00FDu32 as i32).unwrap();
+        assert_eq!(code, NtStatus::StackOverflow);
+        assert_eq!(code.code(), 0xC000_00FD);
+        assert_eq!(code.to_string(), "STATUS_STACK_OVERFLOW (0xC00000FD)");
+        let other = NtStatus::from_code(0xC0DE_0001u32).unwrap();
+        assert_eq!(other, NtStatus::Other(0xC0DE_0001));
+        assert_eq!(other.to_string(), "NTSTATUS 0xC0DE0001");
+        assert_eq!(NtStatus::from_code(1), None);
+        assert_eq!(NtStatus::from_code(0x4000_0000u32), None);
+        // neither a u32 nor an i32
+        assert_eq!(NtStatus::from_code(0x1_C000_0005i64), None);
+        assert_eq!(NtStatus::from_code(-0x1_0000_0000i64), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_items() -> AnyResult<()> {
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "echo one; echo two; exit 3"]);
+        let mut cmd = Command::from(std_cmd);
+        let handle = cmd.line_mode(true).run(subscriber.into())?;
+        let items = handle.items().collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(items.len(), 3);
+        assert_eq!(
+            items[..2],
+            [Item::Line("one".into()), Item::Line("two".into())]
+        );
+        assert!(matches!(items[2], Item::Exit(status) if status.code() == Some(3)));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_effective_env() -> AnyResult<()> {
+        let mut std_cmd = std::process::Command::new("env");
+        std_cmd.env("MANAGED_COMMAND_EFFECTIVE", "set");
+        let mut cmd = Command::from(std_cmd);
+        let vars = cmd.effective_env();
+        assert!(vars.contains(&("MANAGED_COMMAND_EFFECTIVE".into(), "set".into())));
+        assert!(vars.iter().any(|(key, _)| key == "PATH"));
+        cmd.secure_env(&[]);
+        let vars = cmd.effective_env();
+        assert_eq!(vars, [("MANAGED_COMMAND_EFFECTIVE".into(), "set".into())]);
+        let printed = cmd.stdout_string()?;
+        assert_eq!(printed.trim_end(), "MANAGED_COMMAND_EFFECTIVE=set");
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_last_line() -> AnyResult<()> {
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "echo 10%; echo 50%; echo done; echo"]);
+        let mut cmd = Command::from(std_cmd);
+        assert_eq!(cmd.last_line()?.as_deref(), Some("done"));
+        assert!(!cmd.line_mode);
+        let mut cmd = Command::from(std::process::Command::new("true"));
+        assert_eq!(cmd.last_line()?, None);
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "echo partial; exit 2"]);
+        let err = Command::from(std_cmd).last_line().unwrap_err();
+        assert!(matches!(err, Error::NonZeroExit { .. }));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_send_if_idle() -> AnyResult<()> {
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "while read l; do sleep 0.2; echo \"got $l\"; done"]);
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut process = Command::from(std_cmd)
+            .line_mode(true)
+            .run(subscriber.into())?;
+        let (stdin, stdout) = (
+            process.stdin.take().unwrap(),
+            process.stdout.take().unwrap(),
+        );
+        assert!(stdin.send_if_idle("a\n".into())?);
+        assert!(!stdin.send_if_idle("b\n".into())?);
+        assert!(stdin.is_awaiting_response());
+        assert_eq!(stdout.recv()?, "got a");
+        assert!(!stdin.is_awaiting_response());
+        assert!(stdin.send_if_idle("c\n".into())?);
+        assert_eq!(stdout.recv()?, "got c");
+        drop(stdin);
+        assert!(process.wait()?.success());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_kill_schedule() -> AnyResult<()> {
+        use std::os::unix::process::ExitStatusExt;
+        let (broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args([
+            "-c",
+            "trap 'echo term' TERM; echo ready; while :; do sleep 0.05; done",
+        ]);
+        let process = Command::from(std_cmd)
+            .kill_schedule(vec![
+                (Duration::ZERO, Signal::Term),
+                (Duration::from_millis(300), Signal::Term),
+                (Duration::from_millis(600), Signal::Kill),
+            ])
+            .line_mode(true)
+            .run(subscriber.into())?;
+        let stdout = process.stdout.as_ref().unwrap();
+        assert_eq!(stdout.recv()?, "ready");
+        let started = Instant::now();
+        broadcaster.broadcast(())?;
+        assert_eq!(stdout.recv()?, "term");
+        assert_eq!(stdout.recv()?, "term");
+        assert_eq!(process.wait()?.signal(), Some(9));
+        assert!(started.elapsed() >= Duration::from_millis(600));
+        assert!(process.was_cancelled());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_stdin_as_writer() -> AnyResult<()> {
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut process =
+            Command::from(std::process::Command::new("cat")).run(subscriber.into())?;
+        let stdin = process.stdin.take().unwrap();
+        let mut writer = stdin.as_writer();
+        writeln!(writer, "line {}", 1)?;
+        writer.write_all("caf\u{e9}".as_bytes().split_last().unwrap().1)?;
+        writer.flush()?;
+        writer.write_all(&[0xa9])?;
+        write!(writer, " tail")?;
+        drop(writer);
+        let mut invalid = stdin.as_writer();
+        invalid.write_all(&[0xff])?;
+        assert_eq!(
+            invalid.flush().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        drop(invalid);
+        drop(stdin);
+        assert_eq!(
+            process.finish_and_collect()?.stdout,
+            "line 1\ncaf\u{e9} tail"
+        );
+
+        // a failed write leaves only what was pending before it
+        let (tx, rx) = channel();
+        let stdin = StdinSender::from(tx);
+        let mut writer = ChannelWriter {
+            tx: &stdin,
+            pending: Vec::new(),
+        };
+        writer.write_all(b"par")?;
+        let invalid = writer.write(b"\xff\n").unwrap_err();
+        assert_eq!(invalid.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(writer.pending, b"par");
+        drop(rx);
+        let closed = writer.write(b"tial\n").unwrap_err();
+        assert_eq!(closed.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(writer.pending, b"par");
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_lazy() -> AnyResult<()> {
+        let marker = env::temp_dir().join(format!("managed-command-lazy-{}", std::process::id()));
+        let _ = std::fs::remove_file(&marker);
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "touch \"$0\"; cat"]).arg(&marker);
+        let mut cmd = Command::from(std_cmd);
+        let mut process = cmd.lazy(true).line_mode(true).run(subscriber.into())?;
+        assert!(matches!(
+            cmd.run(idle_canceller().1),
+            Err(Error::AlreadyRun(_))
+        ));
+        thread::sleep(Duration::from_millis(200));
+        assert!(!marker.exists());
+        let stdin = process.stdin.take().unwrap();
+        stdin.send("hello\n".into())?;
+        assert_eq!(process.stdout.as_ref().unwrap().recv()?, "hello");
+        assert!(marker.exists() && process.id().is_some());
+        drop(stdin);
+        let output = process.finish_and_collect()?;
+        assert!(output.status.success());
+        std::fs::remove_file(&marker)?;
+
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let std_cmd = std::process::Command::new("/nonexistent/managed-command");
+        let process = Command::from(std_cmd).lazy(true).run(subscriber.into())?;
+        assert!(matches!(process.wait(), Err(Error::IoError(_))));
+        assert!(matches!(process.wait(), Err(Error::NoChild)));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_lazy_stdin() -> AnyResult<()> {
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "while read l; do sleep 0.2; echo \"got $l\"; done"]);
+        let mut process = Command::from(std_cmd)
+            .lazy(true)
+            .line_mode(true)
+            .run(subscriber.into())?;
+        let (stdin, stdout) = (
+            process.stdin.take().unwrap(),
+            process.stdout.take().unwrap(),
+        );
+        assert!(!stdin.is_awaiting_response());
+        assert!(stdin.send_if_idle("a\n".into())?);
+        assert!(!stdin.send_if_idle("b\n".into())?);
+        assert!(stdin.is_awaiting_response());
+        assert_eq!(stdout.recv()?, "got a");
+        assert!(!stdin.is_awaiting_response());
+        assert!(stdin.send_if_idle("c\n".into())?);
+        assert_eq!(stdout.recv()?, "got c");
+        drop(stdin);
+        assert!(process.wait()?.success());
+
+        // a window set before the spawn counts from the spawn
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let clock = ObservedClock::default();
+        let mut process = Command::from(std::process::Command::new("cat"))
+            .lazy(true)
+            .clock(clock.clone())
+            .run(subscriber.into())?;
+        let stdin = process.stdin.take().unwrap();
+        stdin.auto_close_after_idle(Duration::from_secs(5));
+        clock.manual.advance(Duration::from_secs(10));
+        stdin.send("first\n".to_owned())?;
+        clock.advance(Duration::from_secs(4));
+        stdin.send("second\n".to_owned())?;
+        // the watcher stops reading the clock once it closed stdin
+        clock.manual.advance(Duration::from_secs(6));
+        let output = process.finish_and_collect()?;
+        assert_eq!(output.stdout, "first\nsecond\n");
+        assert!(matches!(
+            stdin.send("late\n".to_owned()),
+            Err(Error::StdinClosed(_))
+        ));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_run_and_wait() -> AnyResult<()> {
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "exit 4"]);
+        let status = Command::from(std_cmd).run_and_wait(idle_canceller().1)?;
+        assert_eq!(status.code(), Some(4));
+
+        let (broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let mut std_cmd = std::process::Command::new("sleep");
+        std_cmd.arg("10");
+        let started = Instant::now();
+        let cancel = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            broadcaster.broadcast(())
+        });
+        let status = Command::from(std_cmd).run_and_wait(subscriber.into())?;
+        assert!(!status.success() && started.elapsed() < Duration::from_secs(5));
+        cancel.join().unwrap()?;
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_arg0() -> AnyResult<()> {
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args(["-c", "echo $0"]);
+        let mut cmd = Command::from(std_cmd);
+        cmd.arg0("-applet");
+        assert_eq!(cmd.spawned_argv(), ["-applet", "-c", "echo $0"]);
+        assert_eq!(cmd.stdout_string()?, "-applet");
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_on_stall() -> AnyResult<()> {
+        let (tx, rx) = channel();
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let clock = ObservedClock::default();
+        let mut process = Command::from(std::process::Command::new("cat"))
+            .line_mode(true)
+            .clock(clock.clone())
+            .on_stall(Duration::from_secs(5), move || {
+                let _ = tx.send(());
+            })
+            .run(subscriber.into())?;
+        let (stdin, stdout) = (
+            process.stdin.take().unwrap(),
+            process.stdout.take().unwrap(),
+        );
+        for line in ["a", "b"] {
+            stdin.send(format!("{line}\n"))?;
+            assert_eq!(stdout.recv()?, line);
+            // lets the watchdog see the message before the clock moves on
+            clock.advance(Duration::ZERO);
+            clock.advance(Duration::from_secs(4));
+            assert!(rx.try_recv().is_err());
+            clock.advance(Duration::from_secs(2));
+            rx.recv_timeout(Duration::from_secs(5))?;
+            // once per quiet spell
+            clock.advance(Duration::from_secs(10));
+            assert!(rx.try_recv().is_err());
+        }
+        drop(stdin);
+        assert!(process.wait()?.success());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_dedup_consecutive() -> AnyResult<()> {
+        let mut std_cmd = std::process::Command::new("sh");
+        std_cmd.args([
+            "-c",
+            "echo start; for i in 1 2 3 4 5; do echo same; done; echo end; echo end",
+        ]);
+        let (_broadcaster, subscriber) = broadcasting_channel("test the managed command");
+        let output = Command::from(std_cmd)
+            .line_mode(true)
+            .dedup_consecutive(true)
+            .run(subscriber.into())?
+            .finish_and_collect()?;
+        assert_eq!(
+            output.stdout,
+            "start\nsame\n(repeated 4 times)\nend\n(repeated 1 time)\n"
+        );
         Ok(())
     }
 }
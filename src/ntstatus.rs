@@ -0,0 +1,129 @@
+//! Names for the NTSTATUS codes a crashed Windows process exits with.
+
+use std::fmt;
+
+/// An NTSTATUS error code, as left in the exit code by a Windows process that
+/// ended abnormally, see `RunResult::nt_status` on Windows.
+/// Decoding works on every platform, e.g. for codes reported by a remote host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NtStatus {
+    AccessViolation,
+    InPageError,
+    IllegalInstruction,
+    ArrayBoundsExceeded,
+    IntegerDivideByZero,
+    IntegerOverflow,
+    PrivilegedInstruction,
+    StackOverflow,
+    DllNotFound,
+    EntryPointNotFound,
+    ControlCExit,
+    DllInitFailed,
+    HeapCorruption,
+    StackBufferOverrun,
+    /// Any other code with the error severity.
+    Other(u32),
+}
+
+const NAMES: [(NtStatus, u32, &str); 14] = [
+    (
+        NtStatus::AccessViolation,
+        0xC000_0005,
+        "STATUS_ACCESS_VIOLATION",
+    ),
+    (NtStatus::InPageError, 0xC000_0006, "STATUS_IN_PAGE_ERROR"),
+    (
+        NtStatus::IllegalInstruction,
+        0xC000_001D,
+        "STATUS_ILLEGAL_INSTRUCTION",
+    ),
+    (
+        NtStatus::ArrayBoundsExceeded,
+        0xC000_008C,
+        "STATUS_ARRAY_BOUNDS_EXCEEDED",
+    ),
+    (
+        NtStatus::IntegerDivideByZero,
+        0xC000_0094,
+        "STATUS_INTEGER_DIVIDE_BY_ZERO",
+    ),
+    (
+        NtStatus::IntegerOverflow,
+        0xC000_0095,
+        "STATUS_INTEGER_OVERFLOW",
+    ),
+    (
+        NtStatus::PrivilegedInstruction,
+        0xC000_0096,
+        "STATUS_PRIVILEGED_INSTRUCTION",
+    ),
+    (
+        NtStatus::StackOverflow,
+        0xC000_00FD,
+        "STATUS_STACK_OVERFLOW",
+    ),
+    (NtStatus::DllNotFound, 0xC000_0135, "STATUS_DLL_NOT_FOUND"),
+    (
+        NtStatus::EntryPointNotFound,
+        0xC000_0139,
+        "STATUS_ENTRYPOINT_NOT_FOUND",
+    ),
+    (NtStatus::ControlCExit, 0xC000_013A, "STATUS_CONTROL_C_EXIT"),
+    (
+        NtStatus::DllInitFailed,
+        0xC000_0142,
+        "STATUS_DLL_INIT_FAILED",
+    ),
+    (
+        NtStatus::HeapCorruption,
+        0xC000_0374,
+        "STATUS_HEAP_CORRUPTION",
+    ),
+    (
+        NtStatus::StackBufferOverrun,
+        0xC000_0409,
+        "STATUS_STACK_BUFFER_OVERRUN",
+    ),
+];
+
+impl NtStatus {
+    /// Decodes an exit code, `None` unless it has the error severity, i.e. its
+    /// top two bits are set. Windows exit codes are `u32`, std hands them out
+    /// as `i32`, both are accepted; values that fit neither give `None`.
+    pub fn from_code(code: impl Into<i64>) -> Option<Self> {
+        let code = code.into();
+        let code = u32::try_from(code)
+            .or_else(|_| i32::try_from(code).map(|code| code as u32))
+            .ok()?;
+        if code >> 30 != 0b11 {
+            return None;
+        }
+        let known = NAMES.iter().find(|(_, known, _)| *known == code);
+        Some(known.map_or(NtStatus::Other(code), |(status, _, _)| *status))
+    }
+
+    /// The raw code.
+    pub fn code(self) -> u32 {
+        match self {
+            NtStatus::Other(code) => code,
+            status => NAMES.iter().find(|(known, ..)| *known == status).unwrap().1,
+        }
+    }
+
+    /// The `STATUS_*` name from the Windows headers, `None` for `Other`.
+    pub fn name(self) -> Option<&'static str> {
+        NAMES
+            .iter()
+            .find(|(known, ..)| *known == self)
+            .map(|(.., name)| *name)
+    }
+}
+
+impl fmt::Display for NtStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name() {
+            Some(name) => write!(f, "{name} ({:#010X})", self.code()),
+            None => write!(f, "NTSTATUS {:#010X}", self.code()),
+        }
+    }
+}
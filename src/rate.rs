@@ -0,0 +1,58 @@
+//! A write-rate cap that several commands can share.
+
+use crate::Clock;
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// A token bucket holding up to one second worth of bytes, see
+/// [`Command::stdin_rate_limit`](crate::Command::stdin_rate_limit). Clones share
+/// the bucket, so every command given one stays under the cap together.
+#[derive(Debug, Clone)]
+pub struct RateLimiter(Arc<Mutex<Bucket>>);
+
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    /// Negative while writers are waiting for their share.
+    tokens: f64,
+    /// `None` until the first write, as each command brings its own clock.
+    refilled: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_second: u64) -> Self {
+        let rate = bytes_per_second.max(1) as f64;
+        Self(Arc::new(Mutex::new(Bucket {
+            rate,
+            tokens: rate,
+            refilled: None,
+        })))
+    }
+
+    /// Takes `bytes` from the bucket, sleeping until they are covered, on `clock`
+    /// checked at `poll_interval`. A write larger than the bucket goes into debt
+    /// that later writes wait out.
+    pub(crate) fn acquire(&self, bytes: usize, clock: &dyn Clock, poll_interval: Duration) {
+        let now = clock.now();
+        let until = {
+            let mut bucket = self.0.lock().unwrap();
+            let refilled = bucket.refilled.unwrap_or(now);
+            let refill = now.saturating_duration_since(refilled).as_secs_f64() * bucket.rate;
+            bucket.tokens = (bucket.tokens + refill).min(bucket.rate) - bytes as f64;
+            bucket.refilled = Some(now);
+            match bucket.tokens {
+                tokens if tokens < 0.0 => now + Duration::from_secs_f64(-tokens / bucket.rate),
+                _ => now,
+            }
+        };
+        while let Some(left) = until.checked_duration_since(clock.now()) {
+            if left.is_zero() {
+                break;
+            }
+            thread::sleep(left.min(poll_interval));
+        }
+    }
+}
@@ -0,0 +1,56 @@
+//! Reading stdout straight into a ring buffer shared with the consumer, with the
+//! `rtrb` feature.
+
+use crate::{Error, Worker};
+use rtrb::Producer;
+use std::{
+    io::{self, Read},
+    sync::mpsc::Sender,
+    thread,
+    time::Duration,
+};
+use tracing::trace;
+
+/// How long the reader backs off while the ring buffer is full.
+const FULL_BACKOFF: Duration = Duration::from_micros(200);
+
+/// Reads `pipe` into the free part of the ring buffer, without a copy or an
+/// allocation; rtrb zeroes the slots before each read. Dropping `producer` on the way
+/// out tells the consumer that the stream ended. A failed read goes to `errors`.
+pub(crate) fn reader_worker(
+    cmd: String,
+    mut pipe: impl Read + Send + 'static,
+    mut producer: Producer<u8>,
+    errors: Sender<Error>,
+) -> Worker {
+    Box::new(move || {
+        trace!("'{cmd:}' is in stdout ring read");
+        loop {
+            if producer.is_abandoned() {
+                trace!("'{cmd:}' stdout ring is not read anymore");
+                break;
+            }
+            let free = producer.slots();
+            if free == 0 {
+                thread::sleep(FULL_BACKOFF);
+                continue;
+            }
+            let mut chunk = producer
+                .write_chunk(free)
+                .expect("the producer alone takes slots");
+            // the free part may wrap around, the rest is read on the next turn
+            let (room, _) = chunk.as_mut_slices();
+            match pipe.read(room) {
+                Ok(0) => break,
+                Ok(read_bytes) => chunk.commit(read_bytes),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    trace!("'{cmd:}' stdout read failed: {e}");
+                    let _ = errors.send(e.into());
+                    break;
+                }
+            }
+        }
+        trace!("exiting the stdout ring thread of '{cmd:}'");
+    })
+}
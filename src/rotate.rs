@@ -0,0 +1,70 @@
+//! Copies of a stream kept in a bounded set of numbered log files.
+
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::PathBuf,
+};
+use tracing::trace;
+
+/// Where [`Command::tee_stdout_rotating`](crate::Command::tee_stdout_rotating)
+/// writes: `<stream>.0.log`, `<stream>.1.log` and so on in `dir`, each at most
+/// `max_size` bytes unless a single message is larger.
+pub(crate) struct RotatingLog {
+    dir: PathBuf,
+    stream: &'static str,
+    max_size: u64,
+    /// How many files to keep, the oldest are removed past that.
+    keep: Option<usize>,
+    index: usize,
+    file: File,
+    written: u64,
+}
+
+impl RotatingLog {
+    /// Creates `dir` if needed and starts over at file 0.
+    pub(crate) fn create(
+        dir: PathBuf,
+        stream: &'static str,
+        max_size: u64,
+        keep: Option<usize>,
+    ) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let file = File::create(dir.join(format!("{stream}.0.log")))?;
+        Ok(Self {
+            dir,
+            stream,
+            max_size,
+            keep,
+            index: 0,
+            file,
+            written: 0,
+        })
+    }
+
+    pub(crate) fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let len = data.len() as u64;
+        if self.written > 0 && self.written + len > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(data)?;
+        self.written += len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.index += 1;
+        trace!("rotating the {} log to file {}", self.stream, self.index);
+        self.file = File::create(self.path(self.index))?;
+        self.written = 0;
+        if let Some(stale) = self.keep.and_then(|keep| self.index.checked_sub(keep)) {
+            // a file removed by hand is fine
+            let _ = fs::remove_file(self.path(stale));
+        }
+        Ok(())
+    }
+
+    fn path(&self, index: usize) -> PathBuf {
+        self.dir.join(format!("{}.{index}.log", self.stream))
+    }
+}
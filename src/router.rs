@@ -0,0 +1,92 @@
+//! Sorting output lines into named channels by regex, with the `regex` feature.
+
+use crate::OutputSender;
+use regex::Regex;
+use std::{
+    cell::Cell,
+    sync::mpsc::{channel, Receiver, Sender},
+};
+
+/// An [`OutputSender`] that delivers each message to the channel of the first
+/// rule whose regex matches it, or else to the default channel. Meant for
+/// [`Command::line_mode`](crate::Command::line_mode), so that rules see whole
+/// lines. A message for a category whose receiver is gone is dropped; reading
+/// only stops once every receiver is gone.
+pub struct Router {
+    rules: Vec<(Regex, Route)>,
+    default: Route,
+    /// Routes whose receiver has not been seen gone yet.
+    open: Cell<usize>,
+}
+
+struct Route {
+    tx: Sender<String>,
+    closed: Cell<bool>,
+}
+
+impl Route {
+    fn new(tx: Sender<String>) -> Self {
+        Self {
+            tx,
+            closed: Cell::new(false),
+        }
+    }
+}
+
+/// The receiving ends of a [`Router`], one per rule plus the default.
+pub struct Routes {
+    /// Messages no rule matched.
+    pub default: Receiver<String>,
+    categories: Vec<(String, Option<Receiver<String>>)>,
+}
+
+impl Router {
+    /// Builds a router from `(name, regex)` rules, tried in order. Names are
+    /// expected to be distinct, [`Routes::take`] finds the first of a kind.
+    pub fn new<N: Into<String>>(rules: impl IntoIterator<Item = (N, Regex)>) -> (Self, Routes) {
+        let (mut senders, mut categories) = (Vec::new(), Vec::new());
+        for (name, regex) in rules {
+            let (tx, rx) = channel();
+            senders.push((regex, Route::new(tx)));
+            categories.push((name.into(), Some(rx)));
+        }
+        let (default_tx, default) = channel();
+        let router = Self {
+            open: Cell::new(senders.len() + 1),
+            rules: senders,
+            default: Route::new(default_tx),
+        };
+        (
+            router,
+            Routes {
+                default,
+                categories,
+            },
+        )
+    }
+}
+
+impl OutputSender for Router {
+    fn send(&self, msg: String) -> bool {
+        let route = self
+            .rules
+            .iter()
+            .find(|(regex, _)| regex.is_match(&msg))
+            .map_or(&self.default, |(_, route)| route);
+        if route.tx.send(msg).is_err() && !route.closed.replace(true) {
+            self.open.set(self.open.get() - 1);
+        }
+        self.open.get() > 0
+    }
+}
+
+impl Routes {
+    /// Takes the receiver of the category `name`, `None` if there is no such
+    /// rule or it was taken already.
+    pub fn take(&mut self, name: &str) -> Option<Receiver<String>> {
+        self.categories
+            .iter_mut()
+            .find(|(category, _)| category == name)
+            .and_then(|(_, rx)| rx.take())
+    }
+}
@@ -0,0 +1,166 @@
+//! Delivering signals to the child, and optionally forwarding the parent's own.
+
+use std::{io, process::Child};
+
+/// A signal to deliver to the child. Signals only exist on Unix; elsewhere every
+/// variant terminates the child like [`Child::kill`] does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Signal {
+    Hup,
+    Int,
+    Quit,
+    Kill,
+    Usr1,
+    Usr2,
+    Term,
+    /// A raw Unix signal number not covered above.
+    Raw(i32),
+}
+
+impl Signal {
+    #[cfg(unix)]
+    pub fn as_raw(self) -> i32 {
+        match self {
+            Signal::Hup => libc::SIGHUP,
+            Signal::Int => libc::SIGINT,
+            Signal::Quit => libc::SIGQUIT,
+            Signal::Kill => libc::SIGKILL,
+            Signal::Usr1 => libc::SIGUSR1,
+            Signal::Usr2 => libc::SIGUSR2,
+            Signal::Term => libc::SIGTERM,
+            Signal::Raw(raw) => raw,
+        }
+    }
+}
+
+/// Sends `signal` unless the child has been reaped already, as its pid may have
+/// been reused by then.
+pub(crate) fn send(child: &mut Child, signal: Signal) -> io::Result<()> {
+    if child.try_wait()?.is_some() {
+        return Ok(());
+    }
+    #[cfg(unix)]
+    {
+        // SAFETY: plain syscall, the pid belongs to our not yet reaped child
+        if unsafe { libc::kill(child.id() as libc::pid_t, signal.as_raw()) } == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = signal;
+        child.kill()
+    }
+}
+
+/// Blocks SIGPIPE for the calling thread. A write to a pipe the child has closed
+/// then fails with `BrokenPipe` instead of raising SIGPIPE, which would terminate
+/// this process in case it does not ignore the signal. The signal stays pending
+/// for this thread only and is discarded when the thread exits.
+#[cfg(unix)]
+pub(crate) fn block_sigpipe() {
+    // SAFETY: the set is initialized by sigemptyset before use
+    unsafe {
+        let mut set = std::mem::MaybeUninit::<libc::sigset_t>::uninit();
+        libc::sigemptyset(set.as_mut_ptr());
+        libc::sigaddset(set.as_mut_ptr(), libc::SIGPIPE);
+        libc::pthread_sigmask(libc::SIG_BLOCK, set.as_ptr(), std::ptr::null_mut());
+    }
+}
+
+/// Blocks SIGPIPE for the calling thread like [`block_sigpipe`] until dropped,
+/// for threads that outlive the write. The drop discards a SIGPIPE raised
+/// meanwhile and restores the previous signal mask.
+#[cfg(unix)]
+pub(crate) struct SigpipeBlocked {
+    sigpipe: libc::sigset_t,
+    previous: libc::sigset_t,
+}
+
+#[cfg(unix)]
+impl SigpipeBlocked {
+    pub(crate) fn new() -> Self {
+        // SAFETY: both sets are initialized, by sigemptyset and by
+        // pthread_sigmask, before they are read
+        unsafe {
+            let mut sigpipe = std::mem::MaybeUninit::<libc::sigset_t>::uninit();
+            let mut previous = std::mem::MaybeUninit::<libc::sigset_t>::uninit();
+            libc::sigemptyset(sigpipe.as_mut_ptr());
+            libc::sigaddset(sigpipe.as_mut_ptr(), libc::SIGPIPE);
+            libc::pthread_sigmask(libc::SIG_BLOCK, sigpipe.as_ptr(), previous.as_mut_ptr());
+            Self {
+                sigpipe: sigpipe.assume_init(),
+                previous: previous.assume_init(),
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for SigpipeBlocked {
+    fn drop(&mut self) {
+        // SAFETY: the sets were initialized in new; sigwait returns at once, as
+        // the signal is pending
+        unsafe {
+            if libc::sigismember(&self.previous, libc::SIGPIPE) == 0 {
+                let mut pending = std::mem::MaybeUninit::<libc::sigset_t>::uninit();
+                libc::sigemptyset(pending.as_mut_ptr());
+                libc::sigpending(pending.as_mut_ptr());
+                if libc::sigismember(pending.as_ptr(), libc::SIGPIPE) == 1 {
+                    let mut signal = 0;
+                    libc::sigwait(&self.sigpipe, &mut signal);
+                }
+            }
+            libc::pthread_sigmask(libc::SIG_SETMASK, &self.previous, std::ptr::null_mut());
+        }
+    }
+}
+
+/// Resets every signal to its default disposition and unblocks them all. Runs in
+/// the forked child right before exec, so it sticks to async-signal-safe calls.
+#[cfg(unix)]
+pub(crate) fn reset_dispositions() -> io::Result<()> {
+    // SAFETY: signal and sigprocmask are async-signal-safe; numbers the platform
+    // does not know, SIGKILL and SIGSTOP simply fail
+    unsafe {
+        for raw in 1..65 {
+            libc::signal(raw, libc::SIG_DFL);
+        }
+        let mut set = std::mem::MaybeUninit::<libc::sigset_t>::uninit();
+        libc::sigemptyset(set.as_mut_ptr());
+        if libc::pthread_sigmask(libc::SIG_SETMASK, set.as_ptr(), std::ptr::null_mut()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(unix, feature = "signal-forwarding"))]
+pub(crate) fn forwarder(
+    cmd: String,
+    signals: &[Signal],
+    child: std::sync::Arc<std::sync::Mutex<Child>>,
+    poll_interval: std::time::Duration,
+) -> io::Result<crate::Worker> {
+    use signal_hook::iterator::Signals;
+    use tracing::trace;
+
+    let mut pending = Signals::new(signals.iter().map(|signal| signal.as_raw()))?;
+    Ok(Box::new(move || {
+        loop {
+            for raw in pending.pending() {
+                trace!("forwarding signal {raw} to '{cmd:}'");
+                let _ = send(&mut child.lock().unwrap(), Signal::Raw(raw));
+            }
+            if !matches!(child.lock().unwrap().try_wait(), Ok(None)) {
+                break;
+            }
+            std::thread::sleep(poll_interval);
+        }
+        // dropping the only registration unregisters the handlers
+        drop(pending);
+        trace!("exiting the signal forwarding thread of '{cmd:}'");
+    }))
+}
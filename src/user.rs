@@ -0,0 +1,31 @@
+//! Looking up accounts in the system user database.
+
+use std::{ffi::CString, io, mem, ptr};
+
+/// The uid and primary gid of `username`, `None` if there is no such user.
+pub(crate) fn lookup(username: &str) -> io::Result<Option<(u32, u32)>> {
+    let name =
+        CString::new(username).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut buf: Vec<libc::c_char> = vec![0; 1024];
+    loop {
+        // SAFETY: passwd is plain old data, all-zero is a valid value
+        let mut entry: libc::passwd = unsafe { mem::zeroed() };
+        let mut found = ptr::null_mut();
+        // SAFETY: every pointer is valid for the call, the buffer for its length
+        let rc = unsafe {
+            libc::getpwnam_r(
+                name.as_ptr(),
+                &mut entry,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut found,
+            )
+        };
+        match rc {
+            0 if found.is_null() => return Ok(None),
+            0 => return Ok(Some((entry.pw_uid, entry.pw_gid))),
+            libc::ERANGE => buf.resize(buf.len() * 2, 0),
+            rc => return Err(io::Error::from_raw_os_error(rc)),
+        }
+    }
+}